@@ -4,6 +4,7 @@ use crate::serialization::{
 };
 use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
 use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct DnsAnswer {
@@ -32,6 +33,10 @@ impl DnsAnswer {
             Ok(tup) => tup,
             Err(_) => return Err(DnsAnswer::new()),
         };
+        // type, class, ttl and the rdata length
+        if bytes.len() < bytes_read + 10 {
+            return Err(DnsAnswer::new());
+        }
         let qtype: ResourceType = match NetworkEndian::read_u16(&bytes[bytes_read..]).try_into() {
             Ok(rrtype) => rrtype,
             _ => return Err(DnsAnswer::new()),
@@ -43,22 +48,17 @@ impl DnsAnswer {
         bytes_read += 4;
         let data_length = NetworkEndian::read_u16(&bytes[bytes_read..]);
         bytes_read += 2;
-        let b = match qtype {
-            ResourceType::NS | ResourceType::CName => {
-                let (domain, num_read) = deserialize_domain_from_bytes(&packet_bytes, &bytes[bytes_read..]).unwrap();
-                bytes_read += num_read;
-                serialize_domain_to_bytes(&domain).to_vec()
-            },
-            ResourceType::AAAA => {
-                let rdata = bytes[bytes_read..(bytes_read + data_length as usize)].to_vec();
-                bytes_read += 6;
-                rdata
-            }
-            _ => {
-                let rdata = bytes[bytes_read..(bytes_read + data_length as usize)].to_vec();
-                bytes_read += 4;
-                rdata
-            }
+        let rdata = match bytes.get(bytes_read..(bytes_read + data_length as usize)) {
+            Some(rdata) => rdata,
+            None => return Err(DnsAnswer::new()),
+        };
+        bytes_read += data_length as usize;
+        // names in the rdata may point anywhere in the packet they came from,
+        // they are expanded here since the pointers mean nothing once we
+        // serialize the record into a packet of our own
+        let b = match decompress_rdata(packet_bytes, &qtype, rdata) {
+            Ok(b) => b,
+            Err(_) => return Err(DnsAnswer::new()),
         };
         Ok((
             DnsAnswer {
@@ -74,6 +74,147 @@ impl DnsAnswer {
     }
 }
 
+impl DnsAnswer {
+    /// The rdata in the same presentation format a zone file would use, e.g.
+    /// `10 mail.foo.com.` for an MX record. Anything we can't decode is
+    /// printed in the generic `\# <length> <hex>` format from RFC 3597
+    pub fn data_to_string(&self) -> String {
+        let decoded = match self.qtype {
+            ResourceType::A if self.rdata.len() == 4 => Some(
+                Ipv4Addr::new(self.rdata[0], self.rdata[1], self.rdata[2], self.rdata[3])
+                    .to_string(),
+            ),
+            ResourceType::AAAA if self.rdata.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.rdata);
+                Some(Ipv6Addr::from(octets).to_string())
+            }
+            ResourceType::CName | ResourceType::NS | ResourceType::PTR => {
+                rdata_domain(&self.rdata, 0).map(|(name, _)| name)
+            }
+            ResourceType::MX if self.rdata.len() > 2 => rdata_domain(&self.rdata, 2)
+                .map(|(name, _)| format!("{} {}", NetworkEndian::read_u16(&self.rdata), name)),
+            ResourceType::TXT => character_strings(&self.rdata).map(|strings| {
+                strings
+                    .iter()
+                    .map(|s| format!("{:?}", String::from_utf8_lossy(s)))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }),
+            ResourceType::SRV if self.rdata.len() > 6 => {
+                rdata_domain(&self.rdata, 6).map(|(target, _)| {
+                    let numbers = (0..3)
+                        .map(|i| NetworkEndian::read_u16(&self.rdata[i * 2..]).to_string())
+                        .collect::<Vec<String>>();
+                    format!("{} {}", numbers.join(" "), target)
+                })
+            }
+            ResourceType::StartOfAuthority => {
+                rdata_domain(&self.rdata, 0).and_then(|(mname, read)| {
+                    let (rname, more) = rdata_domain(&self.rdata, read)?;
+                    let numbers = &self.rdata[read + more..];
+                    if numbers.len() != 20 {
+                        return None;
+                    }
+                    let numbers = (0..5)
+                        .map(|i| NetworkEndian::read_u32(&numbers[i * 4..]).to_string())
+                        .collect::<Vec<String>>();
+                    Some(format!("{} {} {}", mname, rname, numbers.join(" ")))
+                })
+            }
+            _ => None,
+        };
+        decoded.unwrap_or_else(|| {
+            let hex = self
+                .rdata
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            format!("\\# {} {}", self.rdata.len(), hex)
+        })
+    }
+}
+
+fn decompress_rdata(
+    packet_bytes: &[u8],
+    qtype: &ResourceType,
+    rdata: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let name =
+        |start: usize| deserialize_domain_from_bytes(packet_bytes, rdata.get(start..).ok_or(())?);
+    Ok(match qtype {
+        ResourceType::NS | ResourceType::CName | ResourceType::PTR => {
+            serialize_domain_to_bytes(&name(0)?.0)
+        }
+        ResourceType::MX => {
+            let preference = rdata.get(..2).ok_or(())?;
+            let mut res = preference.to_vec();
+            res.extend(serialize_domain_to_bytes(&name(2)?.0));
+            res
+        }
+        // the target shouldn't be compressed (RFC 2782) but that doesn't
+        // stop everyone
+        ResourceType::SRV => {
+            let numbers = rdata.get(..6).ok_or(())?;
+            let mut res = numbers.to_vec();
+            res.extend(serialize_domain_to_bytes(&name(6)?.0));
+            res
+        }
+        ResourceType::StartOfAuthority => {
+            let (mname, read) = name(0)?;
+            let (rname, more) = name(read)?;
+            let numbers = &rdata[read + more..];
+            if numbers.len() != 20 {
+                return Err(());
+            }
+            let mut res = serialize_domain_to_bytes(&mname);
+            res.extend(serialize_domain_to_bytes(&rname));
+            res.extend(numbers);
+            res
+        }
+        _ => rdata.to_vec(),
+    })
+}
+
+/// Reads an uncompressed domain starting at `start`, as stored in the rdata
+/// of our answers, returning it fully qualified along with its length
+pub(crate) fn rdata_domain(rdata: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut pos = start;
+    loop {
+        let len = *rdata.get(pos)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        // pointers have no meaning outside of the packet they came from
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(rdata.get(pos + 1..pos + 1 + len)?).into_owned());
+        pos += len + 1;
+    }
+    Some((labels.join(".") + ".", pos - start))
+}
+
+/// The length prefixed strings a TXT record is made of
+pub(crate) fn character_strings(rdata: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut strings = Vec::new();
+    let mut pos = 0;
+    while pos < rdata.len() {
+        let len = rdata[pos] as usize;
+        strings.push(rdata.get(pos + 1..pos + 1 + len)?);
+        pos += len + 1;
+    }
+    Some(strings)
+}
+
+impl Default for DnsAnswer {
+    fn default() -> Self {
+        DnsAnswer::new()
+    }
+}
+
 impl ToBytes for DnsAnswer {
     fn to_bytes(&self) -> Vec<u8> {
         let mut res = Vec::new();
@@ -137,4 +278,77 @@ mod tests {
         assert_eq!(expected_bytes.len(), num_read);
         assert_eq!(expected_bytes.to_vec(), answer.to_bytes());
     }
+
+    #[test]
+    fn test_records_are_read_by_their_length() {
+        // foo.com, pointed to below
+        let mut packet = b"\x03foo\x03com\x00".to_vec();
+        // foo.com AAAA ::1
+        packet.extend(&[
+            0xc0, 0x00, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x10, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        ]);
+        // foo.com MX 10 mail.foo.com
+        packet.extend(&[
+            0xc0, 0x00, 0x00, 0x0f, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x09, 0x00, 0x0a,
+            0x04, 0x6d, 0x61, 0x69, 0x6c, 0xc0, 0x00,
+        ]);
+        let (aaaa, read) = DnsAnswer::from_bytes(&packet, &packet[9..]).unwrap();
+        assert_eq!(28, read);
+        assert_eq!("::1", aaaa.data_to_string());
+        let (mx, read) = DnsAnswer::from_bytes(&packet, &packet[37..]).unwrap();
+        assert_eq!(21, read);
+        assert_eq!("foo.com", mx.name);
+        assert_eq!("10 mail.foo.com.", mx.data_to_string());
+        assert_eq!(mx.rdata.len() as u16, mx.data_length);
+
+        // the rdata can't run past the end of the packet
+        DnsAnswer::from_bytes(&packet, &packet[9..30]).unwrap_err();
+    }
+
+    #[test]
+    fn test_data_to_string() {
+        let mut ans = DnsAnswer::new();
+        ans.qtype = ResourceType::A;
+        ans.rdata = vec![12, 34, 56, 78];
+        assert_eq!("12.34.56.78", ans.data_to_string());
+
+        ans.qtype = ResourceType::AAAA;
+        ans.rdata = vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!("::1", ans.data_to_string());
+
+        ans.qtype = ResourceType::CName;
+        ans.rdata = serialize_domain_to_bytes("bla.com");
+        assert_eq!("bla.com.", ans.data_to_string());
+
+        ans.qtype = ResourceType::MX;
+        ans.rdata = vec![0x00, 0x0a];
+        ans.rdata.extend(serialize_domain_to_bytes("mail.foo.com"));
+        assert_eq!("10 mail.foo.com.", ans.data_to_string());
+
+        ans.qtype = ResourceType::StartOfAuthority;
+        ans.rdata = serialize_domain_to_bytes("soa.foo.com");
+        ans.rdata.extend(serialize_domain_to_bytes("admin.foo.com"));
+        for n in 42..47 {
+            ans.rdata.write_u32::<NetworkEndian>(n).unwrap();
+        }
+        assert_eq!(
+            "soa.foo.com. admin.foo.com. 42 43 44 45 46",
+            ans.data_to_string()
+        );
+
+        ans.qtype = ResourceType::TXT;
+        ans.rdata = b"\x05hello\x0ea \"quoted\" one".to_vec();
+        assert_eq!(r#""hello" "a \"quoted\" one""#, ans.data_to_string());
+
+        ans.qtype = ResourceType::SRV;
+        ans.rdata = vec![0x00, 0x0a, 0x00, 0x3c, 0x13, 0xc4];
+        ans.rdata.extend(serialize_domain_to_bytes("sip.foo.com"));
+        assert_eq!("10 60 5060 sip.foo.com.", ans.data_to_string());
+
+        // a compression pointer left in the rdata can't be decoded on its own
+        ans.qtype = ResourceType::NS;
+        ans.rdata = vec![0x02, 0x6e, 0x73, 0xc0, 0x0c];
+        assert_eq!("\\# 5 026e73c00c", ans.data_to_string());
+    }
 }
@@ -1,4 +1,3 @@
-use std::env;
 use std::fs::{create_dir, read_dir, read_to_string};
 use std::io::ErrorKind;
 use std::path::Path;
@@ -8,7 +7,7 @@ use yaml_rust::{Yaml, YamlLoader};
 use crate::header::ResourceType;
 use crate::record::{extract_string, Record, SoaInformation};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Authority {
     pub default_ttl: usize,
     pub origin: String,
@@ -24,62 +23,82 @@ impl Authority {
         }
     }
 
-    pub fn new_from_yaml(yaml: &Yaml) -> Self {
+    pub fn new_from_yaml(yaml: &Yaml) -> Result<Self, String> {
         let mut records = Vec::new();
         match &yaml["records"] {
             Yaml::Array(arr) => {
                 for record_yaml in arr {
-                    records.push(Record::from_yaml(&record_yaml));
+                    records.push(Record::from_yaml(&record_yaml)?);
                 }
             }
-            _ => panic!("The 'records' field must be an array"),
+            _ => return Err("The 'records' field must be an array".to_owned()),
         }
         let authority = Authority {
-            default_ttl: yaml["ttl"].as_i64().expect("Invalid yaml file") as usize,
-            origin: yaml["origin"].as_str().unwrap().to_owned(),
+            default_ttl: yaml["ttl"].as_i64().ok_or("'ttl' must be an integer")? as usize,
+            origin: yaml["origin"]
+                .as_str()
+                .ok_or("'origin' must be a string")?
+                .to_owned(),
             records,
         };
-        authority.check_has_one_authority_record();
-        authority
+        authority.check_has_one_authority_record()?;
+        Ok(authority)
     }
 
-    /// Panics if `self.records` does not have one and only one SOA record
-    fn check_has_one_authority_record(&self) {
+    /// Fails if `self.records` does not have one and only one SOA record
+    fn check_has_one_authority_record(&self) -> Result<(), String> {
         let soa_count = self
             .records
             .iter()
             .filter(|rec| rec.rec_type == ResourceType::StartOfAuthority)
             .count();
-        assert_eq!(1, soa_count, "You must have one SOA record in each file");
+        if soa_count != 1 {
+            return Err("You must have one SOA record in each file".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Loads every zone file found in `dirs`, directories that don't exist yet are
+/// created. Nothing is returned unless every file is valid
+pub fn authorities(dirs: &[String]) -> Result<Vec<Authority>, String> {
+    let mut auths = Vec::new();
+    for dir in dirs {
+        auths.extend(authorities_in(dir)?);
     }
+    Ok(auths)
 }
 
-pub fn authorities() -> Vec<Authority> {
-    let authority_dir = env::var("AUTHORITY_DIR").unwrap_or("authorities".to_owned());
-    let files = match read_dir(&authority_dir) {
+fn authorities_in(authority_dir: &str) -> Result<Vec<Authority>, String> {
+    let files = match read_dir(authority_dir) {
         Ok(files) => files,
         Err(e) => match e.kind() {
             ErrorKind::NotFound => {
-                create_dir(&authority_dir).expect("Could not create the authorities directory");
-                return authorities();
+                create_dir(authority_dir)
+                    .map_err(|_| "Could not create the authorities directory".to_owned())?;
+                return authorities_in(authority_dir);
             }
-            _ => panic!("An unexpected io error happened"),
+            _ => return Err(format!("Could not read {}: {}", authority_dir, e)),
         },
     };
     let mut auths = Vec::new();
     for auth in files {
-        let os_file_name = auth.unwrap().file_name();
+        let os_file_name = auth.map_err(|e| e.to_string())?.file_name();
         let file_name = os_file_name
             .to_str()
-            .expect("We do not support your operating system");
-        let file_location = Path::new(&authority_dir).join(file_name);
-        let yaml_arr = YamlLoader::load_from_str(&read_to_string(&file_location).unwrap())
-            .expect(&format!("Invalid yaml in {}", file_name));
+            .ok_or("We do not support your operating system")?;
+        let file_location = Path::new(authority_dir).join(file_name);
+        let contents = read_to_string(&file_location)
+            .map_err(|e| format!("Could not read {}: {}", file_name, e))?;
+        let yaml_arr = YamlLoader::load_from_str(&contents)
+            .map_err(|_| format!("Invalid yaml in {}", file_name))?;
         for yaml in yaml_arr {
-            auths.push(Authority::new_from_yaml(&yaml));
+            auths.push(
+                Authority::new_from_yaml(&yaml).map_err(|e| format!("{}: {}", file_name, e))?,
+            );
         }
     }
-    auths
+    Ok(auths)
 }
 
 #[cfg(test)]
@@ -87,7 +106,6 @@ mod tests {
     use super::*;
     use crate::header::ResourceType;
     use crate::record::RecordInformation;
-    use std::env;
     use std::fs::{create_dir, read_dir, remove_dir, remove_dir_all, File};
     use std::io::Write;
     use std::path::Path;
@@ -114,10 +132,11 @@ records:
       minimum: 46
 ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        let actual_authority = Authority::new_from_yaml(&yaml[0]);
+        let actual_authority = Authority::new_from_yaml(&yaml[0]).unwrap();
         let mut expected_authority = Authority::new();
         // we already test for this in another test so we can reuse it here
-        let expected_soa_information = SoaInformation::from_yaml(&yaml[0]["records"][0]["data"]);
+        let expected_soa_information =
+            SoaInformation::from_yaml(&yaml[0]["records"][0]["data"]).unwrap();
         expected_authority.default_ttl = 60;
         expected_authority.origin = "foo.com".to_owned();
         expected_authority.records.push(Record::new());
@@ -129,7 +148,6 @@ records:
     }
 
     #[test]
-    #[should_panic]
     fn test_fails_with_two_soa_records() {
         let input = "
 ttl: 60
@@ -163,11 +181,10 @@ records:
       minimum: 46
 ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        Authority::new_from_yaml(&yaml[0]);
+        Authority::new_from_yaml(&yaml[0]).unwrap_err();
     }
 
     #[test]
-    #[should_panic]
     fn test_fails_with_zero_soa_records() {
         let input = "
 ttl: 60
@@ -175,14 +192,13 @@ origin: foo.com
 records: []
 ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        Authority::new_from_yaml(&yaml[0]);
+        Authority::new_from_yaml(&yaml[0]).unwrap_err();
     }
 
     #[test]
     fn test_authorities_with_real_files() {
         let temp_authorities_dir = TempDir::new("authorities").unwrap();
         let authority_file_path = temp_authorities_dir.path().join("authority1.yml");
-        env::set_var("AUTHORITY_DIR", temp_authorities_dir.path());
         let mut authority_file = File::create(authority_file_path).unwrap();
         let input = b"
 ttl: 60
@@ -203,11 +219,13 @@ records:
       minimum: 46
 ";
         authority_file.write_all(input).unwrap();
-        let auths = authorities();
+        let auths =
+            authorities(&[temp_authorities_dir.path().to_str().unwrap().to_owned()]).unwrap();
         let yaml = YamlLoader::load_from_str(std::str::from_utf8(input).unwrap()).unwrap();
         let mut expected_authority = Authority::new();
         // we already test for this in another test so we can reuse it here
-        let expected_soa_information = SoaInformation::from_yaml(&yaml[0]["records"][0]["data"]);
+        let expected_soa_information =
+            SoaInformation::from_yaml(&yaml[0]["records"][0]["data"]).unwrap();
         expected_authority.default_ttl = 60;
         expected_authority.origin = "foo.com".to_owned();
         expected_authority.records.push(Record::new());
@@ -220,9 +238,10 @@ records:
 
     #[test]
     fn test_create_authorities_directory_if_absent() {
-        let authority_dir = env::var("AUTHORITY_DIR").unwrap_or("authorities".to_owned());
+        let temp_dir = TempDir::new("zones").unwrap();
+        let authority_dir = temp_dir.path().join("authorities");
         read_dir(&authority_dir).expect_err("The authorities directory already exists");
-        authorities();
+        authorities(&[authority_dir.to_str().unwrap().to_owned()]).unwrap();
         read_dir(&authority_dir).unwrap();
         // we want to use `remove_dir` instead of `remove_dir_all` because we expect the
         // `authorities` directory to be empty
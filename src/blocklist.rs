@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use std::env;
 use std::fs::{read_to_string, File};
 use std::path::Path;
 
@@ -28,37 +27,52 @@ fn validate_blocklist_entry(s: &str) -> Result<(String, bool), String> {
     Ok((s[(pos.unwrap() + 2)..].to_owned(), true))
 }
 
-pub fn load_blocklist() -> HashMap<String, bool> {
-    let filename = env::var("BLOCKLIST_FILE").unwrap_or("blocklist.yml".to_owned());
-    if !Path::new(&filename).exists() {
-        File::create(&filename).unwrap();
+/// Merges every blocklist file in `filenames` into one, files that don't exist
+/// yet are created. Nothing is returned unless every file is valid
+pub fn load_blocklist(filenames: &[String]) -> Result<HashMap<String, bool>, String> {
+    let mut res = HashMap::new();
+    for filename in filenames {
+        load_blocklist_file(filename, &mut res).map_err(|e| format!("{}: {}", filename, e))?;
+    }
+    Ok(res)
+}
+
+fn load_blocklist_file(filename: &str, res: &mut HashMap<String, bool>) -> Result<(), String> {
+    if !Path::new(filename).exists() {
+        File::create(filename).map_err(|_| "Could not create blocklist file".to_owned())?;
     }
     let yaml_arr = YamlLoader::load_from_str(
-        &read_to_string(&filename).expect("Could not load blocklist file"),
+        &read_to_string(filename).map_err(|_| "Could not load blocklist file".to_owned())?,
     )
-    .expect("Could not load blocklist yaml file");
-    let mut res = HashMap::new();
+    .map_err(|_| "Could not load blocklist yaml file".to_owned())?;
     if yaml_arr.len() > 0 {
         match &yaml_arr[0] {
             Yaml::Array(a) => {
                 for s in a {
                     let blocked = match s {
                         Yaml::String(s) => s,
-                        _ => panic!("The blocklist file can only be a list of strings"),
+                        _ => {
+                            return Err(
+                                "The blocklist file can only be a list of strings".to_owned()
+                            )
+                        }
                     };
-                    let (domain, contains_wildcard) = validate_blocklist_entry(&blocked).unwrap();
-                    res.insert(domain, contains_wildcard);
+                    let (domain, contains_wildcard) = validate_blocklist_entry(&blocked)?;
+                    // a wildcard from any of the files wins
+                    *res.entry(domain).or_insert(false) |= contains_wildcard;
                 }
             }
-            _ => panic!("The blocklist file can only be a list of strings"),
+            _ => return Err("The blocklist file can only be a list of strings".to_owned()),
         }
     }
-    res
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
 
     #[test]
     fn test_validate_blocklist_entry() {
@@ -70,4 +84,36 @@ mod tests {
             validate_blocklist_entry("*.foo.com").unwrap()
         );
     }
+
+    #[test]
+    fn test_load_multiple_blocklists() {
+        let temp_dir = TempDir::new("blocklists").unwrap();
+        let first = temp_dir.path().join("first.yml");
+        let second = temp_dir.path().join("second.yml");
+        let missing = temp_dir.path().join("missing.yml");
+        File::create(&first)
+            .unwrap()
+            .write_all(b"- foo.com\n- bar.com\n")
+            .unwrap();
+        File::create(&second)
+            .unwrap()
+            .write_all(b"- '*.foo.com'\n")
+            .unwrap();
+        let files = [&first, &second, &missing]
+            .iter()
+            .map(|path| path.to_str().unwrap().to_owned())
+            .collect::<Vec<String>>();
+        let mut expected = HashMap::new();
+        expected.insert("foo.com".to_owned(), true);
+        expected.insert("bar.com".to_owned(), false);
+        assert_eq!(expected, load_blocklist(&files).unwrap());
+        // missing files are created empty
+        assert!(missing.exists());
+
+        File::create(&second)
+            .unwrap()
+            .write_all(b"- '*.'\n")
+            .unwrap();
+        load_blocklist(&files).unwrap_err();
+    }
 }
@@ -1,5 +1,350 @@
+//! The responses we got from other nameservers, kept for as long as the
+//! records in them are good for so that asking again doesn't go out
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use ttl_cache::TtlCache;
+
 use crate::answer::DnsAnswer;
+use crate::header::{ResourceType, ResponseCode};
+use crate::hints::normalize;
+use crate::packet::DnsPacket;
 use crate::query::DnsQuery;
-use ttl_cache::TtlCache;
 
-pub type Cache = TtlCache<DnsQuery, DnsAnswer>;
+/// The question a response is for: name, type and class
+type Key = (String, u16, u16);
+
+/// Where the cache gets the time from, tests move it along themselves
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// What we keep of a response, with the TTLs its records had when it came in
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    response_code: ResponseCode,
+    answers: Vec<DnsAnswer>,
+    authority: Vec<DnsAnswer>,
+    additional: Vec<DnsAnswer>,
+    stored: Instant,
+    expires: Instant,
+}
+
+pub struct Cache {
+    entries: Mutex<TtlCache<Key, Entry>>,
+    min_ttl: u32,
+    max_ttl: u32,
+    clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl Cache {
+    /// A cache of the responses to up to `size` questions
+    pub fn new(size: usize) -> Self {
+        Cache {
+            entries: Mutex::new(TtlCache::new(size)),
+            min_ttl: 0,
+            max_ttl: u32::MAX,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Keeps the TTLs of what we store within these, so that records are
+    /// neither refetched too often nor kept around for too long
+    pub fn with_ttl_limits(mut self, min_ttl: u32, max_ttl: u32) -> Self {
+        self.min_ttl = min_ttl;
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock + Send + Sync>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The response we have for `query`, all sections of it. The TTLs are
+    /// what's left of them, the time it's been in the cache taken off
+    pub fn get(&self, query: &DnsQuery) -> Option<DnsPacket> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = self.clock.now();
+        let entry = entries.get(&key(query))?;
+        if now >= entry.expires {
+            entries.remove(&key(query));
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(entry.stored).as_secs();
+        let remaining = |records: &[DnsAnswer]| {
+            records
+                .iter()
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.ttl = (record.ttl as u64).saturating_sub(elapsed) as u32;
+                    record
+                })
+                .collect()
+        };
+        let mut res = DnsPacket::new_response();
+        // we only ask around, and so have anything cached, when we recurse
+        res.header.recursion_available = true;
+        res.header.response_code = entry.response_code.clone();
+        res.answers = remaining(&entry.answers);
+        res.authority = remaining(&entry.authority);
+        res.additional = remaining(&entry.additional);
+        Some(res)
+    }
+
+    /// Keeps `res`, the response to `query`, until the first of its records
+    /// expires. Names that don't exist and names without records of the type
+    /// asked for are kept for as long as the SOA that came with them says
+    /// (RFC 2308), anything else isn't kept at all. The TTLs are brought
+    /// within our limits first, `res` is returned the way we keep it
+    pub fn insert(&self, query: &DnsQuery, mut res: DnsPacket) -> DnsPacket {
+        for record in res
+            .answers
+            .iter_mut()
+            .chain(res.authority.iter_mut())
+            .chain(res.additional.iter_mut())
+        {
+            record.ttl = self.clamp(record.ttl);
+        }
+        let ttl = match res.header.response_code {
+            ResponseCode::NoError if !res.answers.is_empty() => res
+                .answers
+                .iter()
+                .chain(&res.authority)
+                .chain(&res.additional)
+                .map(|record| record.ttl)
+                .min(),
+            ResponseCode::NoError | ResponseCode::NameError => {
+                negative_ttl(&res).map(|ttl| self.clamp(ttl))
+            }
+            _ => None,
+        };
+        let ttl = match ttl {
+            Some(ttl) if ttl > 0 => Duration::from_secs(ttl as u64),
+            _ => return res,
+        };
+        let stored = self.clock.now();
+        let entry = Entry {
+            response_code: res.header.response_code.clone(),
+            answers: res.answers.clone(),
+            authority: res.authority.clone(),
+            additional: res.additional.clone(),
+            stored,
+            expires: stored + ttl,
+        };
+        self.entries.lock().unwrap().insert(key(query), entry, ttl);
+        res
+    }
+
+    fn clamp(&self, ttl: u32) -> u32 {
+        ttl.max(self.min_ttl).min(self.max_ttl)
+    }
+}
+
+fn key(query: &DnsQuery) -> Key {
+    (normalize(&query.name), query.qtype.as_u16(), query.class)
+}
+
+/// How long the SOA in a negative response says we may remember that there's
+/// nothing, the smaller of its TTL and its minimum field
+pub fn negative_ttl(res: &DnsPacket) -> Option<u32> {
+    res.authority
+        .iter()
+        .find(|record| record.qtype == ResourceType::StartOfAuthority && record.rdata.len() >= 4)
+        .map(|soa| {
+            let minimum = NetworkEndian::read_u32(&soa.rdata[soa.rdata.len() - 4..]);
+            soa.ttl.min(minimum)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::serialize_domain_to_bytes;
+    use pretty_assertions::assert_eq;
+
+    fn record(name: &str, qtype: ResourceType, ttl: u32, rdata: Vec<u8>) -> DnsAnswer {
+        let mut record = DnsAnswer::new();
+        record.name = name.to_owned();
+        record.qtype = qtype;
+        record.ttl = ttl;
+        record.data_length = rdata.len() as u16;
+        record.rdata = rdata;
+        record
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DnsAnswer {
+        let mut rdata = serialize_domain_to_bytes("ns.foo.com");
+        rdata.extend(serialize_domain_to_bytes("admin.foo.com"));
+        for n in &[1, 2, 3, 4, minimum] {
+            rdata.extend(&n.to_be_bytes());
+        }
+        record("foo.com", ResourceType::StartOfAuthority, ttl, rdata)
+    }
+
+    /// A clock that only moves when told to
+    struct TestClock(Mutex<Instant>);
+
+    impl TestClock {
+        fn advance(&self, secs: u64) {
+            *self.0.lock().unwrap() += Duration::from_secs(secs);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn query(name: &str, qtype: ResourceType) -> DnsQuery {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = qtype;
+        query
+    }
+
+    #[test]
+    fn test_whole_responses_are_cached() {
+        let cache = Cache::new(10);
+        let mut res = DnsPacket::new_response();
+        res.answers = vec![
+            record(
+                "www.foo.com",
+                ResourceType::CName,
+                300,
+                serialize_domain_to_bytes("foo.com"),
+            ),
+            record("foo.com", ResourceType::A, 60, vec![10, 0, 0, 1]),
+            record("foo.com", ResourceType::A, 60, vec![10, 0, 0, 2]),
+        ];
+        res.authority = vec![record(
+            "foo.com",
+            ResourceType::NS,
+            3600,
+            serialize_domain_to_bytes("ns.foo.com"),
+        )];
+        res.additional = vec![record(
+            "ns.foo.com",
+            ResourceType::A,
+            3600,
+            vec![10, 0, 0, 53],
+        )];
+        cache.insert(&query("www.foo.com", ResourceType::A), res.clone());
+
+        let cached = cache.get(&query("WWW.foo.com.", ResourceType::A)).unwrap();
+        assert_eq!(res.answers, cached.answers);
+        assert_eq!(res.authority, cached.authority);
+        assert_eq!(res.additional, cached.additional);
+        assert_eq!(ResponseCode::NoError, cached.header.response_code);
+        assert_eq!(None, cache.get(&query("www.foo.com", ResourceType::AAAA)));
+        assert_eq!(None, cache.get(&query("foo.com", ResourceType::A)));
+    }
+
+    #[test]
+    fn test_negative_responses_are_cached_for_the_soa_ttl() {
+        let cache = Cache::new(10);
+        let mut res = DnsPacket::new_error(ResponseCode::NameError);
+        res.authority = vec![soa(3600, 60)];
+        assert_eq!(Some(60), negative_ttl(&res));
+        res.authority[0].ttl = 30;
+        assert_eq!(Some(30), negative_ttl(&res));
+        cache.insert(&query("nope.foo.com", ResourceType::A), res.clone());
+        let cached = cache.get(&query("nope.foo.com", ResourceType::A)).unwrap();
+        assert_eq!(ResponseCode::NameError, cached.header.response_code);
+        assert_eq!(res.authority, cached.authority);
+
+        // no records of the type asked for
+        let mut res = DnsPacket::new_response();
+        res.authority = vec![soa(3600, 60)];
+        cache.insert(&query("foo.com", ResourceType::AAAA), res);
+        let cached = cache.get(&query("foo.com", ResourceType::AAAA)).unwrap();
+        assert!(cached.answers.is_empty());
+    }
+
+    #[test]
+    fn test_what_isnt_cached() {
+        let cache = Cache::new(10);
+        // without an SOA we don't know for how long
+        cache.insert(
+            &query("nope.foo.com", ResourceType::A),
+            DnsPacket::new_error(ResponseCode::NameError),
+        );
+        let mut failed = DnsPacket::new_error(ResponseCode::ServerError);
+        failed.authority = vec![soa(3600, 60)];
+        cache.insert(&query("foo.com", ResourceType::A), failed);
+        let mut res = DnsPacket::new_response();
+        res.answers = vec![record("foo.com", ResourceType::MX, 0, vec![0, 10, 0])];
+        cache.insert(&query("foo.com", ResourceType::MX), res);
+
+        assert_eq!(None, cache.get(&query("nope.foo.com", ResourceType::A)));
+        assert_eq!(None, cache.get(&query("foo.com", ResourceType::A)));
+        assert_eq!(None, cache.get(&query("foo.com", ResourceType::MX)));
+    }
+
+    #[test]
+    fn test_ttls_count_down() {
+        let clock = Arc::new(TestClock(Mutex::new(Instant::now())));
+        let cache = Cache::new(10).with_clock(clock.clone());
+        let mut res = DnsPacket::new_response();
+        res.answers = vec![
+            record("foo.com", ResourceType::A, 60, vec![10, 0, 0, 1]),
+            record("foo.com", ResourceType::A, 120, vec![10, 0, 0, 2]),
+        ];
+        let foo = query("foo.com", ResourceType::A);
+        cache.insert(&foo, res);
+
+        let ttls = |res: DnsPacket| res.answers.iter().map(|a| a.ttl).collect::<Vec<u32>>();
+        assert_eq!(vec![60, 120], ttls(cache.get(&foo).unwrap()));
+        clock.advance(30);
+        assert_eq!(vec![30, 90], ttls(cache.get(&foo).unwrap()));
+        clock.advance(29);
+        assert_eq!(vec![1, 61], ttls(cache.get(&foo).unwrap()));
+        // gone with the first of its records
+        clock.advance(1);
+        assert_eq!(None, cache.get(&foo));
+    }
+
+    #[test]
+    fn test_ttls_are_clamped() {
+        let clock = Arc::new(TestClock(Mutex::new(Instant::now())));
+        let cache = Cache::new(10)
+            .with_ttl_limits(10, 100)
+            .with_clock(clock.clone());
+        let mut res = DnsPacket::new_response();
+        res.answers = vec![
+            record("foo.com", ResourceType::A, 1, vec![10, 0, 0, 1]),
+            record("foo.com", ResourceType::A, 3600, vec![10, 0, 0, 2]),
+        ];
+        let foo = query("foo.com", ResourceType::A);
+        let res = cache.insert(&foo, res);
+        assert_eq!(
+            vec![10, 100],
+            res.answers.iter().map(|a| a.ttl).collect::<Vec<u32>>()
+        );
+        clock.advance(9);
+        assert_eq!(1, cache.get(&foo).unwrap().answers[0].ttl);
+        clock.advance(1);
+        assert_eq!(None, cache.get(&foo));
+
+        // negative responses are kept within the limits too
+        let mut res = DnsPacket::new_error(ResponseCode::NameError);
+        res.authority = vec![soa(3600, 3600)];
+        let nope = query("nope.foo.com", ResourceType::A);
+        cache.insert(&nope, res);
+        clock.advance(99);
+        assert!(cache.get(&nope).is_some());
+        clock.advance(1);
+        assert_eq!(None, cache.get(&nope));
+    }
+}
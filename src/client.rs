@@ -1,38 +1,127 @@
 use crate::answer::DnsAnswer;
-use crate::authority::authorities;
-use crate::blocklist;
+use crate::authority::{authorities, Authority};
+use crate::blocklist::load_blocklist;
 use crate::cache::Cache;
+use crate::config::Config;
 use crate::header::{ResourceType, ResponseCode};
+use crate::hints::normalize;
+use crate::hosts::{load_hosts, Hosts};
 use crate::packet::DnsPacket;
 use crate::query::DnsQuery;
 use crate::record::{RecordInformation, SoaInformation};
+use crate::resolvers::{Limits, ResolveError, Resolver};
 use crate::serialization::{
     deserialize_ipv4_from_str, deserialize_ipv6_from_str, serialize_domain_to_bytes, ToBytes, deserialize_domain_from_bytes,
 };
 use std::collections::HashMap;
-use std::fs::read_to_string;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// What we serve from files on disk, it is only ever replaced as a whole so a
+/// query never sees the zones of one reload and the blocklist of another
+#[derive(Clone)]
+struct LocalData {
+    authorities: Vec<Authority>,
+    blocklist: HashMap<String, bool>,
+    hosts: Hosts,
+}
+
+/// The longest chain of CNAMEs we follow, anything longer is more likely a
+/// loop than someone's actual setup
+const MAX_CNAME_CHAIN: usize = 8;
 
-pub struct DnsClient<'a, F>
+pub struct DnsClient<'a, R>
 where
-    F: Fn(&str, DnsPacket, u16) -> DnsPacket,
+    R: Resolver,
 {
-    resolver: F,
-    cache: &'a mut Cache,
-    blocklist: HashMap<String, bool>,
+    /// Answers whatever isn't in our own zones
+    resolver: R,
+    /// What other nameservers told us before
+    cache: &'a Cache,
+    local: RwLock<Arc<LocalData>>,
+    recursion: bool,
+    timeout: Duration,
+    attempts: u32,
+    /// How long a client query may take in total
+    deadline: Duration,
 }
 
-impl<'a, F> DnsClient<'a, F>
+impl<'a, R> DnsClient<'a, R>
 where
-    F: Fn(&str, DnsPacket, u16) -> DnsPacket,
+    R: Resolver,
 {
-    pub fn new(resolver: F, cache: &'a mut Cache, blocklist: HashMap<String, bool>) -> Self {
+    pub fn new(resolver: R, cache: &'a Cache, blocklist: HashMap<String, bool>) -> Self {
+        let config = Config::new();
         DnsClient {
             resolver,
             cache,
-            blocklist,
+            local: RwLock::new(Arc::new(LocalData {
+                authorities: Vec::new(),
+                blocklist,
+                hosts: Hosts::default(),
+            })),
+            recursion: config.recursion.enabled,
+            timeout: config.recursion.timeout,
+            attempts: config.recursion.attempts,
+            deadline: config.recursion.deadline,
         }
     }
 
+    /// The zones we are the authority for
+    pub fn with_authorities(self, authorities: Vec<Authority>) -> Self {
+        let mut local = (*self.local()).clone();
+        local.authorities = authorities;
+        *self.local.write().unwrap() = Arc::new(local);
+        self
+    }
+
+    /// The names from hosts files we answer for
+    pub fn with_hosts(self, hosts: Hosts) -> Self {
+        let mut local = (*self.local()).clone();
+        local.hosts = hosts;
+        *self.local.write().unwrap() = Arc::new(local);
+        self
+    }
+
+    /// Applies the recursion settings from `config`, the resolver is
+    /// built from the rest of it
+    pub fn configure(mut self, config: &Config) -> Self {
+        self.recursion = config.recursion.enabled;
+        self.timeout = config.recursion.timeout;
+        self.attempts = config.recursion.attempts;
+        self.deadline = config.recursion.deadline;
+        self
+    }
+
+    /// Loads the zones, blocklists and hosts files named in `config` again and
+    /// swaps them in for the ones we are serving, if any file is invalid we
+    /// keep what we had
+    pub fn reload(&self, config: &Config) -> Result<(), String> {
+        let authorities = authorities(&config.zones)?;
+        let blocklist = load_blocklist(&config.blocklists)?;
+        let hosts = load_hosts(&config.hosts)?;
+        *self.local.write().unwrap() = Arc::new(LocalData {
+            authorities,
+            blocklist,
+            hosts,
+        });
+        Ok(())
+    }
+
+    /// Like `reload` but only for the hosts files
+    pub fn reload_hosts(&self, config: &Config) -> Result<(), String> {
+        let hosts = load_hosts(&config.hosts)?;
+        let mut local = self.local.write().unwrap();
+        let mut reloaded = (**local).clone();
+        reloaded.hosts = hosts;
+        *local = Arc::new(reloaded);
+        Ok(())
+    }
+
+    fn local(&self) -> Arc<LocalData> {
+        self.local.read().unwrap().clone()
+    }
+
     /// Given `self` is a request packet, `results()` will return the packet
     /// to send back
     pub fn results(&self, req: DnsPacket) -> Result<DnsPacket, ()> {
@@ -51,10 +140,10 @@ where
 
     /// Assumes the `domain` has at least one '.' else it will panic.
     /// Returns `true` if the domain is not in the blocklist.
-    fn check_blocklist(&self, domain: &str) -> bool {
+    fn check_blocklist(blocklist: &HashMap<String, bool>, domain: &str) -> bool {
         // if `domain` or any of its super-domains (with `true` as that value
-        // in the hash map) are present in `self.blocklist` return `false`
-        if self.blocklist.contains_key(domain) {
+        // in the hash map) are present in `blocklist` return `false`
+        if blocklist.contains_key(domain) {
             return false;
         }
 
@@ -67,7 +156,7 @@ where
             } else {
                 suffix = part.to_owned() + "." + suffix.as_str();
             }
-            match self.blocklist.get(&suffix) {
+            match blocklist.get(&suffix) {
                 Some(include_suffix) => {
                     if *include_suffix {
                         return false;
@@ -86,81 +175,157 @@ where
             res.header.response_code = ResponseCode::NotImplemented;
             return Ok(res);
         }
-        let mut answers: Vec<DnsAnswer> = Vec::new();
-        let query = req.queries.first().unwrap();
-        if self.cache.contains_key(&query) {
-            answers.push(self.cache.get(&query).unwrap().clone());
-            res.answers = vec![self.cache.get(&query).unwrap().clone()];
-            Ok(res)
-        } else {
-            // either we own the tld, or we need to get it
-            let parts = query.name.split(".").collect::<Vec<&str>>();
-            if parts.len() < 2 {
-                // invalid domain
-                res.header.response_code = ResponseCode::NameError;
-                res.header.is_response = true;
-                return Ok(res);
-            }
-            // check blocklist
-            if !self.check_blocklist(&query.name) {
-                return Err(());
+        // no question at all, or one we couldn't parse
+        let query = match (req.header.questions_count, req.queries.first()) {
+            (1, Some(query)) => query,
+            _ => return Ok(reply(&req, DnsPacket::new_error(ResponseCode::FormatError))),
+        };
+        // either we own the tld, or we need to get it
+        let parts = query.name.split(".").collect::<Vec<&str>>();
+        let local = self.local();
+        // hosts files are full of names like `localhost`
+        if parts.len() < 2 && !local.hosts.contains(&query.name) {
+            // invalid domain
+            res.header.response_code = ResponseCode::NameError;
+            res.header.is_response = true;
+            return Ok(res);
+        }
+        // check blocklist
+        if !Self::check_blocklist(&local.blocklist, &query.name) {
+            return Err(());
+        }
+        let limits = Limits {
+            timeout: self.timeout,
+            attempts: self.attempts,
+            deadline: Instant::now() + self.deadline,
+        };
+        let mut name = query.name.clone();
+        let mut chain = Vec::new();
+        let mut authoritative = None;
+        loop {
+            let mut step = req.clone();
+            step.queries[0].name = name.clone();
+            let mut res = self.lookup(&local, step, limits);
+            // the AA bit is about the name that was asked for
+            let authoritative = *authoritative.get_or_insert(res.header.authoritative);
+            let chased = follow_cnames(&res.answers, &name, &query.qtype, &mut chain);
+            if chain.len() > MAX_CNAME_CHAIN {
+                return Ok(reply(&req, DnsPacket::new_error(ResponseCode::ServerError)));
             }
-            let tld = parts.last().unwrap();
-            let auths = authorities();
-            // check custom tlds
-            for tld_match in auths
-                .iter()
-                .filter(|a| a.origin.split(".").last().unwrap_or("") == *tld)
-            {
-                for record in &tld_match.records {
-                    let name = record.name.clone() + "." + &tld_match.origin;
-                    if query.qtype == record.rec_type && query.name == name {
-                        // we are the authority for this record
-                        let mut ans = DnsAnswer::new();
-                        ans.ttl = record.ttl;
-                        ans.name = name;
-                        ans.qtype = query.qtype.clone();
-                        match &record.data {
-                            RecordInformation::A(data) => {
-                                ans.data_length = 4;
-                                ans.rdata = deserialize_ipv4_from_str(&data);
-                            }
-                            RecordInformation::NS(data) => {
-                                ans.rdata = serialize_domain_to_bytes(data);
-                                ans.data_length = ans.rdata.len() as u16;
-                            }
-                            RecordInformation::AAAA(data) => {
-                                ans.data_length = 16;
-                                ans.rdata = deserialize_ipv6_from_str(&data);
-                            }
-                            RecordInformation::CName(data) => {
-                                ans.rdata = serialize_domain_to_bytes(data);
-                                ans.data_length = ans.rdata.len() as u16;
-                            }
-                            RecordInformation::Soa(data) => {
-                                ans.rdata = data.to_bytes();
-                                ans.data_length = ans.rdata.len() as u16;
-                            }
-                            RecordInformation::MX(data) => {
-                                ans.rdata = data.to_bytes();
-                                ans.data_length = ans.rdata.len() as u16;
-                            }
+            match chased {
+                Chased::Continues(target) if res.header.response_code == ResponseCode::NoError => {
+                    if !Self::check_blocklist(&local.blocklist, &target) {
+                        return Err(());
+                    }
+                    name = target;
+                }
+                chased => {
+                    if !chain.is_empty() {
+                        res.answers = chain;
+                        if let Chased::Answered(rrset) = chased {
+                            res.answers.extend(rrset);
                         }
-                        let mut res = DnsPacket::new_response();
-                        res.header.authoritative = true;
-                        res.header.answers_count = 1;
-                        res.header.questions_count = 1;
-                        res.header.tx_id = req.header.tx_id;
-                        res.queries = req.queries;
-                        res.answers = vec![ans];
-                        return Ok(res);
+                    }
+                    res.header.authoritative = authoritative;
+                    return Ok(reply(&req, res));
+                }
+            }
+        }
+    }
+
+    /// Answers a single step of a query, from the hosts files or our own zones
+    /// if we can and by asking around otherwise
+    fn lookup(&self, local: &LocalData, req: DnsPacket, limits: Limits) -> DnsPacket {
+        let query = req.queries.first().unwrap();
+        if let Some(answers) = local.hosts.answers(query) {
+            let mut res = DnsPacket::new_response();
+            res.header.authoritative = true;
+            res.answers = answers;
+            return res;
+        }
+        let answers = Self::authoritative_answers(local, query);
+        if !answers.is_empty() {
+            let mut res = DnsPacket::new_response();
+            res.header.authoritative = true;
+            res.answers = answers;
+            return res;
+        }
+        // check local authorities for the address, else go to the web
+        if !self.recursion {
+            return DnsPacket::new_error(ResponseCode::Refused);
+        }
+        if let Some(res) = self.cache.get(query) {
+            return res;
+        }
+        match self.resolver.resolve(&req, limits) {
+            Ok(res) => self.cache.insert(query, res),
+            Err(ResolveError::Refused) => DnsPacket::new_error(ResponseCode::Refused),
+            Err(e) => {
+                eprintln!("Could not resolve {} {:?}: {}", query.name, query.qtype, e);
+                DnsPacket::new_error(e.response_code())
+            }
+        }
+    }
+
+    /// The records of our own zones for `query`, or the CNAME for its name
+    /// when there are none of the type asked for
+    fn authoritative_answers(local: &LocalData, query: &DnsQuery) -> Vec<DnsAnswer> {
+        let tld = query.name.rsplit('.').next().unwrap_or("");
+        let mut answers = Vec::new();
+        let mut cnames = Vec::new();
+        // check custom tlds
+        for tld_match in local
+            .authorities
+            .iter()
+            .filter(|a| a.origin.split(".").last().unwrap_or("") == tld)
+        {
+            for record in &tld_match.records {
+                let name = record.name.clone() + "." + &tld_match.origin;
+                if query.name != name {
+                    continue;
+                }
+                // we are the authority for this record
+                let mut ans = DnsAnswer::new();
+                ans.ttl = record.ttl;
+                ans.name = name;
+                ans.qtype = record.rec_type.clone();
+                match &record.data {
+                    RecordInformation::A(data) => {
+                        ans.data_length = 4;
+                        ans.rdata = deserialize_ipv4_from_str(&data);
+                    }
+                    RecordInformation::NS(data) => {
+                        ans.rdata = serialize_domain_to_bytes(data);
+                        ans.data_length = ans.rdata.len() as u16;
+                    }
+                    RecordInformation::AAAA(data) => {
+                        ans.data_length = 16;
+                        ans.rdata = deserialize_ipv6_from_str(&data);
+                    }
+                    RecordInformation::CName(data) => {
+                        ans.rdata = serialize_domain_to_bytes(data);
+                        ans.data_length = ans.rdata.len() as u16;
+                    }
+                    RecordInformation::Soa(data) => {
+                        ans.rdata = data.to_bytes();
+                        ans.data_length = ans.rdata.len() as u16;
+                    }
+                    RecordInformation::MX(data) => {
+                        ans.rdata = data.to_bytes();
+                        ans.data_length = ans.rdata.len() as u16;
                     }
                 }
+                if record.rec_type == query.qtype {
+                    answers.push(ans);
+                } else if record.rec_type == ResourceType::CName {
+                    cnames.push(ans);
+                }
             }
-            // check local authorities for the address, else go to the web
-            let res = (self.resolver)("198.41.0.4", req, 40000);
-            Ok(res)
         }
+        if answers.is_empty() {
+            return cnames;
+        }
+        answers
     }
 
     /// From the spec:
@@ -175,31 +340,81 @@ where
     }
 }
 
-fn get_nameserver() -> String {
-    let file = String::new();
-    read_to_string("src/named.root").expect("Could not open named.root file");
-    file
+/// Where following the CNAMEs in a response got us
+enum Chased {
+    /// To the records that were asked for, if there are any
+    Answered(Vec<DnsAnswer>),
+    /// To a name the response has nothing more about
+    Continues(String),
+}
+
+/// Follows the CNAMEs starting at `name` through `answers`, adding them to
+/// `chain`
+fn follow_cnames(
+    answers: &[DnsAnswer],
+    name: &str,
+    qtype: &ResourceType,
+    chain: &mut Vec<DnsAnswer>,
+) -> Chased {
+    let mut name = normalize(name);
+    let mut followed = false;
+    while chain.len() <= MAX_CNAME_CHAIN {
+        let owned = |record: &&DnsAnswer| normalize(&record.name) == name;
+        let rrset = answers
+            .iter()
+            .filter(owned)
+            .filter(|record| record.qtype == *qtype)
+            .cloned()
+            .collect::<Vec<DnsAnswer>>();
+        if !rrset.is_empty() || *qtype == ResourceType::CName {
+            return Chased::Answered(rrset);
+        }
+        let cname = answers
+            .iter()
+            .filter(owned)
+            .find(|record| record.qtype == ResourceType::CName);
+        let target = match cname.map(|cname| deserialize_domain_from_bytes(&[], &cname.rdata)) {
+            Some(Ok((target, _))) => normalize(&target),
+            _ if followed => return Chased::Continues(name),
+            _ => return Chased::Answered(Vec::new()),
+        };
+        chain.push(cname.unwrap().clone());
+        name = target;
+        followed = true;
+    }
+    Chased::Answered(Vec::new())
+}
+
+/// Turns `res` into the response to `req`
+fn reply(req: &DnsPacket, mut res: DnsPacket) -> DnsPacket {
+    res.header.tx_id = req.header.tx_id;
+    res.header.is_response = true;
+    res.header.recursion_desired = req.header.recursion_desired;
+    res.queries = req.queries.clone();
+    res.header.questions_count = res.queries.len() as u16;
+    res.header.answers_count = res.answers.len() as u16;
+    res.header.authority_count = res.authority.len() as u16;
+    res.header.additional_count = res.additional.len() as u16;
+    res
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::resolvers::{MockResolver, StubResolver};
     use byteorder::{NetworkEndian, WriteBytesExt};
     use pretty_assertions::assert_eq;
-    use std::env;
-    use std::fs::{remove_file, File};
+    use std::fs::{create_dir, remove_file, write, File};
     use std::io::Write;
-    use std::time::Duration;
     use tempdir::TempDir;
-    use ttl_cache::TtlCache;
     use yaml_rust::YamlLoader;
 
     #[test]
     fn accepts_single_question_only() {
         // Doesn't compile:
-        // let client = DnsClient::new(|host: &str, req: DnsPacket, _port| {req}, &mut TtlCache::new(0));
-        let mut cache = TtlCache::new(0);
-        let client = DnsClient::new(|_: &str, req: DnsPacket, _port| req, &mut cache, HashMap::new());
+        // let client = DnsClient::new(StubResolver, &mut TtlCache::new(0));
+        let cache = Cache::new(0);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new());
         let mut req = DnsPacket::new();
         req.header.questions_count = 2;
         let res = client.results(req).unwrap();
@@ -207,26 +422,47 @@ mod tests {
     }
 
     #[test]
-    fn test_query_hits_cache() {
-        let query = DnsQuery::new();
-        let mut answer = DnsAnswer::new();
-        answer.name = "12.34.56.78".to_owned();
-        let mut cache = TtlCache::new(1);
-        cache.insert(query.clone(), answer.clone(), Duration::from_secs(10));
-        let client = DnsClient::new(|_: &str, req: DnsPacket, _port| req, &mut cache, HashMap::new());
+    fn test_formerr_without_a_question() {
+        let cache = Cache::new(0);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new());
         let mut req = DnsPacket::new();
+        req.header.tx_id = 7;
+        let res = client.results(req.clone()).unwrap();
+        assert_eq!(res.header.response_code, ResponseCode::FormatError);
+        assert_eq!(res.header.tx_id, 7);
+        // the question was there but didn't parse
         req.header.questions_count = 1;
-        req.queries = vec![query];
         let res = client.results(req).unwrap();
-        assert_eq!(res.answers, vec![answer]);
+        assert_eq!(res.header.response_code, ResponseCode::FormatError);
+    }
+
+    #[test]
+    fn test_query_hits_cache() {
+        let mut answer = DnsAnswer::new();
+        answer.name = "foo.com".to_owned();
+        answer.qtype = ResourceType::A;
+        answer.ttl = 60;
+        answer.data_length = 4;
+        answer.rdata = vec![12, 34, 56, 78];
+        let mut other = answer.clone();
+        other.rdata = vec![12, 34, 56, 79];
+        let resolver = MockResolver::new().with_records(vec![answer.clone(), other.clone()]);
+        let cache = Cache::new(1);
+        let client = DnsClient::new(&resolver, &cache, HashMap::new());
+        client.results(a_query("foo.com")).unwrap();
+        let res = client.results(a_query("FOO.com")).unwrap();
+        assert_eq!(vec![answer, other], res.answers);
+        assert_eq!(2, res.header.answers_count);
+        assert_eq!("FOO.com", res.queries[0].name);
+        assert_eq!(vec!["foo.com"], resolver.asked());
     }
 
     #[test]
     fn test_gives_error_for_invalid_domain() {
         let mut query = DnsQuery::new();
         query.name = "invalid domain".to_owned();
-        let mut cache = TtlCache::new(1);
-        let client = DnsClient::new(|_: &str, req: DnsPacket, _port| req, &mut cache, HashMap::new());
+        let cache = Cache::new(1);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new());
         let mut req = DnsPacket::new();
         req.header.questions_count = 1;
         req.queries = vec![query];
@@ -238,8 +474,8 @@ mod tests {
     fn test_inverse_query() {
         let mut query = DnsQuery::new();
         query.name = "invalid domain".to_owned();
-        let mut cache = TtlCache::new(1);
-        let client = DnsClient::new(|_: &str, req: DnsPacket, _port| req, &mut cache, HashMap::new());
+        let cache = Cache::new(1);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new());
         let mut req = DnsPacket::new();
         req.header.opcode = 1;
         let actual = client.results(req).unwrap();
@@ -252,7 +488,6 @@ mod tests {
     fn test_authoritative_query() {
         let temp_authorities_dir = TempDir::new("authorities").unwrap();
         let authority_file_path = temp_authorities_dir.path().join("authority1.yml");
-        env::set_var("AUTHORITY_DIR", temp_authorities_dir.path());
         let mut authority_file = File::create(authority_file_path).unwrap();
         let input = b"
 ttl: 60
@@ -310,8 +545,10 @@ records:
         req.header.questions_count = 1;
         req.header.tx_id = 0xbeef;
 
-        let mut cache = TtlCache::new(1);
-        let client = DnsClient::new(|_, _, _port| DnsPacket::new(), &mut cache, HashMap::new());
+        let cache = Cache::new(1);
+        let zones = vec![temp_authorities_dir.path().to_str().unwrap().to_owned()];
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new())
+            .with_authorities(authorities(&zones).unwrap());
         let actual_packet = client.standard_query(req).unwrap();
 
         let mut expected_packet = DnsPacket::new_response();
@@ -441,7 +678,7 @@ retry: 44
 expire: 45
 minimum: 46";
         let yaml = YamlLoader::load_from_str(soa_yaml).unwrap();
-        let soa_information = SoaInformation::from_yaml(&yaml[0]);
+        let soa_information = SoaInformation::from_yaml(&yaml[0]).unwrap();
         let mut expected_packet = DnsPacket::new_response();
         expected_packet.header.questions_count = 1;
         expected_packet.header.answers_count = 1;
@@ -496,10 +733,285 @@ minimum: 46";
         req.header.questions_count = 1;
         req.header.tx_id = 0xbeef;
 
-        let mut cache = TtlCache::new(1);
+        let cache = Cache::new(1);
         let mut blocklist = HashMap::new();
         blocklist.insert("foo.com".to_owned(), true);
-        let client = DnsClient::new(|_, _, _port| DnsPacket::new(), &mut cache, blocklist);
+        let client = DnsClient::new(StubResolver, &cache, blocklist);
         client.standard_query(req).unwrap_err();
     }
+
+    #[test]
+    fn test_reload_keeps_old_data_on_failure() {
+        let temp_dir = TempDir::new("reload").unwrap();
+        let zones_dir = temp_dir.path().join("authorities");
+        let blocklist_file = temp_dir.path().join("blocklist.yml");
+        let mut config = Config::new();
+        config.zones = vec![zones_dir.to_str().unwrap().to_owned()];
+        config.blocklists = vec![blocklist_file.to_str().unwrap().to_owned()];
+        create_dir(&zones_dir).unwrap();
+        let zone = "
+ttl: 60
+origin: customtld
+records:
+  - type: SOA
+    class: IN
+    ttl: 60
+    name: soa
+    data:
+      domain: foo
+      fqdn: soa.customtld
+      email: foo.customtld
+      serial: 42
+      refresh: 43
+      retry: 44
+      expire: 45
+      minimum: 46
+  - type: A
+    class: IN
+    ttl: 30
+    name: foo
+    data: 12.34.56.78
+";
+        write(zones_dir.join("zone.yml"), zone).unwrap();
+        write(&blocklist_file, "- bar.com\n").unwrap();
+
+        let request = |name: &str| {
+            let mut query = DnsQuery::new();
+            query.name = name.to_owned();
+            query.qtype = ResourceType::A;
+            let mut req = DnsPacket::new();
+            req.header.questions_count = 1;
+            req.queries = vec![query];
+            req
+        };
+        let cache = Cache::new(1);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new());
+        client.results(request("bar.com")).unwrap();
+        assert!(
+            !client
+                .results(request("foo.customtld"))
+                .unwrap()
+                .header
+                .authoritative
+        );
+
+        client.reload(&config).unwrap();
+        client.results(request("bar.com")).unwrap_err();
+        assert!(
+            client
+                .results(request("foo.customtld"))
+                .unwrap()
+                .header
+                .authoritative
+        );
+
+        // neither a bad blocklist nor a bad zone file replaces what we have
+        write(&blocklist_file, "- '*.'\n").unwrap();
+        client.reload(&config).unwrap_err();
+        write(&blocklist_file, "- baz.com\n").unwrap();
+        write(
+            zones_dir.join("broken.yml"),
+            "ttl: 60\norigin: broken\nrecords: []\n",
+        )
+        .unwrap();
+        client.reload(&config).unwrap_err();
+        client.results(request("bar.com")).unwrap_err();
+        client.results(request("baz.com")).unwrap();
+        assert!(
+            client
+                .results(request("foo.customtld"))
+                .unwrap()
+                .header
+                .authoritative
+        );
+    }
+
+    #[test]
+    fn test_answers_from_hosts_files() {
+        let cache = Cache::new(1);
+        let resolver = MockResolver::new();
+        let hosts = Hosts::parse("10.0.0.1 db.internal db\n").unwrap();
+        let client = DnsClient::new(&resolver, &cache, HashMap::new()).with_hosts(hosts);
+        let request = |name: &str, qtype: ResourceType| {
+            let mut query = DnsQuery::new();
+            query.name = name.to_owned();
+            query.qtype = qtype;
+            let mut req = DnsPacket::new();
+            req.header.questions_count = 1;
+            req.queries = vec![query];
+            req
+        };
+        let res = client.results(request("db", ResourceType::A)).unwrap();
+        assert!(res.header.authoritative);
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert_eq!(vec![10, 0, 0, 1], res.answers[0].rdata);
+        let res = client
+            .results(request("db.internal", ResourceType::AAAA))
+            .unwrap();
+        assert!(res.header.authoritative);
+        assert!(res.answers.is_empty());
+        let res = client
+            .results(request("1.0.0.10.in-addr.arpa", ResourceType::PTR))
+            .unwrap();
+        assert_eq!(2, res.header.answers_count);
+        // the files know all there is to know about their names
+        let res = client
+            .results(request("db.internal", ResourceType::MX))
+            .unwrap();
+        assert!(res.header.authoritative);
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert!(res.answers.is_empty());
+        assert!(resolver.asked().is_empty());
+
+        // anything else is up to the resolver
+        client
+            .results(request("web.internal", ResourceType::A))
+            .unwrap();
+        assert_eq!(vec!["web.internal"], resolver.asked());
+    }
+
+    #[test]
+    fn test_recursion_disabled() {
+        let mut config = Config::new();
+        config.recursion.enabled = false;
+        let cache = Cache::new(1);
+        let resolver = MockResolver::new();
+        let client = DnsClient::new(&resolver, &cache, HashMap::new()).configure(&config);
+        let mut query = DnsQuery::new();
+        query.name = "foo.com".to_owned();
+        let mut req = DnsPacket::new();
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        let res = client.results(req).unwrap();
+        assert_eq!(ResponseCode::Refused, res.header.response_code);
+        assert!(res.header.is_response);
+        assert!(resolver.asked().is_empty());
+    }
+
+    #[test]
+    fn test_ttl_limits_and_resolver_errors() {
+        let mut answer = DnsAnswer::new();
+        answer.name = "foo.com".to_owned();
+        answer.qtype = ResourceType::A;
+        answer.ttl = 5;
+        let mut long_lived = answer.clone();
+        long_lived.ttl = 1000;
+        let resolver = MockResolver::new()
+            .with_records(vec![answer, long_lived])
+            .failing("bar.com", ResolveError::Timeout)
+            .failing("baz.com", ResolveError::Refused);
+        let cache = Cache::new(1).with_ttl_limits(10, 100);
+        let client = DnsClient::new(resolver, &cache, HashMap::new());
+        let res = client.results(a_query("foo.com")).unwrap();
+        assert_eq!(
+            vec![10, 100],
+            res.answers.iter().map(|a| a.ttl).collect::<Vec<u32>>()
+        );
+
+        let res = client.results(a_query("bar.com")).unwrap();
+        assert_eq!(ResponseCode::ServerError, res.header.response_code);
+        assert_eq!(0xbeef, res.header.tx_id);
+        let res = client.results(a_query("baz.com")).unwrap();
+        assert_eq!(ResponseCode::Refused, res.header.response_code);
+    }
+
+    fn cname(name: &str, target: &str) -> DnsAnswer {
+        let mut answer = DnsAnswer::new();
+        answer.name = name.to_owned();
+        answer.qtype = ResourceType::CName;
+        answer.ttl = 60;
+        answer.rdata = serialize_domain_to_bytes(target);
+        answer.data_length = answer.rdata.len() as u16;
+        answer
+    }
+
+    fn a_query(name: &str) -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = ResourceType::A;
+        let mut req = DnsPacket::new();
+        req.header.tx_id = 0xbeef;
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    #[test]
+    fn test_follows_cname_chains() {
+        let temp_dir = TempDir::new("authorities").unwrap();
+        write(
+            temp_dir.path().join("foo.yml"),
+            "
+ttl: 60
+origin: foo.com
+records:
+  - type: SOA
+    class: IN
+    ttl: 60
+    name: ns
+    data:
+      domain: foo
+      fqdn: ns.foo.com.
+      email: foo@foo.com
+      serial: 1
+      refresh: 2
+      retry: 3
+      expire: 4
+      minimum: 5
+  - type: CNAME
+    class: IN
+    ttl: 60
+    name: www
+    data: cdn.bar.net
+",
+        )
+        .unwrap();
+        let zones = vec![temp_dir.path().to_str().unwrap().to_owned()];
+        let mut edge = DnsAnswer::new();
+        edge.name = "edge.baz.org".to_owned();
+        edge.qtype = ResourceType::A;
+        edge.ttl = 60;
+        edge.rdata = vec![1, 2, 3, 4];
+        edge.data_length = 4;
+        let resolver = MockResolver::new()
+            .with_records(vec![cname("cdn.bar.net", "edge.baz.org"), edge.clone()]);
+        let cache = Cache::new(1);
+        let client = DnsClient::new(&resolver, &cache, HashMap::new())
+            .with_authorities(authorities(&zones).unwrap());
+
+        let res = client.results(a_query("www.foo.com")).unwrap();
+        assert_eq!(
+            vec![
+                cname("www.foo.com", "cdn.bar.net"),
+                cname("cdn.bar.net", "edge.baz.org"),
+                edge
+            ],
+            res.answers
+        );
+        assert_eq!(3, res.header.answers_count);
+        assert_eq!(0xbeef, res.header.tx_id);
+        assert!(res.header.authoritative);
+        assert_eq!(a_query("www.foo.com").queries, res.queries);
+        assert_eq!(vec!["cdn.bar.net", "edge.baz.org"], resolver.asked());
+
+        // the CNAME itself is what's asked for
+        let mut req = a_query("www.foo.com");
+        req.queries[0].qtype = ResourceType::CName;
+        let res = client.results(req).unwrap();
+        assert_eq!(1, res.answers.len());
+    }
+
+    #[test]
+    fn test_cname_loops_fail() {
+        let resolver = MockResolver::new().with_records(vec![
+            cname("a.foo.com", "b.foo.com"),
+            cname("b.foo.com", "a.foo.com"),
+        ]);
+        let cache = Cache::new(1);
+        let client = DnsClient::new(resolver, &cache, HashMap::new());
+        let res = client.results(a_query("a.foo.com")).unwrap();
+        assert_eq!(ResponseCode::ServerError, res.header.response_code);
+        assert_eq!(0xbeef, res.header.tx_id);
+        assert!(res.answers.is_empty());
+    }
 }
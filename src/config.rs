@@ -0,0 +1,917 @@
+//! Everything that can be tuned about the server lives in one yaml file,
+//! `dns.yml` unless another one is given with `--config`. Any setting can also
+//! be overridden from the command line by its dotted path, e.g.
+//! `--cache.size 2048` or `--listeners.udp=127.0.0.1:53`
+use std::fs::read_to_string;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::dns64::{Network, WELL_KNOWN_PREFIX};
+use crate::forwarding::Strategy;
+use crate::upstream::{TlsUpstream, Upstream};
+
+pub const DEFAULT_CONFIG_FILE: &str = "dns.yml";
+
+/// `(dotted.path, value)` pairs given on the command line
+type Overrides = Vec<(String, String)>;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Listeners {
+    pub udp: String,
+    /// The encrypted listeners are only started when `tls` is configured,
+    /// setting them to `~` turns them off even then
+    pub doh: Option<String>,
+    pub doq: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheConfig {
+    pub size: usize,
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RecursionConfig {
+    /// When recursion is off we only answer for our own zones
+    pub enabled: bool,
+    /// The local port used when talking to other nameservers, 0 picks a
+    /// random one for every query
+    pub source_port: u16,
+    /// A `named.root` style file to start from instead of the built-in one
+    pub root_hints: Option<String>,
+    /// How long the first attempt at a nameserver gets, doubled on every retry
+    pub timeout: Duration,
+    /// How many rounds through a zone's nameservers we make before giving up
+    pub attempts: u32,
+    /// How long a client query may take before it is answered with SERVFAIL
+    pub deadline: Duration,
+    /// Only tell the servers on the way down as much of the name as they
+    /// need to know (RFC 9156)
+    pub qname_minimisation: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ForwardingConfig {
+    /// Recursive resolvers to send queries to instead of iterating from the
+    /// roots ourselves, forwarding is off while there are none
+    pub upstreams: Vec<Upstream>,
+    pub strategy: Strategy,
+    /// Iterate from the roots when none of the upstreams answer
+    pub fallback: bool,
+    /// Domains that go to their own upstreams, whether or not we forward
+    /// everything else
+    pub rules: Vec<ForwardingRule>,
+    /// Print the rule every query that matches one goes to
+    pub log_rules: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ForwardingRule {
+    /// The rule covers this domain and everything below it
+    pub suffix: String,
+    pub upstreams: Vec<Upstream>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dns64Config {
+    /// Make up AAAA records for names that only have A records
+    pub enabled: bool,
+    /// Where the IPv4 addresses go in the last 32 bits of
+    pub prefix: Ipv6Addr,
+    /// AAAA records in these IPv6 ranges count as none, A records in these
+    /// IPv4 ranges don't get AAAA records made out of them
+    pub exclude: Vec<Network>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Config {
+    pub listeners: Listeners,
+    pub tls: Option<TlsFiles>,
+    pub cache: CacheConfig,
+    pub recursion: RecursionConfig,
+    pub forwarding: ForwardingConfig,
+    pub dns64: Dns64Config,
+    /// Directories holding yaml zone files
+    pub zones: Vec<String>,
+    pub blocklists: Vec<String>,
+    /// /etc/hosts style files we answer A, AAAA and PTR queries from
+    pub hosts: Vec<String>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Config {
+            listeners: Listeners {
+                udp: "0.0.0.0:5554".to_owned(),
+                doh: Some("0.0.0.0:5443".to_owned()),
+                doq: Some("0.0.0.0:853".to_owned()),
+            },
+            tls: None,
+            cache: CacheConfig {
+                size: 1024,
+                min_ttl: 0,
+                max_ttl: 86400,
+            },
+            recursion: RecursionConfig {
+                enabled: true,
+                source_port: 0,
+                root_hints: None,
+                timeout: Duration::from_millis(1000),
+                attempts: 3,
+                deadline: Duration::from_millis(10000),
+                qname_minimisation: false,
+            },
+            forwarding: ForwardingConfig {
+                upstreams: Vec::new(),
+                strategy: Strategy::Ordered,
+                fallback: false,
+                rules: Vec::new(),
+                log_rules: true,
+            },
+            dns64: Dns64Config {
+                enabled: false,
+                prefix: WELL_KNOWN_PREFIX,
+                // IPv4-mapped addresses aren't reachable over IPv6
+                exclude: vec![Network::parse("::ffff:0:0/96").unwrap()],
+            },
+            zones: vec!["authorities".to_owned()],
+            blocklists: vec!["blocklist.yml".to_owned()],
+            hosts: Vec::new(),
+        }
+    }
+
+    /// Builds the configuration from the command line arguments (without the
+    /// program name), reading the config file they point to
+    pub fn load<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let (path, overrides) = parse_args(args)?;
+        let mut yaml = match path {
+            Some(path) => read_yaml(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_yaml(DEFAULT_CONFIG_FILE)?,
+            None => Yaml::Hash(Hash::new()),
+        };
+        for (key, value) in overrides {
+            set_path(&mut yaml, &key, &value)?;
+        }
+        Config::from_yaml(&yaml)
+    }
+
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let mut config = Config::new();
+        match yaml {
+            Yaml::Hash(_) => (),
+            // an empty file
+            Yaml::Null | Yaml::BadValue => return Ok(config),
+            _ => return Err("The configuration file must be a mapping of settings".to_owned()),
+        }
+        check_keys(
+            yaml,
+            "",
+            &[
+                "listeners",
+                "tls",
+                "cache",
+                "recursion",
+                "forwarding",
+                "dns64",
+                "zones",
+                "blocklists",
+                "hosts",
+            ],
+        )?;
+
+        let listeners = &yaml["listeners"];
+        check_keys(listeners, "listeners", &["udp", "doh", "doq"])?;
+        if let Some(udp) = socket_addr(&listeners["udp"], "listeners.udp")? {
+            config.listeners.udp = udp;
+        }
+        if !listeners["doh"].is_badvalue() {
+            config.listeners.doh = socket_addr(&listeners["doh"], "listeners.doh")?;
+        }
+        if !listeners["doq"].is_badvalue() {
+            config.listeners.doq = socket_addr(&listeners["doq"], "listeners.doq")?;
+        }
+
+        let tls = &yaml["tls"];
+        check_keys(tls, "tls", &["cert", "key"])?;
+        config.tls = match (
+            string(&tls["cert"], "tls.cert")?,
+            string(&tls["key"], "tls.key")?,
+        ) {
+            (Some(cert), Some(key)) => {
+                for (path, file) in &[("tls.cert", &cert), ("tls.key", &key)] {
+                    if !Path::new(file).is_file() {
+                        return Err(format!("{}: '{}' does not exist", path, file));
+                    }
+                }
+                Some(TlsFiles { cert, key })
+            }
+            (None, None) => None,
+            _ => return Err("tls: both 'cert' and 'key' must be given".to_owned()),
+        };
+
+        let cache = &yaml["cache"];
+        check_keys(cache, "cache", &["size", "min_ttl", "max_ttl"])?;
+        if let Some(size) = integer(&cache["size"], "cache.size", 1, u32::MAX as i64)? {
+            config.cache.size = size as usize;
+        }
+        if let Some(ttl) = integer(&cache["min_ttl"], "cache.min_ttl", 0, u32::MAX as i64)? {
+            config.cache.min_ttl = ttl as u32;
+        }
+        if let Some(ttl) = integer(&cache["max_ttl"], "cache.max_ttl", 0, u32::MAX as i64)? {
+            config.cache.max_ttl = ttl as u32;
+        }
+        if config.cache.min_ttl > config.cache.max_ttl {
+            return Err(format!(
+                "cache.min_ttl ({}) can't be larger than cache.max_ttl ({})",
+                config.cache.min_ttl, config.cache.max_ttl
+            ));
+        }
+
+        let recursion = &yaml["recursion"];
+        check_keys(
+            recursion,
+            "recursion",
+            &[
+                "enabled",
+                "source_port",
+                "root_hints",
+                "timeout_ms",
+                "attempts",
+                "deadline_ms",
+                "qname_minimisation",
+            ],
+        )?;
+        if let Some(enabled) = boolean(&recursion["enabled"], "recursion.enabled")? {
+            config.recursion.enabled = enabled;
+        }
+        if let Some(port) = integer(&recursion["source_port"], "recursion.source_port", 0, 65535)? {
+            config.recursion.source_port = port as u16;
+        }
+        if let Some(root_hints) = string(&recursion["root_hints"], "recursion.root_hints")? {
+            if !Path::new(&root_hints).is_file() {
+                return Err(format!(
+                    "recursion.root_hints: '{}' does not exist",
+                    root_hints
+                ));
+            }
+            config.recursion.root_hints = Some(root_hints);
+        }
+        if let Some(ms) = integer(&recursion["timeout_ms"], "recursion.timeout_ms", 1, 60000)? {
+            config.recursion.timeout = Duration::from_millis(ms as u64);
+        }
+        if let Some(attempts) = integer(&recursion["attempts"], "recursion.attempts", 1, 10)? {
+            config.recursion.attempts = attempts as u32;
+        }
+        if let Some(ms) = integer(&recursion["deadline_ms"], "recursion.deadline_ms", 1, 60000)? {
+            config.recursion.deadline = Duration::from_millis(ms as u64);
+        }
+        if let Some(minimise) = boolean(
+            &recursion["qname_minimisation"],
+            "recursion.qname_minimisation",
+        )? {
+            config.recursion.qname_minimisation = minimise;
+        }
+
+        let forwarding = &yaml["forwarding"];
+        check_keys(
+            forwarding,
+            "forwarding",
+            &["upstreams", "strategy", "fallback", "rules", "log_rules"],
+        )?;
+        if let Some(upstreams) = upstreams(&forwarding["upstreams"], "forwarding.upstreams")? {
+            config.forwarding.upstreams = upstreams;
+        }
+        if let Some(strategy) = string(&forwarding["strategy"], "forwarding.strategy")? {
+            config.forwarding.strategy = Strategy::from_name(&strategy).ok_or_else(|| {
+                format!(
+                    "forwarding.strategy: expected ordered, round_robin or fastest, got '{}'",
+                    strategy
+                )
+            })?;
+        }
+        if let Some(fallback) = boolean(&forwarding["fallback"], "forwarding.fallback")? {
+            config.forwarding.fallback = fallback;
+        }
+        match &forwarding["rules"] {
+            Yaml::BadValue | Yaml::Null => (),
+            Yaml::Hash(rules) => {
+                for (suffix, upstream_list) in rules {
+                    // `*.consul` and `consul` mean the same thing
+                    let suffix = match suffix.as_str() {
+                        Some(suffix) => suffix.trim_start_matches("*.").trim_end_matches('.'),
+                        None => "",
+                    };
+                    let path = format!("forwarding.rules.{}", suffix);
+                    if suffix.is_empty() {
+                        return Err("forwarding.rules: expected domains as the keys".to_owned());
+                    }
+                    config.forwarding.rules.push(ForwardingRule {
+                        suffix: suffix.to_ascii_lowercase(),
+                        upstreams: upstreams(upstream_list, &path)?
+                            .filter(|upstreams| !upstreams.is_empty())
+                            .ok_or_else(|| format!("{}: expected at least one upstream", path))?,
+                    });
+                }
+            }
+            rules => {
+                return Err(format!(
+                    "forwarding.rules: expected a mapping of domains to upstreams, got {}",
+                    describe(rules)
+                ))
+            }
+        }
+
+        if let Some(log_rules) = boolean(&forwarding["log_rules"], "forwarding.log_rules")? {
+            config.forwarding.log_rules = log_rules;
+        }
+
+        let dns64 = &yaml["dns64"];
+        check_keys(dns64, "dns64", &["enabled", "prefix", "exclude"])?;
+        if let Some(enabled) = boolean(&dns64["enabled"], "dns64.enabled")? {
+            config.dns64.enabled = enabled;
+        }
+        if let Some(prefix) = string(&dns64["prefix"], "dns64.prefix")? {
+            config.dns64.prefix = match Network::parse(&prefix) {
+                Some(Network {
+                    address: IpAddr::V6(address),
+                    length: 96,
+                }) => address,
+                _ => {
+                    return Err(format!(
+                        "dns64.prefix: expected an IPv6 /96 like 64:ff9b::/96, got '{}'",
+                        prefix
+                    ))
+                }
+            };
+        }
+        if let Some(exclude) = string_list(&dns64["exclude"], "dns64.exclude")? {
+            config.dns64.exclude = exclude
+                .iter()
+                .enumerate()
+                .map(|(i, network)| {
+                    Network::parse(network).ok_or_else(|| {
+                        format!(
+                            "dns64.exclude[{}]: expected a range like ::ffff:0:0/96 or 10.0.0.0/8, got '{}'",
+                            i, network
+                        )
+                    })
+                })
+                .collect::<Result<Vec<Network>, String>>()?;
+        }
+
+        if let Some(zones) = string_list(&yaml["zones"], "zones")? {
+            config.zones = zones;
+        }
+        if let Some(blocklists) = string_list(&yaml["blocklists"], "blocklists")? {
+            config.blocklists = blocklists;
+        }
+        if let Some(hosts) = string_list(&yaml["hosts"], "hosts")? {
+            config.hosts = hosts;
+        }
+        Ok(config)
+    }
+}
+
+fn read_yaml(path: &str) -> Result<Yaml, String> {
+    let contents = read_to_string(path)
+        .map_err(|e| format!("Could not read the configuration file {}: {}", path, e))?;
+    let mut docs = YamlLoader::load_from_str(&contents)
+        .map_err(|e| format!("Invalid yaml in {}: {}", path, e))?;
+    Ok(if docs.is_empty() {
+        Yaml::Null
+    } else {
+        docs.remove(0)
+    })
+}
+
+/// Splits the arguments into the config file path (if any) and the overrides
+fn parse_args<I: IntoIterator<Item = String>>(
+    args: I,
+) -> Result<(Option<String>, Overrides), String> {
+    let mut path = None;
+    let mut overrides = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) if !flag.is_empty() => flag,
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        };
+        let (key, value) = match flag.find('=') {
+            Some(pos) => (flag[..pos].to_owned(), flag[pos + 1..].to_owned()),
+            None => match args.next() {
+                Some(value) => (flag.to_owned(), value),
+                None => return Err(format!("Missing a value for '{}'", arg)),
+            },
+        };
+        if key == "config" {
+            path = Some(value);
+        } else {
+            overrides.push((key, value));
+        }
+    }
+    Ok((path, overrides))
+}
+
+/// Sets the setting at `key` (e.g. `cache.size`) to `value`, the value is
+/// parsed as yaml so lists can be given as `[a, b]`
+fn set_path(yaml: &mut Yaml, key: &str, value: &str) -> Result<(), String> {
+    let value = match YamlLoader::load_from_str(value) {
+        Ok(mut docs) if !docs.is_empty() => docs.remove(0),
+        _ => Yaml::String(value.to_owned()),
+    };
+    let mut node = yaml;
+    for part in key.split('.') {
+        if let Yaml::Null = node {
+            *node = Yaml::Hash(Hash::new());
+        }
+        node = match node {
+            Yaml::Hash(hash) => hash
+                .entry(Yaml::String(part.to_owned()))
+                .or_insert(Yaml::Null),
+            _ => return Err(format!("--{}: '{}' is not a section", key, part)),
+        };
+    }
+    *node = value;
+    Ok(())
+}
+
+/// Makes sure typos don't go unnoticed
+fn check_keys(yaml: &Yaml, path: &str, allowed: &[&str]) -> Result<(), String> {
+    let hash = match yaml {
+        Yaml::Hash(hash) => hash,
+        Yaml::BadValue => return Ok(()),
+        _ => return Err(format!("{}: must be a mapping of settings", path)),
+    };
+    for key in hash.keys() {
+        match key.as_str() {
+            Some(key) if allowed.contains(&key) => (),
+            _ => {
+                let name = match key {
+                    Yaml::String(s) => s.clone(),
+                    _ => format!("{:?}", key),
+                };
+                let full = if path.is_empty() {
+                    name
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                return Err(format!(
+                    "Unknown setting '{}', expected one of: {}",
+                    full,
+                    allowed.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe(yaml: &Yaml) -> String {
+    match yaml {
+        Yaml::String(s) => format!("'{}'", s),
+        Yaml::Integer(n) => n.to_string(),
+        Yaml::Real(r) => r.clone(),
+        Yaml::Boolean(b) => b.to_string(),
+        Yaml::Null => "nothing".to_owned(),
+        Yaml::Array(_) => "a list".to_owned(),
+        Yaml::Hash(_) => "a mapping".to_owned(),
+        _ => "an invalid value".to_owned(),
+    }
+}
+
+fn string(yaml: &Yaml, path: &str) -> Result<Option<String>, String> {
+    match yaml {
+        Yaml::BadValue => Ok(None),
+        Yaml::String(s) => Ok(Some(s.clone())),
+        _ => Err(format!(
+            "{}: expected a string, got {}",
+            path,
+            describe(yaml)
+        )),
+    }
+}
+
+fn string_list(yaml: &Yaml, path: &str) -> Result<Option<Vec<String>>, String> {
+    match yaml {
+        Yaml::BadValue => Ok(None),
+        Yaml::Null => Ok(Some(Vec::new())),
+        Yaml::String(s) => Ok(Some(vec![s.clone()])),
+        Yaml::Array(arr) => arr
+            .iter()
+            .enumerate()
+            .map(|(i, item)| match item {
+                Yaml::String(s) => Ok(s.clone()),
+                _ => Err(format!(
+                    "{}[{}]: expected a string, got {}",
+                    path,
+                    i,
+                    describe(item)
+                )),
+            })
+            .collect::<Result<Vec<String>, String>>()
+            .map(Some),
+        _ => Err(format!(
+            "{}: expected a list of strings, got {}",
+            path,
+            describe(yaml)
+        )),
+    }
+}
+
+fn integer(yaml: &Yaml, path: &str, min: i64, max: i64) -> Result<Option<i64>, String> {
+    match yaml {
+        Yaml::BadValue => Ok(None),
+        Yaml::Integer(n) if *n >= min && *n <= max => Ok(Some(*n)),
+        _ => Err(format!(
+            "{}: expected a whole number between {} and {}, got {}",
+            path,
+            min,
+            max,
+            describe(yaml)
+        )),
+    }
+}
+
+/// Other resolvers, either the address of one we ask over plain UDP or a
+/// mapping for one we ask over DNS-over-TLS or DNS-over-HTTPS
+fn upstreams(yaml: &Yaml, path: &str) -> Result<Option<Vec<Upstream>>, String> {
+    let upstreams = match yaml {
+        Yaml::BadValue => return Ok(None),
+        Yaml::Null => return Ok(Some(Vec::new())),
+        Yaml::Array(arr) => arr.iter().collect::<Vec<&Yaml>>(),
+        upstream => vec![upstream],
+    };
+    upstreams
+        .into_iter()
+        .enumerate()
+        .map(|(i, upstream)| {
+            let path = format!("{}[{}]", path, i);
+            match upstream {
+                Yaml::String(s) => match address(s, 53) {
+                    Some(address) => Ok(Upstream::Udp(address)),
+                    None => Err(format!(
+                        "{}: expected an address like 10.0.0.1 or 10.0.0.1:53, got '{}'",
+                        path, s
+                    )),
+                },
+                Yaml::Hash(_) => encrypted_upstream(upstream, &path),
+                _ => Err(format!(
+                    "{}: expected an address or a mapping, got {}",
+                    path,
+                    describe(upstream)
+                )),
+            }
+        })
+        .collect::<Result<Vec<Upstream>, String>>()
+        .map(Some)
+}
+
+fn encrypted_upstream(yaml: &Yaml, path: &str) -> Result<Upstream, String> {
+    check_keys(yaml, path, &["tls", "https", "name", "pins", "ca", "path"])?;
+    let (protocol, port) = match (&yaml["tls"], &yaml["https"]) {
+        (Yaml::BadValue, Yaml::BadValue) | (Yaml::String(_), Yaml::String(_)) => {
+            return Err(format!("{}: expected one of 'tls' or 'https'", path))
+        }
+        (Yaml::BadValue, _) => ("https", 443),
+        _ => ("tls", 853),
+    };
+    let key = format!("{}.{}", path, protocol);
+    let given = string(&yaml[protocol], &key)?.unwrap_or_default();
+    let address = address(&given, port).ok_or_else(|| {
+        format!(
+            "{}: expected an address like 10.0.0.1 or 10.0.0.1:{}, got '{}'",
+            key, port, given
+        )
+    })?;
+    let name = string(&yaml["name"], &format!("{}.name", path))?
+        .ok_or_else(|| format!("{}: 'name' must be given to check the certificate", path))?;
+    let pins = string_list(&yaml["pins"], &format!("{}.pins", path))?
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+        .map(|(i, pin)| match STANDARD.decode(pin) {
+            Ok(digest) if digest.len() == 32 => Ok(digest),
+            _ => Err(format!(
+                "{}.pins[{}]: expected a base64 encoded SHA-256 digest, got '{}'",
+                path, i, pin
+            )),
+        })
+        .collect::<Result<Vec<Vec<u8>>, String>>()?;
+    let ca = string(&yaml["ca"], &format!("{}.ca", path))?;
+    if let Some(ca) = &ca {
+        if !Path::new(ca).is_file() {
+            return Err(format!("{}.ca: '{}' does not exist", path, ca));
+        }
+    }
+    let tls = TlsUpstream {
+        address,
+        name,
+        pins,
+        ca,
+    };
+    Ok(match protocol {
+        "tls" => {
+            if !matches!(yaml["path"], Yaml::BadValue) {
+                return Err(format!("{}: 'path' is only for https", path));
+            }
+            Upstream::Tls(tls)
+        }
+        _ => {
+            let query_path = string(&yaml["path"], &format!("{}.path", path))?
+                .unwrap_or_else(|| "/dns-query".to_owned());
+            Upstream::Https(tls, query_path)
+        }
+    })
+}
+
+/// The port is optional
+fn address(address: &str, default_port: u16) -> Option<SocketAddr> {
+    address
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            address
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, default_port))
+        })
+        .ok()
+}
+
+fn boolean(yaml: &Yaml, path: &str) -> Result<Option<bool>, String> {
+    match yaml {
+        Yaml::BadValue => Ok(None),
+        Yaml::Boolean(b) => Ok(Some(*b)),
+        _ => Err(format!(
+            "{}: expected true or false, got {}",
+            path,
+            describe(yaml)
+        )),
+    }
+}
+
+/// `~` means the listener is turned off
+fn socket_addr(yaml: &Yaml, path: &str) -> Result<Option<String>, String> {
+    match yaml {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::String(s) if s.parse::<SocketAddr>().is_ok() => Ok(Some(s.clone())),
+        _ => Err(format!(
+            "{}: expected an address like 0.0.0.0:53, got {}",
+            path,
+            describe(yaml)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_config_from_yaml() {
+        let input = "
+listeners:
+  udp: 127.0.0.1:53
+  doq: ~
+cache:
+  size: 42
+  max_ttl: 3600
+recursion:
+  enabled: false
+  timeout_ms: 500
+  deadline_ms: 2000
+  qname_minimisation: true
+forwarding:
+  upstreams:
+    - 10.0.0.1
+    - 10.0.0.2:5353
+    - ::1
+    - tls: 1.1.1.1
+      name: cloudflare-dns.com
+      pins: [AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=]
+    - https: 9.9.9.9:8443
+      name: dns.quad9.net
+  strategy: round_robin
+  fallback: true
+  rules:
+    corp.internal: [10.1.0.1, 10.1.0.2]
+    '*.consul': 127.0.0.1:8600
+  log_rules: false
+dns64:
+  enabled: true
+  prefix: 2001:db8:64::/96
+  exclude: [10.0.0.0/8]
+zones:
+  - zones
+  - more-zones
+blocklists: blocked.yml
+hosts: [/etc/hosts, more-hosts]
+";
+        let yaml = YamlLoader::load_from_str(input).unwrap();
+        let actual = Config::from_yaml(&yaml[0]).unwrap();
+        let mut expected = Config::new();
+        expected.listeners.udp = "127.0.0.1:53".to_owned();
+        expected.listeners.doq = None;
+        expected.cache.size = 42;
+        expected.cache.max_ttl = 3600;
+        expected.recursion.enabled = false;
+        expected.recursion.timeout = Duration::from_millis(500);
+        expected.recursion.deadline = Duration::from_millis(2000);
+        expected.recursion.qname_minimisation = true;
+        expected.forwarding.upstreams = vec![
+            Upstream::Udp("10.0.0.1:53".parse().unwrap()),
+            Upstream::Udp("10.0.0.2:5353".parse().unwrap()),
+            Upstream::Udp("[::1]:53".parse().unwrap()),
+            Upstream::Tls(TlsUpstream {
+                address: "1.1.1.1:853".parse().unwrap(),
+                name: "cloudflare-dns.com".to_owned(),
+                pins: vec![vec![0; 32]],
+                ca: None,
+            }),
+            Upstream::Https(
+                TlsUpstream {
+                    address: "9.9.9.9:8443".parse().unwrap(),
+                    name: "dns.quad9.net".to_owned(),
+                    pins: Vec::new(),
+                    ca: None,
+                },
+                "/dns-query".to_owned(),
+            ),
+        ];
+        expected.forwarding.strategy = Strategy::RoundRobin;
+        expected.forwarding.fallback = true;
+        expected.forwarding.log_rules = false;
+        expected.forwarding.rules = vec![
+            ForwardingRule {
+                suffix: "corp.internal".to_owned(),
+                upstreams: vec![
+                    Upstream::Udp("10.1.0.1:53".parse().unwrap()),
+                    Upstream::Udp("10.1.0.2:53".parse().unwrap()),
+                ],
+            },
+            ForwardingRule {
+                suffix: "consul".to_owned(),
+                upstreams: vec![Upstream::Udp("127.0.0.1:8600".parse().unwrap())],
+            },
+        ];
+        expected.dns64.enabled = true;
+        expected.dns64.prefix = "2001:db8:64::".parse().unwrap();
+        expected.dns64.exclude = vec![Network::parse("10.0.0.0/8").unwrap()];
+        expected.zones = vec!["zones".to_owned(), "more-zones".to_owned()];
+        expected.blocklists = vec!["blocked.yml".to_owned()];
+        expected.hosts = vec!["/etc/hosts".to_owned(), "more-hosts".to_owned()];
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_empty_config_is_the_default() {
+        assert_eq!(Config::new(), Config::from_yaml(&Yaml::Null).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let cases = [
+            (
+                "cahce: {}",
+                "Unknown setting 'cahce', expected one of: listeners, tls, cache, recursion, forwarding, dns64, zones, blocklists, hosts",
+            ),
+            (
+                "cache: {size: 0}",
+                "cache.size: expected a whole number between 1 and 4294967295, got 0",
+            ),
+            (
+                "cache: {min_ttl: 10, max_ttl: 5}",
+                "cache.min_ttl (10) can't be larger than cache.max_ttl (5)",
+            ),
+            (
+                "listeners: {udp: localhost}",
+                "listeners.udp: expected an address like 0.0.0.0:53, got 'localhost'",
+            ),
+            (
+                "recursion: {enabled: yes}",
+                "recursion.enabled: expected true or false, got 'yes'",
+            ),
+            (
+                "recursion: {root_hints: /does/not/exist}",
+                "recursion.root_hints: '/does/not/exist' does not exist",
+            ),
+            (
+                "recursion: {attempts: 0}",
+                "recursion.attempts: expected a whole number between 1 and 10, got 0",
+            ),
+            (
+                "forwarding: {upstreams: [10.0.0.1, dns.corp]}",
+                "forwarding.upstreams[1]: expected an address like 10.0.0.1 or 10.0.0.1:53, got 'dns.corp'",
+            ),
+            (
+                "forwarding: {upstreams: [{tls: 1.1.1.1}]}",
+                "forwarding.upstreams[0]: 'name' must be given to check the certificate",
+            ),
+            (
+                "forwarding: {upstreams: [{tls: 1.1.1.1, https: 1.1.1.1, name: a}]}",
+                "forwarding.upstreams[0]: expected one of 'tls' or 'https'",
+            ),
+            (
+                "forwarding: {upstreams: [{tls: 1.1.1.1, name: a, pins: [c2hvcnQ=]}]}",
+                "forwarding.upstreams[0].pins[0]: expected a base64 encoded SHA-256 digest, got 'c2hvcnQ='",
+            ),
+            (
+                "forwarding: {upstreams: [{https: 1.1.1.1, name: a, ca: /does/not/exist}]}",
+                "forwarding.upstreams[0].ca: '/does/not/exist' does not exist",
+            ),
+            (
+                "forwarding: {strategy: random}",
+                "forwarding.strategy: expected ordered, round_robin or fastest, got 'random'",
+            ),
+            (
+                "forwarding: {rules: {corp.internal: []}}",
+                "forwarding.rules.corp.internal: expected at least one upstream",
+            ),
+            (
+                "forwarding: {rules: [corp.internal]}",
+                "forwarding.rules: expected a mapping of domains to upstreams, got a list",
+            ),
+            (
+                "dns64: {prefix: 64:ff9b::/64}",
+                "dns64.prefix: expected an IPv6 /96 like 64:ff9b::/96, got '64:ff9b::/64'",
+            ),
+            (
+                "dns64: {exclude: [10.0.0.0/8, 10.0.0.1]}",
+                "dns64.exclude[1]: expected a range like ::ffff:0:0/96 or 10.0.0.0/8, got '10.0.0.1'",
+            ),
+            ("zones: [1]", "zones[0]: expected a string, got 1"),
+            ("tls: {cert: cert.pem}", "tls: both 'cert' and 'key' must be given"),
+            (
+                "tls: {cert: /does/not/exist, key: /does/not/exist}",
+                "tls.cert: '/does/not/exist' does not exist",
+            ),
+        ];
+        for (input, error) in cases.iter() {
+            let yaml = YamlLoader::load_from_str(input).unwrap();
+            assert_eq!(Err(error.to_string()), Config::from_yaml(&yaml[0]));
+        }
+    }
+
+    #[test]
+    fn test_command_line_overrides_file() {
+        let temp_dir = TempDir::new("config").unwrap();
+        let config_path = temp_dir.path().join("dns.yml");
+        let mut config_file = File::create(&config_path).unwrap();
+        config_file
+            .write_all(b"cache:\n  size: 42\n  min_ttl: 5\nzones: [zones]\n")
+            .unwrap();
+        let config = Config::load(args(&[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--cache.size",
+            "2048",
+            "--listeners.udp=127.0.0.1:5353",
+            "--blocklists",
+            "[a.yml, b.yml]",
+        ]))
+        .unwrap();
+        let mut expected = Config::new();
+        expected.cache.size = 2048;
+        expected.cache.min_ttl = 5;
+        expected.listeners.udp = "127.0.0.1:5353".to_owned();
+        expected.zones = vec!["zones".to_owned()];
+        expected.blocklists = vec!["a.yml".to_owned(), "b.yml".to_owned()];
+        assert_eq!(expected, config);
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert_eq!(
+            Err("Unexpected argument 'cache.size'".to_owned()),
+            Config::load(args(&["cache.size", "1"]))
+        );
+        assert_eq!(
+            Err("Missing a value for '--cache.size'".to_owned()),
+            Config::load(args(&["--cache.size"]))
+        );
+        assert_eq!(
+            Err("--cache.size.foo: 'foo' is not a section".to_owned()),
+            Config::load(args(&["--cache.size=1", "--cache.size.foo=2"]))
+        );
+        Config::load(args(&["--config", "/does/not/exist.yml"])).unwrap_err();
+    }
+}
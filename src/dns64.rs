@@ -0,0 +1,390 @@
+//! DNS64 (RFC 6147) for clients on IPv6-only networks that reach the IPv4
+//! internet through NAT64. Names that only have A records get AAAA records
+//! made up for them, the IPv4 address embedded in a /96 prefix the NAT64
+//! gateway translates, and reverse lookups of those addresses are pointed
+//! back at the IPv4 ones
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::answer::DnsAnswer;
+use crate::cache::negative_ttl;
+use crate::header::{ResourceType, ResponseCode};
+use crate::hints::normalize;
+use crate::packet::DnsPacket;
+use crate::resolvers::{Limits, ResolveError, Resolver};
+use crate::serialization::serialize_domain_to_bytes;
+
+/// The well-known prefix from RFC 6052
+pub const WELL_KNOWN_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+
+/// How long the CNAMEs we make up for reverse lookups are good for
+const PTR_CNAME_TTL: u32 = 600;
+
+/// An address range like `::ffff:0:0/96` or `10.0.0.0/8`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Network {
+    pub address: IpAddr,
+    pub length: u8,
+}
+
+impl Network {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (address, length) = s.split_once('/')?;
+        let address = address.parse::<IpAddr>().ok()?;
+        let length = length.parse::<u8>().ok()?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        if length > max {
+            return None;
+        }
+        Some(Network { address, length })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.length as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.length as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Does DNS64 on top of `resolver`. `exclude` holds the IPv6 ranges whose
+/// AAAA records are as good as none, and the IPv4 ranges whose A records we
+/// don't make AAAA records out of
+pub struct Dns64<R> {
+    resolver: R,
+    prefix: Ipv6Addr,
+    exclude: Vec<Network>,
+}
+
+impl<R: Resolver> Dns64<R> {
+    /// Only the first 96 bits of `prefix` are used
+    pub fn new(resolver: R, prefix: Ipv6Addr, exclude: Vec<Network>) -> Self {
+        Dns64 {
+            resolver,
+            prefix,
+            exclude,
+        }
+    }
+
+    fn excluded(&self, address: IpAddr) -> bool {
+        self.exclude.iter().any(|network| network.contains(address))
+    }
+
+    /// `address` inside our prefix
+    fn synthesize(&self, address: Ipv4Addr) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.prefix) & !0xffff_ffff | u32::from(address) as u128)
+    }
+
+    /// The IPv4 address embedded in `address`, if it's in our prefix
+    fn embedded(&self, address: Ipv6Addr) -> Option<Ipv4Addr> {
+        let prefix = Network {
+            address: IpAddr::V6(self.prefix),
+            length: 96,
+        };
+        if !prefix.contains(IpAddr::V6(address)) {
+            return None;
+        }
+        Some(Ipv4Addr::from(u128::from(address) as u32))
+    }
+
+    /// Answers an AAAA query with made up records when the name only has A
+    /// records, or only AAAA records we were told to ignore
+    fn resolve_aaaa(&self, req: &DnsPacket, limits: Limits) -> Result<DnsPacket, ResolveError> {
+        let mut res = self.resolver.resolve(req, limits)?;
+        if res.header.response_code != ResponseCode::NoError {
+            return Ok(res);
+        }
+        let is_aaaa = |record: &DnsAnswer| record.qtype == ResourceType::AAAA;
+        let has_aaaa = res.answers.iter().any(is_aaaa);
+        res.answers
+            .retain(|record| !is_aaaa(record) || !self.excluded(IpAddr::V6(address(record))));
+        if res.answers.iter().any(is_aaaa) {
+            res.header.answers_count = res.answers.len() as u16;
+            return Ok(res);
+        }
+
+        let mut a_req = req.clone();
+        a_req.queries[0].qtype = ResourceType::A;
+        let mut a_res = match self.resolver.resolve(&a_req, limits) {
+            Ok(a_res) if a_res.header.response_code == ResponseCode::NoError => a_res,
+            // the AAAA answer stands
+            _ => return Ok(res),
+        };
+        // the records we make up can't outlive the fact that there's no AAAA
+        let negative_ttl = if has_aaaa { None } else { negative_ttl(&res) };
+        let mut synthesized = Vec::new();
+        for record in std::mem::take(&mut a_res.answers) {
+            match record.qtype {
+                ResourceType::A if record.rdata.len() == 4 => {
+                    let v4 = Ipv4Addr::new(
+                        record.rdata[0],
+                        record.rdata[1],
+                        record.rdata[2],
+                        record.rdata[3],
+                    );
+                    if self.excluded(IpAddr::V4(v4)) {
+                        continue;
+                    }
+                    let mut aaaa = record.clone();
+                    aaaa.qtype = ResourceType::AAAA;
+                    aaaa.rdata = self.synthesize(v4).octets().to_vec();
+                    aaaa.data_length = 16;
+                    aaaa.ttl = negative_ttl.map_or(record.ttl, |ttl| ttl.min(record.ttl));
+                    synthesized.push(aaaa);
+                }
+                ResourceType::A => (),
+                _ => synthesized.push(record),
+            }
+        }
+        if !synthesized.iter().any(is_aaaa) {
+            return Ok(res);
+        }
+        let mut res = a_res;
+        res.queries = req.queries.clone();
+        res.answers = synthesized;
+        res.authority.clear();
+        res.additional.clear();
+        res.header.answers_count = res.answers.len() as u16;
+        res.header.authority_count = 0;
+        res.header.additional_count = 0;
+        Ok(res)
+    }
+
+    /// Answers the reverse lookup of one of our made up addresses with a
+    /// CNAME to the reverse name of the IPv4 address, along with whatever
+    /// that resolves to
+    fn resolve_ptr(
+        &self,
+        req: &DnsPacket,
+        v4: Ipv4Addr,
+        limits: Limits,
+    ) -> Result<DnsPacket, ResolveError> {
+        let target = reverse_v4(v4);
+        let mut v4_req = req.clone();
+        v4_req.queries[0].name = target.clone();
+        let mut res = self.resolver.resolve(&v4_req, limits)?;
+        let mut cname = DnsAnswer::new();
+        cname.name = req.queries[0].name.clone();
+        cname.qtype = ResourceType::CName;
+        cname.class = 1;
+        cname.ttl = PTR_CNAME_TTL;
+        cname.rdata = serialize_domain_to_bytes(&target);
+        cname.data_length = cname.rdata.len() as u16;
+        res.answers.insert(0, cname);
+        res.queries = req.queries.clone();
+        res.header.answers_count = res.answers.len() as u16;
+        Ok(res)
+    }
+}
+
+impl<R: Resolver> Resolver for Dns64<R> {
+    fn resolve(&self, req: &DnsPacket, limits: Limits) -> Result<DnsPacket, ResolveError> {
+        let query = match req.queries.as_slice() {
+            [query] if query.class == 1 => query,
+            _ => return self.resolver.resolve(req, limits),
+        };
+        match query.qtype {
+            ResourceType::AAAA => self.resolve_aaaa(req, limits),
+            ResourceType::PTR => match reverse_v6(&query.name).and_then(|v6| self.embedded(v6)) {
+                Some(v4) => self.resolve_ptr(req, v4, limits),
+                None => self.resolver.resolve(req, limits),
+            },
+            _ => self.resolver.resolve(req, limits),
+        }
+    }
+}
+
+fn address(record: &DnsAnswer) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    if record.rdata.len() == 16 {
+        octets.copy_from_slice(&record.rdata);
+    }
+    Ipv6Addr::from(octets)
+}
+
+/// `4.3.2.1.in-addr.arpa` for 1.2.3.4
+pub fn reverse_v4(address: Ipv4Addr) -> String {
+    let octets = address.octets();
+    format!(
+        "{}.{}.{}.{}.in-addr.arpa",
+        octets[3], octets[2], octets[1], octets[0]
+    )
+}
+
+/// The address a name in `ip6.arpa` is the reverse of, one nibble per label
+/// with the last one first
+pub fn reverse_v6(name: &str) -> Option<Ipv6Addr> {
+    let name = normalize(name);
+    let nibbles = name.strip_suffix(".ip6.arpa")?.split('.');
+    let mut address = 0u128;
+    let mut count = 0;
+    for (i, nibble) in nibbles.enumerate() {
+        if i >= 32 || nibble.len() != 1 {
+            return None;
+        }
+        let nibble = u128::from_str_radix(nibble, 16).ok()?;
+        address |= nibble << (4 * i);
+        count += 1;
+    }
+    if count != 32 {
+        return None;
+    }
+    Some(Ipv6Addr::from(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::DnsQuery;
+    use crate::resolvers::MockResolver;
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, Instant};
+
+    fn record(name: &str, qtype: ResourceType, rdata: Vec<u8>) -> DnsAnswer {
+        let mut record = DnsAnswer::new();
+        record.name = name.to_owned();
+        record.qtype = qtype;
+        record.class = 1;
+        record.ttl = 300;
+        record.data_length = rdata.len() as u16;
+        record.rdata = rdata;
+        record
+    }
+
+    fn a(name: &str, address: &str) -> DnsAnswer {
+        let address = address.parse::<Ipv4Addr>().unwrap();
+        record(name, ResourceType::A, address.octets().to_vec())
+    }
+
+    fn aaaa(name: &str, address: &str) -> DnsAnswer {
+        let address = address.parse::<Ipv6Addr>().unwrap();
+        record(name, ResourceType::AAAA, address.octets().to_vec())
+    }
+
+    fn request(name: &str, qtype: ResourceType) -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = qtype;
+        query.class = 1;
+        let mut req = DnsPacket::new();
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(1),
+            attempts: 1,
+            deadline: Instant::now() + Duration::from_secs(5),
+        }
+    }
+
+    fn dns64() -> Dns64<MockResolver> {
+        let resolver = MockResolver::new().with_records(vec![
+            a("v4only.com", "192.0.2.1"),
+            a("v4only.com", "10.1.2.3"),
+            a("dual.com", "192.0.2.2"),
+            aaaa("dual.com", "2001:db8::2"),
+            a("mapped.com", "192.0.2.3"),
+            aaaa("mapped.com", "::ffff:192.0.2.3"),
+            record(
+                "1.2.0.192.in-addr.arpa",
+                ResourceType::PTR,
+                serialize_domain_to_bytes("v4only.com"),
+            ),
+        ]);
+        let exclude = ["::ffff:0:0/96", "10.0.0.0/8"]
+            .iter()
+            .map(|network| Network::parse(network).unwrap())
+            .collect();
+        Dns64::new(resolver, WELL_KNOWN_PREFIX, exclude)
+    }
+
+    #[test]
+    fn test_synthesizes_aaaa_records() {
+        let res = dns64()
+            .resolve(&request("v4only.com", ResourceType::AAAA), limits())
+            .unwrap();
+        // 10.1.2.3 is excluded
+        assert_eq!(vec![aaaa("v4only.com", "64:ff9b::c000:201")], res.answers);
+        assert_eq!(
+            request("v4only.com", ResourceType::AAAA).queries,
+            res.queries
+        );
+        assert_eq!(1, res.header.answers_count);
+
+        // real ones are left alone, unless they're excluded
+        let res = dns64()
+            .resolve(&request("dual.com", ResourceType::AAAA), limits())
+            .unwrap();
+        assert_eq!(vec![aaaa("dual.com", "2001:db8::2")], res.answers);
+        let res = dns64()
+            .resolve(&request("mapped.com", ResourceType::AAAA), limits())
+            .unwrap();
+        assert_eq!(vec![aaaa("mapped.com", "64:ff9b::c000:203")], res.answers);
+
+        let res = dns64()
+            .resolve(&request("nope.com", ResourceType::AAAA), limits())
+            .unwrap();
+        assert_eq!(ResponseCode::NameError, res.header.response_code);
+        let res = dns64()
+            .resolve(&request("v4only.com", ResourceType::A), limits())
+            .unwrap();
+        assert_eq!(2, res.answers.len());
+    }
+
+    #[test]
+    fn test_reverse_lookups_of_synthesized_addresses() {
+        let v6 = "64:ff9b::c000:201".parse::<Ipv6Addr>().unwrap();
+        let name = "1.0.2.0.0.0.0.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.b.9.f.f.4.6.0.0.ip6.arpa";
+        assert_eq!(Some(v6), reverse_v6(name));
+        let res = dns64()
+            .resolve(&request(name, ResourceType::PTR), limits())
+            .unwrap();
+        let mut cname = record(
+            name,
+            ResourceType::CName,
+            serialize_domain_to_bytes("1.2.0.192.in-addr.arpa"),
+        );
+        cname.ttl = PTR_CNAME_TTL;
+        let ptr = record(
+            "1.2.0.192.in-addr.arpa",
+            ResourceType::PTR,
+            serialize_domain_to_bytes("v4only.com"),
+        );
+        assert_eq!(vec![cname, ptr], res.answers);
+        assert_eq!(request(name, ResourceType::PTR).queries, res.queries);
+
+        // outside the prefix it's an ordinary lookup
+        let name = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        let res = dns64()
+            .resolve(&request(name, ResourceType::PTR), limits())
+            .unwrap();
+        assert_eq!(ResponseCode::NameError, res.header.response_code);
+        assert_eq!(None, reverse_v6("1.0.ip6.arpa"));
+        assert_eq!(None, reverse_v6("foo.com"));
+    }
+
+    #[test]
+    fn test_networks() {
+        let network = Network::parse("64:ff9b::/96").unwrap();
+        assert!(network.contains("64:ff9b::1.2.3.4".parse().unwrap()));
+        assert!(!network.contains("64:ff9b:1::".parse().unwrap()));
+        assert!(!network.contains("1.2.3.4".parse().unwrap()));
+        let network = Network::parse("10.0.0.0/8").unwrap();
+        assert!(network.contains("10.255.0.1".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+        assert!(Network::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+        assert_eq!(None, Network::parse("10.0.0.0/33"));
+        assert_eq!(None, Network::parse("10.0.0.0"));
+    }
+}
@@ -0,0 +1,450 @@
+//! DNS-over-HTTPS (RFC 8484). Queries arrive either as a base64url encoded
+//! `dns` parameter on a GET or as the body of a POST, in both cases they are
+//! plain wire format packets that we hand to the `DnsClient`
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::{DecodePaddingMode, Engine};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::client::DnsClient;
+use crate::header::ResponseCode;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::json;
+use crate::packet::DnsPacket;
+use crate::query::DnsQuery;
+use crate::resolvers::Resolver;
+use crate::serialization::{FromBytes, ToBytes};
+use crate::signals::SHUTDOWN_POLL;
+
+pub const DNS_MESSAGE: &str = "application/dns-message";
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many connections get a thread of their own at once, the ones after
+/// that are closed right away
+const MAX_CONNECTIONS: usize = 128;
+
+/// RFC 8484 says the `dns` parameter is sent without padding, but being
+/// lenient here costs us nothing
+const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+pub fn serve<R>(client: &DnsClient<R>, addr: &str, tls: Arc<ServerConfig>, shutdown: &AtomicBool)
+where
+    R: Resolver + Sync,
+{
+    let listener = TcpListener::bind(addr).expect("Could not create DNS-over-HTTPS server");
+    serve_listener(client, &listener, tls, shutdown);
+}
+
+pub fn serve_listener<R>(
+    client: &DnsClient<R>,
+    listener: &TcpListener,
+    tls: Arc<ServerConfig>,
+    shutdown: &AtomicBool,
+) where
+    R: Resolver + Sync,
+{
+    let connections = &AtomicUsize::new(0);
+    thread::scope(|s| {
+        s.spawn(|| wake_on_shutdown(listener, shutdown));
+        for stream in listener.incoming() {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            // dropping the stream closes it
+            if connections.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                continue;
+            }
+            connections.fetch_add(1, Ordering::SeqCst);
+            let tls = tls.clone();
+            s.spawn(move || {
+                // a client going away half way through a request is not our problem
+                let _ = handle_connection(client, stream, tls, shutdown);
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+/// `accept()` can't time out, so once we're shutting down we wake it up with a
+/// connection of our own
+pub fn wake_on_shutdown(listener: &TcpListener, shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(SHUTDOWN_POLL);
+    }
+    if let Ok(mut addr) = listener.local_addr() {
+        if addr.ip().is_unspecified() {
+            if addr.is_ipv4() {
+                addr.set_ip(Ipv4Addr::LOCALHOST.into());
+            } else {
+                addr.set_ip(Ipv6Addr::LOCALHOST.into());
+            }
+        }
+        let _ = TcpStream::connect(addr);
+    }
+}
+
+fn handle_connection<R>(
+    client: &DnsClient<R>,
+    stream: TcpStream,
+    tls: Arc<ServerConfig>,
+    shutdown: &AtomicBool,
+) -> Result<(), String>
+where
+    R: Resolver,
+{
+    let conn = ServerConnection::new(tls).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(StreamOwned::new(conn, stream));
+    loop {
+        if shutdown.load(Ordering::SeqCst) || !wait_for_request(&mut reader, shutdown)? {
+            let stream = reader.get_mut();
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+            return Ok(());
+        }
+        let (res, keep_alive) = match HttpRequest::read_from(&mut reader) {
+            Ok(Some(req)) => (handle(client, &req), req.keep_alive()),
+            Ok(None) => return Ok(()),
+            Err(_) => (HttpResponse::new(400), false),
+        };
+        let stream = reader.get_mut();
+        stream
+            .write_all(&res.to_bytes())
+            .and_then(|_| stream.flush())
+            .map_err(|e| e.to_string())?;
+        if !keep_alive {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+            return Ok(());
+        }
+    }
+}
+
+/// Waits for the next request on the connection, giving up when the client has
+/// been idle for too long or we're shutting down. Returns whether there is a
+/// request to read
+fn wait_for_request(
+    reader: &mut BufReader<StreamOwned<ServerConnection, TcpStream>>,
+    shutdown: &AtomicBool,
+) -> Result<bool, String> {
+    let idle_since = Instant::now();
+    let set_timeout = |reader: &BufReader<StreamOwned<ServerConnection, TcpStream>>, timeout| {
+        reader
+            .get_ref()
+            .sock
+            .set_read_timeout(Some(timeout))
+            .map_err(|e| e.to_string())
+    };
+    set_timeout(reader, SHUTDOWN_POLL)?;
+    loop {
+        match reader.fill_buf() {
+            Ok(buf) => {
+                let ready = !buf.is_empty();
+                // a request that has started arriving gets the full timeout
+                set_timeout(reader, IDLE_TIMEOUT)?;
+                return Ok(ready);
+            }
+            Err(ref e)
+                if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut
+                    || e.kind() == ErrorKind::Interrupted =>
+            {
+                if shutdown.load(Ordering::SeqCst) || idle_since.elapsed() >= IDLE_TIMEOUT {
+                    return Ok(false);
+                }
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Turns one HTTP request into the HTTP response to send back
+pub fn handle<R>(client: &DnsClient<R>, req: &HttpRequest) -> HttpResponse
+where
+    R: Resolver,
+{
+    match req.path.as_str() {
+        "/dns-query" => (),
+        "/resolve" => return json::handle(client, req),
+        _ => return HttpResponse::new(404),
+    }
+    let message = match req.method.as_str() {
+        "GET" => match req.params.get("dns").map(|dns| BASE64URL.decode(dns)) {
+            Some(Ok(message)) => message,
+            _ => return HttpResponse::new(400),
+        },
+        "POST" => {
+            if req.header("content-type") != Some(DNS_MESSAGE) {
+                return HttpResponse::new(415);
+            }
+            req.body.clone()
+        }
+        _ => {
+            let mut res = HttpResponse::new(405);
+            res.set_header("Allow", "GET, POST");
+            return res;
+        }
+    };
+    let packet = match DnsPacket::from_bytes(&message) {
+        Ok((packet, _)) => packet,
+        Err(_) => return HttpResponse::new(400),
+    };
+    dns_response(client, packet)
+}
+
+/// Answers `req` through `client`, the HTTP caching lifetime of the result is
+/// that of the shortest lived record in it
+pub fn dns_response<R>(client: &DnsClient<R>, req: DnsPacket) -> HttpResponse
+where
+    R: Resolver,
+{
+    let packet = answer(client, req);
+    let mut res = HttpResponse::with_body(200, DNS_MESSAGE, packet.to_bytes());
+    res.set_header("Cache-Control", &format!("max-age={}", max_age(&packet)));
+    res
+}
+
+/// Unlike over UDP we can't just stay quiet when a domain is blocked, the
+/// HTTP request still needs a response so we refuse the query instead
+pub fn answer<R>(client: &DnsClient<R>, req: DnsPacket) -> DnsPacket
+where
+    R: Resolver,
+{
+    let tx_id = req.header.tx_id;
+    let queries = req.queries.clone();
+    match client.results(req) {
+        Ok(packet) => packet,
+        Err(()) => refused(tx_id, queries),
+    }
+}
+
+/// The answer to a query we won't answer
+pub fn refused(tx_id: u16, queries: Vec<DnsQuery>) -> DnsPacket {
+    let mut packet = DnsPacket::new_error(ResponseCode::Refused);
+    packet.header.tx_id = tx_id;
+    packet.header.questions_count = queries.len() as u16;
+    packet.queries = queries;
+    packet
+}
+
+/// From RFC 8484: 'The assigned freshness lifetime of a DoH HTTP response
+/// MUST be less than or equal to the smallest TTL in the Answer section of
+/// the DNS response.' Negative answers have no answers so we fall back to
+/// the SOA in the authority section
+pub fn max_age(packet: &DnsPacket) -> u32 {
+    packet
+        .answers
+        .iter()
+        .map(|ans| ans.ttl)
+        .min()
+        .or_else(|| packet.authority.iter().map(|ans| ans.ttl).min())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::answer::DnsAnswer;
+    use crate::cache::Cache;
+    use crate::header::ResourceType;
+    use crate::http::HttpResponse;
+    use crate::resolvers::MockResolver;
+    use crate::tls;
+    use pretty_assertions::assert_eq;
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConnection;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    /// Two records for foo.com that don't agree on how long they live
+    fn answers() -> Vec<DnsAnswer> {
+        let mut answer = DnsAnswer::new();
+        answer.name = "foo.com".to_owned();
+        answer.qtype = ResourceType::A;
+        answer.ttl = 300;
+        answer.data_length = 4;
+        answer.rdata = vec![0xde, 0xca, 0xfb, 0xad];
+        let mut shorter = answer.clone();
+        shorter.ttl = 42;
+        vec![answer, shorter]
+    }
+
+    fn resolver() -> MockResolver {
+        MockResolver::new().with_records(answers())
+    }
+
+    fn header<'a>(res: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        res.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn request(name: &str) -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = ResourceType::A;
+        let mut req = DnsPacket::new();
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    #[test]
+    fn test_get_and_post() {
+        let cache = Cache::new(1);
+        let client = DnsClient::new(resolver(), &cache, HashMap::new());
+
+        let mut get = HttpRequest::new();
+        get.method = "GET".to_owned();
+        get.path = "/dns-query".to_owned();
+        get.params.insert(
+            "dns".to_owned(),
+            BASE64URL.encode(request("foo.com").to_bytes()),
+        );
+        let res = handle(&client, &get);
+        assert_eq!(200, res.status);
+        assert_eq!(Some(DNS_MESSAGE), header(&res, "Content-Type"));
+        assert_eq!(Some("max-age=42"), header(&res, "Cache-Control"));
+        let (packet, _) = DnsPacket::from_bytes(&res.body).unwrap();
+        let mut expected = request("foo.com");
+        expected.header.is_response = true;
+        expected.header.recursion_available = true;
+        expected.header.answers_count = 2;
+        expected.answers = answers();
+        assert_eq!(expected, packet);
+
+        let mut post = HttpRequest::new();
+        post.method = "POST".to_owned();
+        post.path = "/dns-query".to_owned();
+        post.headers
+            .insert("content-type".to_owned(), DNS_MESSAGE.to_owned());
+        post.body = request("foo.com").to_bytes();
+        assert_eq!(res, handle(&client, &post));
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let cache = Cache::new(1);
+        let client = DnsClient::new(resolver(), &cache, HashMap::new());
+
+        let mut req = HttpRequest::new();
+        req.method = "GET".to_owned();
+        req.path = "/".to_owned();
+        assert_eq!(404, handle(&client, &req).status);
+
+        req.path = "/dns-query".to_owned();
+        assert_eq!(400, handle(&client, &req).status);
+        req.params
+            .insert("dns".to_owned(), "not base64!".to_owned());
+        assert_eq!(400, handle(&client, &req).status);
+        req.params
+            .insert("dns".to_owned(), BASE64URL.encode([0, 1, 2]));
+        assert_eq!(400, handle(&client, &req).status);
+
+        req.method = "POST".to_owned();
+        req.body = request("foo.com").to_bytes();
+        assert_eq!(415, handle(&client, &req).status);
+
+        req.method = "PUT".to_owned();
+        let res = handle(&client, &req);
+        assert_eq!(405, res.status);
+        assert_eq!(Some("GET, POST"), header(&res, "Allow"));
+    }
+
+    #[test]
+    fn test_blocked_domain_is_refused() {
+        let cache = Cache::new(1);
+        let mut blocklist = HashMap::new();
+        blocklist.insert("foo.com".to_owned(), true);
+        let client = DnsClient::new(resolver(), &cache, blocklist);
+        let mut req = request("bar.foo.com");
+        req.header.tx_id = 0xbeef;
+        let res = answer(&client, req.clone());
+        assert_eq!(ResponseCode::Refused, res.header.response_code);
+        assert_eq!(0xbeef, res.header.tx_id);
+        assert_eq!(req.queries, res.queries);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut packet = DnsPacket::new_response();
+        assert_eq!(0, max_age(&packet));
+        let mut soa = DnsAnswer::new();
+        soa.qtype = ResourceType::StartOfAuthority;
+        soa.ttl = 900;
+        packet.authority = vec![soa];
+        assert_eq!(900, max_age(&packet));
+        packet.answers = answers();
+        assert_eq!(42, max_age(&packet));
+    }
+
+    #[test]
+    fn test_formerr_over_loopback() {
+        let temp_dir = TempDir::new("doh").unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_file = temp_dir.path().join("cert.pem");
+        let key_file = temp_dir.path().join("key.pem");
+        write(&cert_file, cert.cert.pem()).unwrap();
+        write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+        let cert_file = cert_file.to_str().unwrap();
+        let server_tls =
+            tls::server_config(cert_file, key_file.to_str().unwrap(), &[b"http/1.1"]).unwrap();
+        let client_tls = tls::client_config(Some(cert_file), &[], b"http/1.1").unwrap();
+
+        let cache = Cache::new(1);
+        let client = DnsClient::new(resolver(), &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| serve_listener(&client, &listener, server_tls, &shutdown));
+            let name = ServerName::try_from("localhost").unwrap();
+            let conn = ClientConnection::new(client_tls, name).unwrap();
+            let stream = StreamOwned::new(conn, TcpStream::connect(server_addr).unwrap());
+            let mut stream = BufReader::new(stream);
+            let mut post = HttpRequest::new();
+            post.method = "POST".to_owned();
+            post.path = "/dns-query".to_owned();
+            post.headers
+                .insert("content-type".to_owned(), DNS_MESSAGE.to_owned());
+            let mut exchange = |body: Vec<u8>| {
+                post.body = body;
+                stream.get_mut().write_all(&post.to_bytes()).unwrap();
+                let res = HttpResponse::read_from(&mut stream).unwrap();
+                assert_eq!(200, res.status);
+                DnsPacket::from_bytes(&res.body).unwrap().0
+            };
+
+            // a question that stops half way through its name
+            let mut malformed = request("foo.com");
+            malformed.header.tx_id = 0xbeef;
+            malformed.queries.clear();
+            let mut body = malformed.to_bytes();
+            body.extend(&[3, b'f', b'o']);
+            let res = exchange(body);
+            assert_eq!(ResponseCode::FormatError, res.header.response_code);
+            assert_eq!(0xbeef, res.header.tx_id);
+            // the same connection still gets its answers
+            let res = exchange(request("foo.com").to_bytes());
+            // leaving the scope joins the server, so this only passes if it stops
+            shutdown.store(true, Ordering::SeqCst);
+            assert_eq!(answers(), res.answers);
+        });
+    }
+}
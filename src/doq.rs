@@ -0,0 +1,326 @@
+//! DNS-over-QUIC (RFC 9250). Every query gets its own bidirectional stream
+//! and, like DNS over TCP, each message is prefixed with its 2 byte length.
+//! QUIC needs an async runtime, so the QUIC side lives on its own tokio
+//! runtime and hands the actual queries to the (blocking) `DnsClient` over a
+//! channel
+use std::convert::TryFrom;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Endpoint, EndpointConfig, Incoming, RecvStream, SendStream, TokioRuntime, VarInt};
+use rustls::ServerConfig;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::client::DnsClient;
+use crate::doh;
+use crate::packet::DnsPacket;
+use crate::resolvers::Resolver;
+use crate::serialization::{FromBytes, ToBytes};
+use crate::signals::SHUTDOWN_POLL;
+
+pub const ALPN: &[u8] = b"doq";
+/// From RFC 9250: 'No error. This is used when the connection or stream needs
+/// to be closed, but there is no error to signal'
+const DOQ_NO_ERROR: u32 = 0x0;
+/// From RFC 9250: 'The DoQ implementation encountered a protocol error and
+/// is forcibly aborting the connection'
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+/// How long shutting down waits for streams that are still being answered
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many queries get a thread of their own at once, the ones after that
+/// are refused until some of those are answered
+const MAX_QUERIES: usize = 128;
+
+type Job = (DnsPacket, oneshot::Sender<DnsPacket>);
+
+pub fn serve<R>(client: &DnsClient<R>, addr: &str, tls: Arc<ServerConfig>, shutdown: &AtomicBool)
+where
+    R: Resolver + Sync,
+{
+    let socket = UdpSocket::bind(addr).expect("Could not create DNS-over-QUIC server");
+    serve_socket(client, socket, tls, shutdown);
+}
+
+pub fn serve_socket<R>(
+    client: &DnsClient<R>,
+    socket: UdpSocket,
+    tls: Arc<ServerConfig>,
+    shutdown: &AtomicBool,
+) where
+    R: Resolver + Sync,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+    let queries = &AtomicUsize::new(0);
+    thread::scope(|s| {
+        // once `run` returns every sender is gone and the loop below ends
+        s.spawn(move || run(socket, tls, tx, shutdown));
+        while let Some((req, reply)) = rx.blocking_recv() {
+            if queries.load(Ordering::SeqCst) >= MAX_QUERIES {
+                let _ = reply.send(doh::refused(req.header.tx_id, req.queries));
+                continue;
+            }
+            queries.fetch_add(1, Ordering::SeqCst);
+            s.spawn(move || {
+                // the stream might have been reset while we were resolving
+                let _ = reply.send(doh::answer(client, req));
+                queries.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+fn run(
+    socket: UdpSocket,
+    tls: Arc<ServerConfig>,
+    tx: mpsc::UnboundedSender<Job>,
+    shutdown: &AtomicBool,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Could not start the DNS-over-QUIC runtime");
+    runtime.block_on(async move {
+        let crypto = QuicServerConfig::try_from(tls)
+            .expect("The TLS configuration can't be used for DNS-over-QUIC");
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .expect("Could not create DNS-over-QUIC server");
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        while !shutdown.load(Ordering::SeqCst) {
+            match tokio::time::timeout(SHUTDOWN_POLL, endpoint.accept()).await {
+                Ok(Some(incoming)) => {
+                    tokio::spawn(handle_connection(incoming, tx.clone(), in_flight.clone()));
+                }
+                Ok(None) => return,
+                Err(_) => (),
+            }
+        }
+        // refuse new connections, let the streams we have finish and only
+        // then tell everyone we're going away
+        endpoint.set_server_config(None);
+        let started = Instant::now();
+        while in_flight.load(Ordering::SeqCst) > 0 && started.elapsed() < DRAIN_TIMEOUT {
+            tokio::time::sleep(SHUTDOWN_POLL).await;
+        }
+        endpoint.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+        endpoint.wait_idle().await;
+    });
+}
+
+async fn handle_connection(
+    incoming: Incoming,
+    tx: mpsc::UnboundedSender<Job>,
+    in_flight: Arc<AtomicUsize>,
+) {
+    let conn = match incoming.await {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    while let Ok((send, recv)) = conn.accept_bi().await {
+        let (tx, in_flight) = (tx.clone(), in_flight.clone());
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            let _ = handle_stream(send, recv, tx).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+async fn handle_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    tx: mpsc::UnboundedSender<Job>,
+) -> Result<(), String> {
+    let mut len = [0u8; 2];
+    recv.read_exact(&mut len).await.map_err(|e| e.to_string())?;
+    let mut message = vec![0; u16::from_be_bytes(len) as usize];
+    recv.read_exact(&mut message)
+        .await
+        .map_err(|e| e.to_string())?;
+    let req = match DnsPacket::from_bytes(&message) {
+        Ok((req, _)) => req,
+        Err(_) => {
+            let _ = send.reset(VarInt::from_u32(DOQ_PROTOCOL_ERROR));
+            return Err("Malformed query".to_owned());
+        }
+    };
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tx.send((req, reply_tx)).map_err(|e| e.to_string())?;
+    let res = reply_rx.await.map_err(|e| e.to_string())?.to_bytes();
+    send.write_all(&(res.len() as u16).to_be_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    send.write_all(&res).await.map_err(|e| e.to_string())?;
+    send.finish().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::header::{ResourceType, ResponseCode};
+    use crate::query::DnsQuery;
+    use crate::resolvers::{Limits, StubResolver};
+    use crate::tls;
+    use pretty_assertions::assert_eq;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::crypto::ring::default_provider;
+    use rustls::pki_types::CertificateDer;
+    use rustls::{ClientConfig, RootCertStore};
+    use std::collections::HashMap;
+    use std::fs::write;
+    use std::net::SocketAddr;
+    use tempdir::TempDir;
+
+    /// TLS for a server on localhost and for a client that trusts it
+    fn configs() -> (Arc<ServerConfig>, quinn::ClientConfig) {
+        let temp_dir = TempDir::new("doq").unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_file = temp_dir.path().join("cert.pem");
+        let key_file = temp_dir.path().join("key.pem");
+        write(&cert_file, cert.cert.pem()).unwrap();
+        write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+        let server_tls = tls::server_config(
+            cert_file.to_str().unwrap(),
+            key_file.to_str().unwrap(),
+            &[ALPN],
+        )
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.cert.der().to_vec()))
+            .unwrap();
+        let mut client_tls = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_tls.alpn_protocols = vec![ALPN.to_vec()];
+        let client_config =
+            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_tls).unwrap()));
+        (server_tls, client_config)
+    }
+
+    /// Sends each of `messages` to the server at `server_addr` on a stream of
+    /// its own, all on the same connection, and returns the raw responses
+    fn exchange(
+        server_addr: SocketAddr,
+        client_config: quinn::ClientConfig,
+        messages: Vec<Vec<u8>>,
+    ) -> Vec<Vec<u8>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            endpoint.set_default_client_config(client_config);
+            let conn = endpoint
+                .connect(server_addr, "localhost")
+                .unwrap()
+                .await
+                .unwrap();
+            let mut responses = Vec::new();
+            for message in messages {
+                let (mut send, mut recv) = conn.open_bi().await.unwrap();
+                send.write_all(&(message.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                send.write_all(&message).await.unwrap();
+                send.finish().unwrap();
+                responses.push(recv.read_to_end(4096).await.unwrap());
+            }
+            responses
+        })
+    }
+
+    fn query() -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = "foo.com".to_owned();
+        query.qtype = ResourceType::A;
+        let mut req = DnsPacket::new();
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    /// The packet in a length prefixed response
+    fn unframe(res_bytes: &[u8]) -> DnsPacket {
+        let len = u16::from_be_bytes([res_bytes[0], res_bytes[1]]) as usize;
+        assert_eq!(res_bytes.len() - 2, len);
+        DnsPacket::from_bytes(&res_bytes[2..]).unwrap().0
+    }
+
+    #[test]
+    fn test_query_over_loopback() {
+        let (server_tls, client_config) = configs();
+        let cache = Cache::new(1);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+
+        let req = query();
+        let res_bytes = thread::scope(|s| {
+            s.spawn(|| serve_socket(&client, socket, server_tls, &shutdown));
+            // two queries on the same connection, each on their own stream
+            let res_bytes = exchange(server_addr, client_config, vec![req.to_bytes(); 2]);
+            // leaving the scope joins the server, so this only passes if it stops
+            shutdown.store(true, Ordering::SeqCst);
+            res_bytes
+        });
+
+        let limits = Limits {
+            timeout: Duration::from_secs(1),
+            attempts: 1,
+            deadline: Instant::now(),
+        };
+        let expected = StubResolver.resolve(&req, limits).unwrap();
+        for res_bytes in res_bytes {
+            assert_eq!(expected, unframe(&res_bytes));
+        }
+    }
+
+    #[test]
+    fn test_formerr_over_loopback() {
+        let (server_tls, client_config) = configs();
+        let cache = Cache::new(1);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+
+        // a question that stops half way through its name
+        let mut malformed = query();
+        malformed.header.tx_id = 0xbeef;
+        malformed.queries.clear();
+        let mut malformed = malformed.to_bytes();
+        malformed.extend(&[3, b'f', b'o']);
+        let res_bytes = thread::scope(|s| {
+            s.spawn(|| serve_socket(&client, socket, server_tls, &shutdown));
+            let messages = vec![malformed, query().to_bytes()];
+            let res_bytes = exchange(server_addr, client_config, messages);
+            shutdown.store(true, Ordering::SeqCst);
+            res_bytes
+        });
+
+        let res = unframe(&res_bytes[0]);
+        assert_eq!(ResponseCode::FormatError, res.header.response_code);
+        assert_eq!(0xbeef, res.header.tx_id);
+        // the same connection still gets its answers
+        let res = unframe(&res_bytes[1]);
+        assert_eq!(query().queries, res.queries);
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+    }
+}
@@ -0,0 +1,444 @@
+//! Forwarding sends every query we can't answer ourselves to a fixed set of
+//! recursive resolvers instead of iterating from the roots. We keep track of
+//! how the upstreams are doing so the ones that are down get left alone for a
+//! while
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::header::ResponseCode;
+use crate::hints::normalize;
+use crate::iterative::in_zone;
+use crate::packet::DnsPacket;
+use crate::resolvers::{Limits, ResolveError, Resolver};
+use crate::upstream::{Transport, Upstream};
+
+/// An upstream that failed this many times in a row is considered down
+const MAX_FAILURES: u32 = 3;
+
+/// How long a down upstream is skipped before it gets another chance
+const DOWN_FOR: Duration = Duration::from_secs(30);
+
+/// Which upstream gets asked first
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Strategy {
+    /// In the order they were configured, the rest are only for failover
+    Ordered,
+    /// Each query starts at the next upstream
+    RoundRobin,
+    /// The one that has been answering the quickest
+    Fastest,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ordered" => Some(Strategy::Ordered),
+            "round_robin" => Some(Strategy::RoundRobin),
+            "fastest" => Some(Strategy::Fastest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Health {
+    failures: u32,
+    down_until: Option<Instant>,
+    /// Smoothed round trip time, `None` until it first answered
+    rtt: Option<Duration>,
+}
+
+impl Health {
+    fn is_up(&self, now: Instant) -> bool {
+        self.down_until.is_none_or(|until| now >= until)
+    }
+
+    fn succeeded(&mut self, rtt: Duration) {
+        self.failures = 0;
+        self.down_until = None;
+        // the same weighting TCP uses for its smoothed RTT
+        self.rtt = Some(match self.rtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.failures += 1;
+        if self.failures >= MAX_FAILURES {
+            self.down_until = Some(now + DOWN_FOR);
+        }
+    }
+}
+
+/// Asks its upstreams instead of resolving anything itself. Plain UDP
+/// upstreams are asked with `exchange`
+pub struct Forwarder<Q> {
+    upstreams: Vec<Transport>,
+    strategy: Strategy,
+    health: Mutex<Vec<Health>>,
+    /// Where the next round robin query starts
+    next: AtomicUsize,
+    exchange: Q,
+}
+
+impl<Q> Forwarder<Q>
+where
+    Q: Fn(&str, DnsPacket, Duration) -> DnsPacket,
+{
+    pub fn new(upstreams: &[Upstream], strategy: Strategy, exchange: Q) -> Result<Self, String> {
+        let upstreams = upstreams
+            .iter()
+            .map(Transport::new)
+            .collect::<Result<Vec<Transport>, String>>()?;
+        let health = vec![Health::default(); upstreams.len()];
+        Ok(Forwarder {
+            upstreams,
+            strategy,
+            health: Mutex::new(health),
+            next: AtomicUsize::new(0),
+            exchange,
+        })
+    }
+
+    /// The upstreams to try in the order the strategy wants them, the ones
+    /// that are up always go before the ones that are down
+    fn order(&self) -> Vec<usize> {
+        let mut order = (0..self.upstreams.len()).collect::<Vec<usize>>();
+        let health = self.health.lock().unwrap();
+        match self.strategy {
+            Strategy::Ordered => (),
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                order.rotate_left(start % self.upstreams.len().max(1));
+            }
+            // upstreams we haven't heard from yet go first so they get
+            // measured
+            Strategy::Fastest => order.sort_by_key(|&i| health[i].rtt),
+        }
+        let now = Instant::now();
+        order.sort_by_key(|&i| !health[i].is_up(now));
+        order
+    }
+}
+
+impl<Q> Resolver for Forwarder<Q>
+where
+    Q: Fn(&str, DnsPacket, Duration) -> DnsPacket,
+{
+    /// Asks the upstreams until one of them answers `req`, with a longer
+    /// timeout for every round through them
+    fn resolve(&self, req: &DnsPacket, limits: Limits) -> Result<DnsPacket, ResolveError> {
+        // the upstreams recurse for us
+        let mut query = req.clone();
+        query.header.recursion_desired = true;
+        let order = self.order();
+        let mut timeout = limits.timeout;
+        for _ in 0..limits.attempts {
+            for &i in &order {
+                let remaining = limits.deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    return Err(ResolveError::Timeout);
+                }
+                let sent = Instant::now();
+                let mut res =
+                    self.upstreams[i].exchange(&query, timeout.min(remaining), &self.exchange);
+                let mut health = self.health.lock().unwrap();
+                match res.header.response_code {
+                    ResponseCode::NoError | ResponseCode::NameError => {
+                        health[i].succeeded(sent.elapsed());
+                        res.header.recursion_available = true;
+                        // we're passing on someone else's data
+                        res.header.authoritative = false;
+                        return Ok(res);
+                    }
+                    _ => health[i].failed(Instant::now()),
+                }
+            }
+            timeout *= 2;
+        }
+        Err(ResolveError::Failed(
+            "None of the upstreams answered".to_owned(),
+        ))
+    }
+}
+
+type Log = Box<dyn Fn(&str) + Send + Sync>;
+
+/// Sends the domains that have forwarding rules to their own upstreams and
+/// everything else to `otherwise`. A rule's domains never go anywhere else,
+/// even when its upstreams fail
+pub struct Rules<Q, R> {
+    rules: Vec<(String, Forwarder<Q>)>,
+    otherwise: R,
+    /// Told which rule each query that matched one went to
+    log: Option<Log>,
+}
+
+impl<Q, R> Rules<Q, R> {
+    pub fn new(rules: Vec<(String, Forwarder<Q>)>, otherwise: R) -> Self {
+        Rules {
+            rules,
+            otherwise,
+            log: None,
+        }
+    }
+
+    pub fn with_log(mut self, log: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.log = Some(Box::new(log));
+        self
+    }
+}
+
+impl<Q, R> Resolver for Rules<Q, R>
+where
+    Q: Fn(&str, DnsPacket, Duration) -> DnsPacket,
+    R: Resolver,
+{
+    fn resolve(&self, req: &DnsPacket, limits: Limits) -> Result<DnsPacket, ResolveError> {
+        let query = match req.queries.first() {
+            Some(query) => query,
+            None => return self.otherwise.resolve(req, limits),
+        };
+        // the most specific rule wins
+        let name = normalize(&query.name);
+        let rule = self
+            .rules
+            .iter()
+            .filter(|(suffix, _)| in_zone(&name, suffix))
+            .max_by_key(|(suffix, _)| suffix.len());
+        match rule {
+            Some((suffix, forwarder)) => {
+                if let Some(log) = &self.log {
+                    log(&format!(
+                        "{} {:?} goes to the upstreams for {}",
+                        query.name, query.qtype, suffix
+                    ));
+                }
+                forwarder.resolve(req, limits)
+            }
+            None => self.otherwise.resolve(req, limits),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::DnsQuery;
+    use pretty_assertions::assert_eq;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::thread::sleep;
+
+    fn upstreams() -> Vec<Upstream> {
+        ["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:5353"]
+            .iter()
+            .map(|address| Upstream::Udp(address.parse().unwrap()))
+            .collect()
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(1),
+            attempts: 2,
+            deadline: Instant::now() + Duration::from_secs(10),
+        }
+    }
+
+    fn request(name: &str) -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        let mut req = DnsPacket::new();
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    fn answer(req: DnsPacket) -> DnsPacket {
+        let mut res = req;
+        res.header.is_response = true;
+        res.header.authoritative = true;
+        res
+    }
+
+    /// Upstreams that answer everything, except for the ones that are down.
+    /// Every upstream asked is written down
+    #[derive(Default)]
+    struct Network {
+        down: RefCell<Vec<&'static str>>,
+        slow: RefCell<Vec<&'static str>>,
+        asked: RefCell<Vec<String>>,
+    }
+
+    impl Network {
+        fn exchange(&self, host: &str, req: DnsPacket) -> DnsPacket {
+            assert!(req.header.recursion_desired);
+            self.asked.borrow_mut().push(host.to_owned());
+            if self.slow.borrow().contains(&host) {
+                sleep(Duration::from_millis(20));
+            }
+            if self.down.borrow().contains(&host) {
+                DnsPacket::new_error(ResponseCode::ServerError)
+            } else {
+                answer(req)
+            }
+        }
+
+        /// Forwards a query with the upstreams in `down` failing, returns
+        /// who was asked
+        fn ask<R: Resolver>(&self, forwarder: &R, down: &[&'static str]) -> Vec<String> {
+            *self.down.borrow_mut() = down.to_vec();
+            let _ = forwarder.resolve(&request("foo.com"), limits());
+            self.asked.replace(Vec::new())
+        }
+    }
+
+    #[test]
+    fn test_ordered_fails_over_and_skips_upstreams_that_are_down() {
+        let network = Network::default();
+        let forwarder = Forwarder::new(&upstreams(), Strategy::Ordered, |host, req, _| {
+            network.exchange(host, req)
+        })
+        .unwrap();
+        assert_eq!(vec!["10.0.0.1:53"], network.ask(&forwarder, &[]));
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(
+                vec!["10.0.0.1:53", "10.0.0.2:53"],
+                network.ask(&forwarder, &["10.0.0.1:53"])
+            );
+        }
+        // 10.0.0.1 is down now and goes last until it's had its time out
+        assert_eq!(vec!["10.0.0.2:53"], network.ask(&forwarder, &[]));
+        forwarder.health.lock().unwrap()[0].down_until = Some(Instant::now());
+        assert_eq!(vec!["10.0.0.1:53"], network.ask(&forwarder, &[]));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let network = Network::default();
+        let forwarder = Forwarder::new(&upstreams(), Strategy::RoundRobin, |host, req, _| {
+            network.exchange(host, req)
+        })
+        .unwrap();
+        let first = (0..4)
+            .map(|_| network.ask(&forwarder, &[]).remove(0))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            vec!["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:5353", "10.0.0.1:53"],
+            first
+        );
+    }
+
+    #[test]
+    fn test_fastest() {
+        let network = Network::default();
+        *network.slow.borrow_mut() = vec!["10.0.0.1:53", "10.0.0.3:5353"];
+        let forwarder = Forwarder::new(&upstreams(), Strategy::Fastest, |host, req, _| {
+            network.exchange(host, req)
+        })
+        .unwrap();
+        for _ in 0..3 {
+            network.ask(&forwarder, &[]);
+        }
+        // after each of them was tried once the quickest one sticks
+        assert_eq!(vec!["10.0.0.2:53"], network.ask(&forwarder, &[]));
+        assert_eq!(vec!["10.0.0.2:53"], network.ask(&forwarder, &[]));
+    }
+
+    #[test]
+    fn test_gives_up_when_no_upstream_answers() {
+        let asked = RefCell::new(Vec::new());
+        let forwarder = Forwarder::new(&upstreams(), Strategy::Ordered, |_, _, timeout| {
+            asked.borrow_mut().push(timeout.as_secs());
+            DnsPacket::new_error(ResponseCode::Refused)
+        })
+        .unwrap();
+        let res = forwarder.resolve(&request("foo.com"), limits());
+        assert_eq!(
+            Err(ResolveError::Failed(
+                "None of the upstreams answered".to_owned()
+            )),
+            res
+        );
+        // every upstream gets a second try with twice the time
+        assert_eq!(vec![1, 1, 1, 2, 2, 2], asked.replace(Vec::new()));
+
+        let mut limits = limits();
+        limits.deadline = Instant::now();
+        let res = forwarder.resolve(&request("foo.com"), limits);
+        assert_eq!(Err(ResolveError::Timeout), res);
+    }
+
+    #[test]
+    fn test_answers_are_marked_as_recursive() {
+        let forwarder =
+            Forwarder::new(&upstreams(), Strategy::Ordered, |_, req, _| answer(req)).unwrap();
+        let res = forwarder.resolve(&request("foo.com"), limits()).unwrap();
+        assert!(res.header.recursion_available);
+        assert!(!res.header.authoritative);
+    }
+
+    #[test]
+    fn test_rules_match_the_longest_suffix() {
+        let network = Network::default();
+        let exchange = |host: &str, req, _| network.exchange(host, req);
+        let rule = |suffix: &str, upstream: &str| {
+            let upstreams = [Upstream::Udp(upstream.parse().unwrap())];
+            let forwarder = Forwarder::new(&upstreams, Strategy::Ordered, exchange).unwrap();
+            (suffix.to_owned(), forwarder)
+        };
+        let everything_else = Forwarder::new(
+            &[Upstream::Udp("9.9.9.9:53".parse().unwrap())],
+            Strategy::Ordered,
+            exchange,
+        )
+        .unwrap();
+        let rules = Rules::new(
+            vec![
+                rule("corp.internal", "10.1.0.1:53"),
+                rule("dev.corp.internal", "10.2.0.1:53"),
+                rule("consul", "127.0.0.1:8600"),
+            ],
+            everything_else,
+        );
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        let rules = rules.with_log(move |line| log.lock().unwrap().push(line.to_owned()));
+        for (name, host) in &[
+            ("corp.internal", "10.1.0.1:53"),
+            ("www.Corp.Internal", "10.1.0.1:53"),
+            ("build.dev.corp.internal", "10.2.0.1:53"),
+            ("notcorp.internal", "9.9.9.9:53"),
+            ("example.com", "9.9.9.9:53"),
+        ] {
+            rules.resolve(&request(name), limits()).unwrap();
+            assert_eq!(
+                vec![host.to_string()],
+                network.asked.replace(Vec::new()),
+                "{}",
+                name
+            );
+        }
+        // only the names that matched a rule are logged, with the rule
+        assert_eq!(
+            vec![
+                "corp.internal Unused goes to the upstreams for corp.internal",
+                "www.Corp.Internal Unused goes to the upstreams for corp.internal",
+                "build.dev.corp.internal Unused goes to the upstreams for dev.corp.internal",
+            ],
+            *logged.lock().unwrap()
+        );
+        // a rule's upstreams failing doesn't send its names anywhere else
+        *network.down.borrow_mut() = vec!["127.0.0.1:8600"];
+        rules
+            .resolve(&request("web.service.consul"), limits())
+            .unwrap_err();
+        assert!(network
+            .asked
+            .into_inner()
+            .iter()
+            .all(|host| host == "127.0.0.1:8600"));
+    }
+}
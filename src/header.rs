@@ -11,6 +11,9 @@ pub enum ResourceType {
     AAAA,
     MX,
     NS,
+    PTR,
+    TXT,
+    SRV,
 }
 
 impl ResourceType {
@@ -21,8 +24,11 @@ impl ResourceType {
             Self::NS => 2,
             Self::CName => 5,
             Self::StartOfAuthority => 6,
+            Self::PTR => 12,
             Self::MX => 15,
+            Self::TXT => 16,
             Self::AAAA => 28,
+            Self::SRV => 33,
             _ => 0, // FIXME should this be 0?
         }
     }
@@ -37,8 +43,11 @@ impl TryInto<ResourceType> for u16 {
             2 => Ok(ResourceType::NS),
             5 => Ok(ResourceType::CName),
             6 => Ok(ResourceType::StartOfAuthority),
+            12 => Ok(ResourceType::PTR),
             15 => Ok(ResourceType::MX),
+            16 => Ok(ResourceType::TXT),
             28 => Ok(ResourceType::AAAA),
+            33 => Ok(ResourceType::SRV),
             _ => Err(ResponseCode::NotImplemented),
         }
     }
@@ -55,6 +64,9 @@ impl TryInto<ResourceType> for &str {
             "SOA" => Ok(ResourceType::StartOfAuthority),
             "AAAA" => Ok(ResourceType::AAAA),
             "MX" => Ok(ResourceType::MX),
+            "PTR" => Ok(ResourceType::PTR),
+            "TXT" => Ok(ResourceType::TXT),
+            "SRV" => Ok(ResourceType::SRV),
             _ => Err(ResponseCode::NotImplemented),
         }
     }
@@ -156,14 +168,28 @@ impl DnsHeader {
     }
 }
 
+impl Default for DnsHeader {
+    fn default() -> Self {
+        DnsHeader::new()
+    }
+}
+
 impl FromBytes for DnsHeader {
     fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), Self> {
+        if bytes.len() < 12 {
+            return Err(DnsHeader::new());
+        }
         let tx_id = NetworkEndian::read_u16(bytes);
         let flags = &bytes[2..4];
         let questions_count = NetworkEndian::read_u16(&bytes[4..6]);
         let answers_count = NetworkEndian::read_u16(&bytes[6..8]);
         let authority_count = NetworkEndian::read_u16(&bytes[8..10]);
         let additional_count = NetworkEndian::read_u16(&bytes[10..12]);
+        // the codes past Refused are for extensions we don't speak
+        let response_code = match (flags[1] & 0x0f).try_into() {
+            Ok(code) => code,
+            Err(_) => return Err(DnsHeader::new()),
+        };
         Ok((
             DnsHeader {
                 tx_id,
@@ -174,7 +200,7 @@ impl FromBytes for DnsHeader {
                 recursion_desired: flags[0] & 0x01 > 0,
                 recursion_available: flags[1] & 0x80 > 0,
                 z: (flags[1] & 0x70) >> 4,
-                response_code: (flags[1] & 0x0f).try_into().unwrap(),
+                response_code,
                 questions_count,
                 answers_count,
                 authority_count,
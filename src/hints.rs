@@ -0,0 +1,123 @@
+//! Root hints in the `named.root` format published by InterNIC, they are
+//! where every iterative resolution starts
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+
+/// The copy of `named.root` we were built with, used unless the config points
+/// at a newer one
+const BUILT_IN: &str = include_str!("named.root");
+
+/// A nameserver along with the addresses we know it by
+#[derive(Debug, PartialEq, Clone)]
+pub struct NameServer {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Reads the root hints from `filename`, or the built-in ones without it
+pub fn root_hints(filename: Option<&str>) -> Result<Vec<NameServer>, String> {
+    match filename {
+        Some(filename) => parse_root_hints(
+            &read_to_string(filename)
+                .map_err(|e| format!("Could not read root hints {}: {}", filename, e))?,
+        ),
+        None => parse_root_hints(BUILT_IN),
+    }
+}
+
+/// Only the NS records of the root and the A and AAAA records of those
+/// nameservers are of interest, everything else in the file is ignored
+pub fn parse_root_hints(contents: &str) -> Result<Vec<NameServer>, String> {
+    let mut names = Vec::new();
+    let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("");
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if fields.is_empty() {
+            continue;
+        }
+        // the class is optional and sits between the ttl and the type
+        let (owner, rtype, data) = match fields.as_slice() {
+            [owner, _ttl, "IN", rtype, data] | [owner, _ttl, rtype, data] => {
+                (normalize(owner), rtype.to_ascii_uppercase(), *data)
+            }
+            _ => return Err(format!("Invalid root hint on line {}", number + 1)),
+        };
+        if rtype == "NS" && owner.is_empty() {
+            names.push(normalize(data));
+        } else if rtype == "A" || rtype == "AAAA" {
+            let address = data
+                .parse::<IpAddr>()
+                .map_err(|_| format!("Invalid address on line {}", number + 1))?;
+            addresses.entry(owner).or_default().push(address);
+        }
+    }
+    let servers = names
+        .into_iter()
+        .filter_map(|name| {
+            let addresses = addresses.remove(&name)?;
+            Some(NameServer { name, addresses })
+        })
+        .collect::<Vec<NameServer>>();
+    if servers.is_empty() {
+        return Err("The root hints have no nameservers with addresses".to_owned());
+    }
+    Ok(servers)
+}
+
+/// Names are compared case insensitively and without the trailing dot
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_root_hints() {
+        let input = "
+; a comment
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000  IN  AAAA  2001:503:ba3e::2:30
+.                        3600000      NS    B.ROOT-SERVERS.NET. ; no addresses
+.                        3600000      NS    C.ROOT-SERVERS.NET.
+C.ROOT-SERVERS.NET.      3600000      A     192.33.4.12
+";
+        assert_eq!(
+            vec![
+                NameServer {
+                    name: "a.root-servers.net".to_owned(),
+                    addresses: vec![
+                        "198.41.0.4".parse().unwrap(),
+                        "2001:503:ba3e::2:30".parse().unwrap()
+                    ],
+                },
+                NameServer {
+                    name: "c.root-servers.net".to_owned(),
+                    addresses: vec!["192.33.4.12".parse().unwrap()],
+                },
+            ],
+            parse_root_hints(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_root_hints() {
+        parse_root_hints("").unwrap_err();
+        parse_root_hints(". 3600000 NS\n").unwrap_err();
+        parse_root_hints(". 3600000 NS a.root.\na.root. 3600000 A 1.2.3\n").unwrap_err();
+        root_hints(Some("does/not/exist")).unwrap_err();
+    }
+
+    #[test]
+    fn test_built_in_root_hints() {
+        let roots = root_hints(None).unwrap();
+        assert_eq!(13, roots.len());
+        assert!(roots.iter().all(|root| root.addresses.len() == 2));
+        assert_eq!("a.root-servers.net", roots[0].name);
+    }
+}
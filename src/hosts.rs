@@ -0,0 +1,349 @@
+//! Names from /etc/hosts style files, for when a handful of them don't call
+//! for a zone of their own. Every line is an address followed by the names it
+//! goes by, the files are read again whenever they change
+use std::collections::HashMap;
+use std::fs::{metadata, read_to_string};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::answer::DnsAnswer;
+use crate::client::DnsClient;
+use crate::config::Config;
+use crate::dns64::reverse_v6;
+use crate::header::ResourceType;
+use crate::hints::normalize;
+use crate::query::DnsQuery;
+use crate::resolvers::Resolver;
+use crate::serialization::serialize_domain_to_bytes;
+use crate::signals::SHUTDOWN_POLL;
+
+/// The files don't say how long their records are good for
+pub const HOSTS_TTL: u32 = 60;
+
+/// How often we check if the files changed
+pub const HOSTS_POLL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Hosts {
+    addresses: HashMap<String, Vec<IpAddr>>,
+    /// The names of each address, the one listed first first
+    names: HashMap<IpAddr, Vec<String>>,
+}
+
+impl Hosts {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut hosts = Hosts::default();
+        for (i, line) in contents.lines().enumerate() {
+            let mut words = line.split('#').next().unwrap_or("").split_whitespace();
+            let address = match words.next() {
+                Some(address) => address,
+                None => continue,
+            };
+            // link local addresses with a zone mean nothing to other machines
+            if address.contains('%') {
+                continue;
+            }
+            let address = address
+                .parse::<IpAddr>()
+                .map_err(|_| format!("line {}: '{}' is not an address", i + 1, address))?;
+            let names = words.map(normalize).collect::<Vec<String>>();
+            if names.is_empty() {
+                return Err(format!("line {}: {} has no names", i + 1, address));
+            }
+            for name in names {
+                let addresses = hosts.addresses.entry(name.clone()).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+                let names = hosts.names.entry(address).or_default();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(hosts)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.addresses.contains_key(&normalize(name))
+    }
+
+    /// The A, AAAA or PTR records for `query`, nothing when the files don't
+    /// know its name. Names they know have no records of any other type, or
+    /// without an address of the type asked for
+    pub fn answers(&self, query: &DnsQuery) -> Option<Vec<DnsAnswer>> {
+        let name = normalize(&query.name);
+        let addresses = self.addresses.get(&name);
+        let names = reverse(&name).and_then(|address| self.names.get(&address));
+        if addresses.is_none() && names.is_none() {
+            return None;
+        }
+        let rdata = match query.qtype {
+            ResourceType::A | ResourceType::AAAA => addresses
+                .into_iter()
+                .flatten()
+                .filter_map(|address| match (address, &query.qtype) {
+                    (IpAddr::V4(v4), ResourceType::A) => Some(v4.octets().to_vec()),
+                    (IpAddr::V6(v6), ResourceType::AAAA) => Some(v6.octets().to_vec()),
+                    _ => None,
+                })
+                .collect::<Vec<Vec<u8>>>(),
+            ResourceType::PTR => names
+                .into_iter()
+                .flatten()
+                .map(|name| serialize_domain_to_bytes(name))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(
+            rdata
+                .into_iter()
+                .map(|rdata| {
+                    let mut record = DnsAnswer::new();
+                    record.name = query.name.clone();
+                    record.qtype = query.qtype.clone();
+                    record.class = query.class;
+                    record.ttl = HOSTS_TTL;
+                    record.data_length = rdata.len() as u16;
+                    record.rdata = rdata;
+                    record
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The address a name in `in-addr.arpa` or `ip6.arpa` is the reverse of
+fn reverse(name: &str) -> Option<IpAddr> {
+    let name = normalize(name);
+    if name.ends_with(".ip6.arpa") {
+        return reverse_v6(&name).map(IpAddr::V6);
+    }
+    let mut octets = name
+        .strip_suffix(".in-addr.arpa")?
+        .split('.')
+        .map(|octet| octet.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if octets.len() != 4 {
+        return None;
+    }
+    octets.reverse();
+    Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into())
+}
+
+/// Merges every hosts file in `filenames` into one, nothing is returned unless
+/// every file is there and valid
+pub fn load_hosts(filenames: &[String]) -> Result<Hosts, String> {
+    let mut hosts = String::new();
+    for filename in filenames {
+        let contents =
+            read_to_string(filename).map_err(|e| format!("{}: Could not read: {}", filename, e))?;
+        Hosts::parse(&contents).map_err(|e| format!("{}: {}", filename, e))?;
+        hosts.push_str(&contents);
+        hosts.push('\n');
+    }
+    Hosts::parse(&hosts)
+}
+
+/// When each of the files was last written to and how long it was then, to
+/// tell if it changed
+fn modified(filenames: &[String]) -> Vec<Option<(SystemTime, u64)>> {
+    filenames
+        .iter()
+        .map(|filename| {
+            let metadata = metadata(filename).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+/// Reloads the hosts files every time one of them changes, checking every
+/// `interval` until `shutdown` is set. A file that changed since the last
+/// check may still be being written, so we wait for one check where it
+/// didn't before reading it
+pub fn watch<R>(client: &DnsClient<R>, config: &Config, interval: Duration, shutdown: &AtomicBool)
+where
+    R: Resolver,
+{
+    let mut loaded = modified(&config.hosts);
+    let mut last = loaded.clone();
+    let mut checked = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(interval.min(SHUTDOWN_POLL));
+        if checked.elapsed() < interval {
+            continue;
+        }
+        checked = Instant::now();
+        let now = modified(&config.hosts);
+        let settled = now == last;
+        last = now;
+        if !settled || last == loaded {
+            continue;
+        }
+        loaded = last.clone();
+        match client.reload_hosts(config) {
+            Ok(()) => println!("Reloaded the hosts files"),
+            Err(e) => eprintln!("Could not reload, keeping the old hosts files: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::resolvers::StubResolver;
+    use pretty_assertions::assert_eq;
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    fn query(name: &str, qtype: ResourceType) -> DnsQuery {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = qtype;
+        query
+    }
+
+    fn rdata(answers: Option<Vec<DnsAnswer>>) -> Option<Vec<String>> {
+        answers.map(|answers| {
+            answers
+                .iter()
+                .map(|answer| answer.data_to_string())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_answers() {
+        let hosts = Hosts::parse(
+            "# the usual\n\
+             127.0.0.1 localhost\n\
+             ::1       localhost ip6-localhost\n\
+             \n\
+             10.0.0.1  db.internal db # the database\n\
+             10.0.0.2  db.internal\n\
+             fd00::1   DB.internal\n\
+             fe80::1%lo0 localhost\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Some(vec!["10.0.0.1".to_owned(), "10.0.0.2".to_owned()]),
+            rdata(hosts.answers(&query("db.Internal.", ResourceType::A)))
+        );
+        assert_eq!(
+            Some(vec!["fd00::1".to_owned()]),
+            rdata(hosts.answers(&query("db.internal", ResourceType::AAAA)))
+        );
+        assert_eq!(
+            "db.Internal.",
+            hosts
+                .answers(&query("db.Internal.", ResourceType::A))
+                .unwrap()[0]
+                .name
+        );
+        // known names have no other records
+        assert_eq!(
+            Some(vec![]),
+            rdata(hosts.answers(&query("ip6-localhost", ResourceType::A)))
+        );
+        assert_eq!(
+            Some(vec![]),
+            rdata(hosts.answers(&query("db.internal", ResourceType::MX)))
+        );
+        assert_eq!(
+            Some(vec![]),
+            rdata(hosts.answers(&query("1.0.0.10.in-addr.arpa", ResourceType::TXT)))
+        );
+        assert_eq!(None, hosts.answers(&query("web.internal", ResourceType::A)));
+
+        assert_eq!(
+            Some(vec!["db.internal.".to_owned(), "db.".to_owned()]),
+            rdata(hosts.answers(&query("1.0.0.10.in-addr.arpa", ResourceType::PTR)))
+        );
+        let v6 = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa";
+        assert_eq!(
+            Some(vec!["db.internal.".to_owned()]),
+            rdata(hosts.answers(&query(v6, ResourceType::PTR)))
+        );
+        assert_eq!(
+            None,
+            hosts.answers(&query("3.0.0.10.in-addr.arpa", ResourceType::PTR))
+        );
+    }
+
+    #[test]
+    fn test_invalid_hosts() {
+        assert_eq!(
+            Err("line 2: '10.0.0' is not an address".to_owned()),
+            Hosts::parse("10.0.0.1 db\n10.0.0 web\n")
+        );
+        assert_eq!(
+            Err("line 1: 10.0.0.1 has no names".to_owned()),
+            Hosts::parse("10.0.0.1 # db\n")
+        );
+    }
+
+    #[test]
+    fn test_load_multiple_hosts_files() {
+        let temp_dir = TempDir::new("hosts").unwrap();
+        let first = temp_dir.path().join("first");
+        let second = temp_dir.path().join("second");
+        write(&first, "10.0.0.1 db").unwrap();
+        write(&second, "10.0.0.2 db\n10.0.0.3 web\n").unwrap();
+        let files = [&first, &second]
+            .iter()
+            .map(|path| path.to_str().unwrap().to_owned())
+            .collect::<Vec<String>>();
+        let hosts = load_hosts(&files).unwrap();
+        assert_eq!(
+            Some(vec!["10.0.0.1".to_owned(), "10.0.0.2".to_owned()]),
+            rdata(hosts.answers(&query("db", ResourceType::A)))
+        );
+        assert!(hosts.contains("web"));
+
+        write(&second, "web\n").unwrap();
+        let e = load_hosts(&files).unwrap_err();
+        assert!(e.starts_with(files[1].as_str()), "{}", e);
+        load_hosts(&[temp_dir.path().join("missing").to_str().unwrap().to_owned()]).unwrap_err();
+    }
+
+    #[test]
+    fn test_changed_files_are_reloaded() {
+        let temp_dir = TempDir::new("hosts").unwrap();
+        let file = temp_dir.path().join("hosts");
+        write(&file, "10.0.0.1 db\n").unwrap();
+        let mut config = Config::new();
+        config.hosts = vec![file.to_str().unwrap().to_owned()];
+        let cache = Cache::new(1);
+        let client = DnsClient::new(StubResolver, &cache, HashMap::new())
+            .with_hosts(load_hosts(&config.hosts).unwrap());
+        let shutdown = AtomicBool::new(false);
+        let address = |name: &str| {
+            let mut req = crate::packet::DnsPacket::new();
+            req.header.questions_count = 1;
+            req.queries = vec![query(name, ResourceType::A)];
+            let res = client.results(req).unwrap();
+            res.answers.first().map(|answer| answer.data_to_string())
+        };
+        thread::scope(|s| {
+            s.spawn(|| watch(&client, &config, Duration::from_millis(20), &shutdown));
+            // it compares against the files as they were when it started
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(Some("10.0.0.1".to_owned()), address("db"));
+            write(&file, "10.0.0.2 db\n10.0.0.3 web\n").unwrap();
+            let reloaded = (0..100).any(|_| {
+                thread::sleep(Duration::from_millis(20));
+                address("web").is_some()
+            });
+            // an invalid file leaves the old names in place
+            write(&file, "web\n").unwrap();
+            thread::sleep(Duration::from_millis(200));
+            shutdown.store(true, Ordering::SeqCst);
+            assert!(reloaded);
+            assert_eq!(Some("10.0.0.2".to_owned()), address("db"));
+            assert_eq!(Some("10.0.0.3".to_owned()), address("web"));
+        });
+    }
+}
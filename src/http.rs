@@ -0,0 +1,395 @@
+//! Just enough HTTP/1.1 to serve DNS over HTTPS and to forward queries to
+//! someone else's, we only ever need a start line, a handful of headers and a
+//! (small) body
+use std::collections::HashMap;
+use std::io::{BufRead, Read};
+
+/// The largest body we accept, a DNS message can't be bigger than this anyway
+const MAX_BODY_LENGTH: usize = 65535;
+const MAX_HEADERS: usize = 100;
+/// The longest start line or header we read, so that a peer that never sends
+/// a newline can't make us buffer forever
+const MAX_LINE_LENGTH: usize = 8192;
+
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub params: HashMap<String, String>,
+    /// Header names are lowercased so lookups are case insensitive
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new() -> Self {
+        HttpRequest {
+            method: String::new(),
+            path: String::new(),
+            params: HashMap::new(),
+            headers: HashMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|v| v.as_str())
+    }
+
+    /// Whether the client wants the connection to stay open after the
+    /// response is written
+    pub fn keep_alive(&self) -> bool {
+        !self
+            .header("connection")
+            .map(|v| v.eq_ignore_ascii_case("close"))
+            .unwrap_or(false)
+    }
+
+    /// Reads one request off of `reader`, `Ok(None)` means the peer closed
+    /// the connection before sending anything
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<Self>, String> {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().ok_or("Missing method")?.to_owned();
+        let target = parts.next().ok_or("Missing request target")?;
+        match parts.next() {
+            Some(version) if version.starts_with("HTTP/1.") => (),
+            _ => return Err("Unsupported HTTP version".to_owned()),
+        }
+        let mut req = HttpRequest::new();
+        req.method = method;
+        match target.find('?') {
+            Some(pos) => {
+                req.path = target[..pos].to_owned();
+                req.params = parse_query_string(&target[pos + 1..]);
+            }
+            None => req.path = target.to_owned(),
+        }
+
+        req.headers = read_headers(reader)?.into_iter().collect();
+        if let Some(length) = req.header("content-length").map(|v| v.to_owned()) {
+            req.body = read_body(reader, &length)?;
+        }
+        Ok(Some(req))
+    }
+
+    /// The request as we'd send it, `params` are expected to be part of
+    /// `path` already
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut req = format!("{} {} HTTP/1.1\r\n", self.method, self.path).into_bytes();
+        for (name, value) in &self.headers {
+            req.extend(format!("{}: {}\r\n", name, value).into_bytes());
+        }
+        req.extend(format!("Content-Length: {}\r\n\r\n", self.body.len()).into_bytes());
+        req.extend(&self.body);
+        req
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> Self {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_body(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        let mut res = HttpResponse::new(status);
+        res.set_header("Content-Type", content_type);
+        res.body = body;
+        res
+    }
+
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_owned(), value.to_owned()));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Reads the response to a request we sent off of `reader`
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Self, String> {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Err("Connection closed before the response".to_owned());
+        }
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some(version) if version.starts_with("HTTP/1.") => (),
+            _ => return Err("Unsupported HTTP version".to_owned()),
+        }
+        let status = parts
+            .next()
+            .and_then(|status| status.parse().ok())
+            .ok_or("Invalid status")?;
+        let mut res = HttpResponse::new(status);
+        res.headers = read_headers(reader)?;
+        // we always know how long the bodies we ask for are going to be
+        let length = res
+            .header("content-length")
+            .ok_or("Missing Content-Length")?
+            .to_owned();
+        res.body = read_body(reader, &length)?;
+        Ok(res)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status)).into_bytes();
+        for (name, value) in &self.headers {
+            res.extend(format!("{}: {}\r\n", name, value).into_bytes());
+        }
+        res.extend(format!("Content-Length: {}\r\n\r\n", self.body.len()).into_bytes());
+        res.extend(&self.body);
+        res
+    }
+}
+
+/// Reads headers up to the empty line that ends them, names are lowercased
+fn read_headers<R: BufRead>(reader: &mut R) -> Result<Vec<(String, String)>, String> {
+    let mut headers = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if read_line(reader, &mut line)? == 0 {
+            return Err("Connection closed in the middle of the headers".to_owned());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err("Too many headers".to_owned());
+        }
+        let pos = header.find(':').ok_or("Malformed header")?;
+        headers.push((
+            header[..pos].trim().to_ascii_lowercase(),
+            header[pos + 1..].trim().to_owned(),
+        ));
+    }
+}
+
+/// Reads one line of at most `MAX_LINE_LENGTH` bytes into `line`
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<usize, String> {
+    let read = reader
+        .take(MAX_LINE_LENGTH as u64)
+        .read_line(line)
+        .map_err(|e| e.to_string())?;
+    if read == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err("Line too long".to_owned());
+    }
+    Ok(read)
+}
+
+fn read_body<R: BufRead>(reader: &mut R, length: &str) -> Result<Vec<u8>, String> {
+    let length: usize = length.parse().map_err(|_| "Invalid Content-Length")?;
+    if length > MAX_BODY_LENGTH {
+        return Err("Body too large".to_owned());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(body)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+pub fn parse_query_string(s: &str) -> HashMap<String, String> {
+    s.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(pos) => (
+                percent_decode(&pair[..pos]),
+                percent_decode(&pair[pos + 1..]),
+            ),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        res.push(high << 4 | low);
+                        i += 3;
+                        continue;
+                    }
+                    _ => res.push(b'%'),
+                }
+            }
+            b'+' => res.push(b' '),
+            byte => res.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::BufReader;
+
+    #[test]
+    fn test_read_get_request() {
+        let input = b"GET /dns-query?dns=AAABAAAB&foo HTTP/1.1\r\nHost: localhost\r\nAccept: application/dns-message\r\n\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let actual = HttpRequest::read_from(&mut reader).unwrap().unwrap();
+        let mut expected = HttpRequest::new();
+        expected.method = "GET".to_owned();
+        expected.path = "/dns-query".to_owned();
+        expected
+            .params
+            .insert("dns".to_owned(), "AAABAAAB".to_owned());
+        expected.params.insert("foo".to_owned(), String::new());
+        expected
+            .headers
+            .insert("host".to_owned(), "localhost".to_owned());
+        expected
+            .headers
+            .insert("accept".to_owned(), "application/dns-message".to_owned());
+        assert_eq!(expected, actual);
+        assert!(HttpRequest::read_from(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_post_request() {
+        let input = b"POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\nContent-Length: 4\r\nConnection: close\r\n\r\n\xde\xca\xfb\xad";
+        let mut reader = BufReader::new(&input[..]);
+        let actual = HttpRequest::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!("POST", actual.method);
+        assert_eq!(
+            Some("application/dns-message"),
+            actual.header("Content-Type")
+        );
+        assert_eq!(vec![0xde, 0xca, 0xfb, 0xad], actual.body);
+        assert!(!actual.keep_alive());
+    }
+
+    #[test]
+    fn test_read_invalid_requests() {
+        let mut reader = BufReader::new(&b"GET /dns-query SPDY/3\r\n\r\n"[..]);
+        HttpRequest::read_from(&mut reader).unwrap_err();
+        let mut reader = BufReader::new(&b"GET /dns-query HTTP/1.1\r\nHost localhost\r\n\r\n"[..]);
+        HttpRequest::read_from(&mut reader).unwrap_err();
+        let mut reader = BufReader::new(&b"POST / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n"[..]);
+        HttpRequest::read_from(&mut reader).unwrap_err();
+    }
+
+    #[test]
+    fn test_read_overlong_lines() {
+        let target = "a".repeat(MAX_LINE_LENGTH);
+        let input = format!("GET /{} HTTP/1.1\r\n\r\n", target);
+        let mut reader = BufReader::new(input.as_bytes());
+        HttpRequest::read_from(&mut reader).unwrap_err();
+        let input = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", target);
+        let mut reader = BufReader::new(input.as_bytes());
+        HttpRequest::read_from(&mut reader).unwrap_err();
+        let input = format!("HTTP/1.1 200 OK\r\nServer: {}\r\n\r\n", target);
+        let mut reader = BufReader::new(input.as_bytes());
+        HttpResponse::read_from(&mut reader).unwrap_err();
+        // right up to the limit is fine
+        let header = format!("Cookie: {}\r\n", "a".repeat(MAX_LINE_LENGTH - 10));
+        let input = format!("GET / HTTP/1.1\r\n{}\r\n", header);
+        let mut reader = BufReader::new(input.as_bytes());
+        let req = HttpRequest::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!(MAX_LINE_LENGTH - 10, req.header("cookie").unwrap().len());
+    }
+
+    #[test]
+    fn test_read_too_many_headers() {
+        let headers = "X-Foo: bar\r\n".repeat(MAX_HEADERS + 1);
+        let input = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        let mut reader = BufReader::new(input.as_bytes());
+        HttpRequest::read_from(&mut reader).unwrap_err();
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!("foo.com", percent_decode("foo.com"));
+        assert_eq!("foo bar/", percent_decode("foo+bar%2F"));
+        assert_eq!("100%", percent_decode("100%"));
+        assert_eq!("%zz", percent_decode("%zz"));
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let mut req = HttpRequest::new();
+        req.method = "POST".to_owned();
+        req.path = "/dns-query".to_owned();
+        req.headers
+            .insert("host".to_owned(), "localhost".to_owned());
+        req.body = vec![0xde, 0xca, 0xfb, 0xad];
+        let bytes = req.to_bytes();
+        let actual = HttpRequest::read_from(&mut BufReader::new(&bytes[..]))
+            .unwrap()
+            .unwrap();
+        // the length is added on the way out
+        req.headers
+            .insert("content-length".to_owned(), "4".to_owned());
+        assert_eq!(req, actual);
+    }
+
+    #[test]
+    fn test_read_response() {
+        let res = HttpResponse::with_body(200, "application/dns-message", vec![1, 2, 3]);
+        let bytes = res.to_bytes();
+        let mut reader = BufReader::new(&bytes[..]);
+        let actual = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(200, actual.status);
+        assert_eq!(
+            Some("application/dns-message"),
+            actual.header("content-type")
+        );
+        assert_eq!(vec![1, 2, 3], actual.body);
+        let mut reader = BufReader::new(&b"HTTP/1.1 204 No Content\r\n\r\n"[..]);
+        HttpResponse::read_from(&mut reader).unwrap_err();
+    }
+
+    #[test]
+    fn test_response_to_bytes() {
+        let res = HttpResponse::with_body(200, "text/plain", b"hi".to_vec());
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi".to_vec(),
+            res.to_bytes()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! When lots of clients ask for the same name at once, say right after the
+//! cache was flushed, only the first of them gets it resolved. The others wait
+//! for that resolution to finish and get the same answer
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::hints::normalize;
+use crate::packet::DnsPacket;
+use crate::resolvers::{Limits, ResolveError, Resolver};
+
+/// The question we're resolving: name, type and class
+type Key = (String, u16, u16);
+
+type Outcome = Result<DnsPacket, ResolveError>;
+
+/// One resolution that's in flight, `landed` is signalled once `outcome` is
+/// there
+#[derive(Default)]
+struct Flight {
+    outcome: Mutex<Option<Outcome>>,
+    landed: Condvar,
+}
+
+/// Coalesces identical queries on their way to `resolver`
+pub struct Coalesce<R> {
+    resolver: R,
+    flights: Mutex<HashMap<Key, Arc<Flight>>>,
+}
+
+impl<R: Resolver> Coalesce<R> {
+    pub fn new(resolver: R) -> Self {
+        Coalesce {
+            resolver,
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolves `req` and hands the outcome to everyone waiting for it, even
+    /// when the resolver panics
+    fn lead(&self, key: Key, flight: &Flight, req: &DnsPacket, limits: Limits) -> Outcome {
+        struct Landing<'a, R> {
+            coalesce: &'a Coalesce<R>,
+            key: Option<Key>,
+            flight: &'a Flight,
+        }
+
+        impl<'a, R> Drop for Landing<'a, R> {
+            fn drop(&mut self) {
+                // whoever asks from now on starts a new resolution
+                if let Some(key) = self.key.take() {
+                    self.coalesce.flights.lock().unwrap().remove(&key);
+                }
+                let mut outcome = self.flight.outcome.lock().unwrap();
+                if outcome.is_none() {
+                    *outcome = Some(Err(ResolveError::Failed(
+                        "The resolution we were waiting for failed".to_owned(),
+                    )));
+                }
+                self.flight.landed.notify_all();
+            }
+        }
+
+        let _landing = Landing {
+            coalesce: self,
+            key: Some(key),
+            flight,
+        };
+        let outcome = self.resolver.resolve(req, limits);
+        *flight.outcome.lock().unwrap() = Some(outcome.clone());
+        outcome
+    }
+
+    /// Waits for someone else's resolution until our own deadline
+    fn follow(&self, flight: &Flight, req: &DnsPacket, limits: Limits) -> Outcome {
+        let mut outcome = flight.outcome.lock().unwrap();
+        loop {
+            if let Some(outcome) = &*outcome {
+                return outcome.clone().map(|res| answer_to(req, res));
+            }
+            let remaining = limits.deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(ResolveError::Timeout);
+            }
+            outcome = flight.landed.wait_timeout(outcome, remaining).unwrap().0;
+        }
+    }
+}
+
+impl<R: Resolver> Resolver for Coalesce<R> {
+    fn resolve(&self, req: &DnsPacket, limits: Limits) -> Outcome {
+        let query = match req.queries.as_slice() {
+            [query] => query,
+            _ => return self.resolver.resolve(req, limits),
+        };
+        let key = (normalize(&query.name), query.qtype.as_u16(), query.class);
+        let (flight, leading) = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(&key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    flights.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+        if leading {
+            self.lead(key, &flight, req, limits)
+        } else {
+            self.follow(&flight, req, limits)
+        }
+    }
+}
+
+/// The response someone else got made out to `req`
+fn answer_to(req: &DnsPacket, mut res: DnsPacket) -> DnsPacket {
+    res.header.tx_id = req.header.tx_id;
+    res.header.recursion_desired = req.header.recursion_desired;
+    // the names as this client spelled them
+    res.queries = req.queries.clone();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::answer::DnsAnswer;
+    use crate::header::ResourceType;
+    use crate::query::DnsQuery;
+    use crate::resolvers::MockResolver;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn request(name: &str, qtype: ResourceType, tx_id: u16) -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = qtype;
+        let mut req = DnsPacket::new();
+        req.header.tx_id = tx_id;
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    fn limits(deadline: Duration) -> Limits {
+        Limits {
+            timeout: Duration::from_secs(1),
+            attempts: 1,
+            deadline: Instant::now() + deadline,
+        }
+    }
+
+    /// Holds every resolution until it's let go
+    struct Gate {
+        resolver: MockResolver,
+        started: AtomicUsize,
+        open: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Resolver for Gate {
+        fn resolve(&self, req: &DnsPacket, limits: Limits) -> Outcome {
+            self.started.fetch_add(1, Ordering::SeqCst);
+            self.open.lock().unwrap().recv().unwrap();
+            self.resolver.resolve(req, limits)
+        }
+    }
+
+    fn gate() -> (Coalesce<Gate>, mpsc::Sender<()>) {
+        let mut record = DnsAnswer::new();
+        record.name = "foo.com".to_owned();
+        record.qtype = ResourceType::A;
+        record.class = 1;
+        record.data_length = 4;
+        record.rdata = vec![10, 0, 0, 1];
+        let (open, gate) = mpsc::channel();
+        let gate = Gate {
+            resolver: MockResolver::new().with_records(vec![record]),
+            started: AtomicUsize::new(0),
+            open: Mutex::new(gate),
+        };
+        (Coalesce::new(gate), open)
+    }
+
+    #[test]
+    fn test_identical_queries_are_resolved_once() {
+        let (coalesce, open) = gate();
+        let coalesce = &coalesce;
+        let responses = thread::scope(|s| {
+            let handles = (0..8)
+                .map(|i| {
+                    let name = if i % 2 == 0 { "foo.com" } else { "FOO.com" };
+                    s.spawn(move || {
+                        let req = request(name, ResourceType::A, i);
+                        (
+                            req.clone(),
+                            coalesce.resolve(&req, limits(Duration::from_secs(5))),
+                        )
+                    })
+                })
+                .collect::<Vec<_>>();
+            // a different type is a different question
+            let other = s.spawn(move || {
+                coalesce.resolve(
+                    &request("foo.com", ResourceType::AAAA, 42),
+                    limits(Duration::from_secs(5)),
+                )
+            });
+            thread::sleep(Duration::from_millis(200));
+            open.send(()).unwrap();
+            open.send(()).unwrap();
+            other.join().unwrap().unwrap();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(2, coalesce.resolver.started.load(Ordering::SeqCst));
+        assert_eq!(2, coalesce.resolver.resolver.asked().len());
+        for (req, res) in responses {
+            let res = res.unwrap();
+            assert_eq!(req.header.tx_id, res.header.tx_id);
+            assert_eq!(req.queries, res.queries);
+            assert_eq!(1, res.answers.len());
+        }
+        assert!(coalesce.flights.lock().unwrap().is_empty());
+
+        // once it's landed the next one goes out again
+        open.send(()).unwrap();
+        coalesce
+            .resolve(
+                &request("foo.com", ResourceType::A, 1),
+                limits(Duration::from_secs(5)),
+            )
+            .unwrap();
+        assert_eq!(3, coalesce.resolver.started.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_followers_give_up_at_their_deadline() {
+        let (coalesce, open) = gate();
+        let coalesce = &coalesce;
+        thread::scope(|s| {
+            let leader = s.spawn(move || {
+                coalesce.resolve(
+                    &request("foo.com", ResourceType::A, 1),
+                    limits(Duration::from_secs(5)),
+                )
+            });
+            thread::sleep(Duration::from_millis(100));
+            let res = coalesce.resolve(
+                &request("foo.com", ResourceType::A, 2),
+                limits(Duration::from_millis(50)),
+            );
+            assert_eq!(Err(ResolveError::Timeout), res);
+            open.send(()).unwrap();
+            leader.join().unwrap().unwrap();
+        });
+    }
+}
@@ -0,0 +1,125 @@
+//! What we've learned about the nameservers we talk to, shared by all the
+//! queries we send them
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What we guess for servers we haven't heard from yet, or not in a while.
+/// A bit slower than most so that the ones we know to be fast go first
+pub const UNKNOWN_RTT: Duration = Duration::from_millis(376);
+
+/// However often a server timed out, it gets asked again eventually
+pub const MAX_RTT: Duration = Duration::from_secs(120);
+
+/// How long what we know about a server stays good for, after that it gets
+/// a fresh start
+pub const INFRA_TTL: Duration = Duration::from_secs(900);
+
+/// How many tenths of a new measurement go into the smoothed RTT, the older
+/// ones decay by the rest every time
+const RTT_WEIGHT: u32 = 3;
+
+struct Rtt {
+    smoothed: Duration,
+    updated: Instant,
+}
+
+pub struct InfraCache {
+    /// Servers that don't answer with the question exactly as we asked it and
+    /// since when we know, there's no point in randomising the case of names
+    /// we send them
+    ignores_case: Mutex<HashMap<SocketAddr, Instant>>,
+    rtts: Mutex<HashMap<SocketAddr, Rtt>>,
+}
+
+impl InfraCache {
+    pub fn new() -> Self {
+        InfraCache {
+            ignores_case: Mutex::new(HashMap::new()),
+            rtts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn preserves_case(&self, server: SocketAddr) -> bool {
+        self.ignores_case
+            .lock()
+            .unwrap()
+            .get(&server)
+            .is_none_or(|since| since.elapsed() >= INFRA_TTL)
+    }
+
+    pub fn ignores_case(&self, server: SocketAddr) {
+        self.ignores_case
+            .lock()
+            .unwrap()
+            .insert(server, Instant::now());
+    }
+
+    /// How long we expect `server` to take to answer
+    pub fn rtt(&self, server: SocketAddr) -> Duration {
+        self.rtt_entry(server).unwrap_or(UNKNOWN_RTT)
+    }
+
+    /// `server` answered after `rtt`
+    pub fn answered(&self, server: SocketAddr, rtt: Duration) {
+        let smoothed = match self.rtt_entry(server) {
+            Some(old) => (old * (10 - RTT_WEIGHT) + rtt * RTT_WEIGHT) / 10,
+            None => rtt,
+        };
+        self.set_rtt(server, smoothed);
+    }
+
+    /// `server` didn't answer within `timeout`, it's held back by at least
+    /// twice that until it answers again
+    pub fn timed_out(&self, server: SocketAddr, timeout: Duration) {
+        let smoothed = self.rtt_entry(server).unwrap_or(UNKNOWN_RTT).max(timeout) * 2;
+        self.set_rtt(server, smoothed.min(MAX_RTT));
+    }
+
+    fn rtt_entry(&self, server: SocketAddr) -> Option<Duration> {
+        self.rtts
+            .lock()
+            .unwrap()
+            .get(&server)
+            .filter(|rtt| rtt.updated.elapsed() < INFRA_TTL)
+            .map(|rtt| rtt.smoothed)
+    }
+
+    fn set_rtt(&self, server: SocketAddr, smoothed: Duration) {
+        let rtt = Rtt {
+            smoothed,
+            updated: Instant::now(),
+        };
+        self.rtts.lock().unwrap().insert(server, rtt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_smoothed_rtt() {
+        let infra = InfraCache::new();
+        let server = "10.0.0.1:53".parse().unwrap();
+        assert_eq!(UNKNOWN_RTT, infra.rtt(server));
+        infra.answered(server, Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), infra.rtt(server));
+        infra.answered(server, Duration::from_millis(200));
+        assert_eq!(Duration::from_millis(130), infra.rtt(server));
+
+        infra.timed_out(server, Duration::from_secs(1));
+        assert_eq!(Duration::from_secs(2), infra.rtt(server));
+        infra.timed_out(server, Duration::from_secs(1));
+        assert_eq!(Duration::from_secs(4), infra.rtt(server));
+        for _ in 0..10 {
+            infra.timed_out(server, Duration::from_secs(1));
+        }
+        assert_eq!(MAX_RTT, infra.rtt(server));
+        // it comes back down once it answers again
+        infra.answered(server, Duration::from_millis(100));
+        assert!(infra.rtt(server) < MAX_RTT);
+    }
+}
@@ -0,0 +1,1113 @@
+//! Iterative resolution as described in RFC 1034 section 5.3.3. We start at
+//! the root servers and follow their referrals down the delegation chain until
+//! a server that is authoritative for the name answers, tells us the name does
+//! not exist (NXDOMAIN) or that it has no records of that type (NODATA)
+//!
+//! With QNAME minimisation (RFC 9156) on, the servers on the way down are
+//! only told one label more than the zone they serve, the full name and type
+//! only go to the servers that are authoritative for it
+//!
+//! Among the nameservers of a zone the ones that answered fastest before get
+//! asked first, the round trip times are kept in the `InfraCache`. If one
+//! doesn't answer quickly we don't wait for it to time out before asking the
+//! next one as well, IPv4 and IPv6 addresses taking turns ("happy eyeballs")
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::answer::DnsAnswer;
+use crate::header::{ResourceType, ResponseCode};
+use crate::hints::{normalize, NameServer};
+use crate::infra::InfraCache;
+use crate::packet::DnsPacket;
+use crate::query::DnsQuery;
+use crate::resolvers::{Cancel, Limits, ResolveError, Resolver};
+use crate::serialization::deserialize_domain_from_bytes;
+
+/// Each referral takes us at least one label further down, no real
+/// delegation chain comes close to this
+pub const MAX_REFERRALS: usize = 16;
+
+/// Looking up the address of a nameserver we weren't given glue for can run
+/// into another glueless delegation, this is how deep that may go
+pub const MAX_DEPTH: usize = 4;
+
+/// Names with lots of labels would take a query per label, past this many
+/// minimised queries we ask for the full name
+pub const MAX_MINIMISED_QUERIES: usize = 10;
+
+/// Now and then, one in this many times, a server that's a bit slower than
+/// the fastest gets asked first. Otherwise we'd never notice it got faster
+pub const EXPLORE_ODDS: u32 = 8;
+
+/// How much slower than the fastest a server may be to get that chance,
+/// those held back after timing out are well past it
+pub const RTT_BAND: Duration = Duration::from_millis(400);
+
+/// How long a server gets to answer before the next one is asked as well,
+/// RFC 8305 suggests this much for connection attempts
+pub const STAGGER: Duration = Duration::from_millis(250);
+
+/// Resolves queries starting from `roots`. `exchange` sends a query to the
+/// nameserver at the given address and returns whatever it answered within
+/// the given time, or as soon as it's cancelled
+pub struct Iterative<Q> {
+    roots: Vec<NameServer>,
+    exchange: Q,
+    minimise: bool,
+    infra: Arc<InfraCache>,
+}
+
+impl<Q> Iterative<Q>
+where
+    Q: Fn(&str, DnsPacket, Duration, &Cancel) -> DnsPacket + Sync,
+{
+    pub fn new(roots: Vec<NameServer>, exchange: Q) -> Self {
+        Iterative {
+            roots,
+            exchange,
+            minimise: false,
+            infra: Arc::new(InfraCache::new()),
+        }
+    }
+
+    /// Where to look up how fast the nameservers are, whoever sends our
+    /// queries should be keeping it up to date
+    pub fn with_infra(mut self, infra: Arc<InfraCache>) -> Self {
+        self.infra = infra;
+        self
+    }
+
+    /// Only tell each server as much of the name as it needs to know
+    pub fn with_qname_minimisation(mut self, minimise: bool) -> Self {
+        self.minimise = minimise;
+        self
+    }
+}
+
+impl<Q> Resolver for Iterative<Q>
+where
+    Q: Fn(&str, DnsPacket, Duration, &Cancel) -> DnsPacket + Sync,
+{
+    fn resolve(&self, req: &DnsPacket, limits: Limits) -> Result<DnsPacket, ResolveError> {
+        let resolution = Resolution {
+            roots: &self.roots,
+            limits,
+            exchange: &self.exchange,
+            minimise: self.minimise,
+            infra: &self.infra,
+        };
+        resolution.run(req)
+    }
+}
+
+/// Why none of the servers we asked gave a usable answer
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Unanswered {
+    /// At least one of them answered, with an error like REFUSED or NOTIMP
+    Rejected,
+    /// None of them answered in time
+    Silent,
+}
+
+impl Unanswered {
+    /// A rejection from anyone counts, the others may simply be down
+    fn or(self, other: Unanswered) -> Unanswered {
+        if self == Unanswered::Rejected {
+            self
+        } else {
+            other
+        }
+    }
+}
+
+/// Everything that stays the same while resolving one client query, including
+/// the lookups of nameserver addresses it takes
+struct Resolution<'a, Q> {
+    roots: &'a [NameServer],
+    limits: Limits,
+    exchange: Q,
+    minimise: bool,
+    infra: &'a InfraCache,
+}
+
+impl<'a, Q> Resolution<'a, Q>
+where
+    Q: Fn(&str, DnsPacket, Duration, &Cancel) -> DnsPacket + Sync,
+{
+    fn run(&self, req: &DnsPacket) -> Result<DnsPacket, ResolveError> {
+        if req.queries.is_empty() {
+            return Ok(finish(req, DnsPacket::new_error(ResponseCode::FormatError)));
+        }
+        match self.iterate(req, 0) {
+            Some(res) => Ok(finish(req, res)),
+            None if Instant::now() >= self.limits.deadline => Err(ResolveError::Timeout),
+            None => Err(ResolveError::Failed(format!(
+                "No nameserver answered for {}",
+                req.queries[0].name
+            ))),
+        }
+    }
+
+    /// Follows the delegation chain for the first question of `req` and
+    /// returns the response of the last server asked, `None` if we couldn't
+    /// get that far
+    fn iterate(&self, req: &DnsPacket, depth: usize) -> Option<DnsPacket> {
+        let qname = normalize(&req.queries.first()?.name);
+        // the servers we talk to are authoritative, they don't recurse for us
+        let mut query = req.clone();
+        query.header.recursion_desired = false;
+        let mut zone = String::new();
+        let mut servers = self.roots.to_vec();
+        // how many labels below `zone` the next minimised query reveals
+        let mut revealed = 1;
+        let mut minimising = self.minimise;
+        let mut minimised_queries = 0;
+        for _ in 0..MAX_REFERRALS + MAX_MINIMISED_QUERIES {
+            if let Some(name) = minimised_name(&qname, &zone, revealed).filter(|_| minimising) {
+                minimised_queries += 1;
+                minimising = minimised_queries < MAX_MINIMISED_QUERIES;
+                let res = self.ask_zone(&servers, &zone, &minimised(&query, &name), depth);
+                match res {
+                    Ok(res) if res.header.response_code == ResponseCode::NoError => {
+                        match referral(&res, &name, &zone) {
+                            Some((child, nameservers)) => {
+                                zone = child;
+                                servers = nameservers;
+                                revealed = 1;
+                            }
+                            // `name` is no zone cut, whatever it has is
+                            // served by the same servers
+                            None => revealed += 1,
+                        }
+                    }
+                    // the name could be an empty non-terminal some servers
+                    // get wrong, or they don't like the query at all. Either
+                    // way the full name is what they'll answer
+                    Ok(_) | Err(Unanswered::Rejected) => minimising = false,
+                    // they'd be just as quiet about the full name
+                    Err(Unanswered::Silent) => return None,
+                }
+                continue;
+            }
+            let res = self.ask_zone(&servers, &zone, &query, depth).ok()?;
+            if res.header.response_code != ResponseCode::NoError
+                || !res.answers.is_empty()
+                || res.header.authoritative
+            {
+                return Some(res);
+            }
+            match referral(&res, &qname, &zone) {
+                Some((child, nameservers)) => {
+                    zone = child;
+                    servers = nameservers;
+                }
+                // neither an answer nor a way forward, the best we can do is
+                // to pass on what we got
+                None => return Some(res),
+            }
+        }
+        None
+    }
+
+    /// Asks the nameservers of `zone`, those we have addresses for first.
+    /// Only when none of them answer do we go looking for the addresses of
+    /// the others
+    fn ask_zone(
+        &self,
+        servers: &[NameServer],
+        zone: &str,
+        query: &DnsPacket,
+        depth: usize,
+    ) -> Result<DnsPacket, Unanswered> {
+        let mut unanswered = match self.ask(servers, query) {
+            Ok(res) => return Ok(res),
+            Err(unanswered) => unanswered,
+        };
+        if depth >= MAX_DEPTH {
+            return Err(unanswered);
+        }
+        // a nameserver inside the zone it serves can only be found through
+        // that zone, without glue we'd end up right back here
+        let mut glueless = servers
+            .iter()
+            .filter(|server| server.addresses.is_empty() && !in_zone(&server.name, zone))
+            .collect::<Vec<&NameServer>>();
+        glueless.shuffle(&mut rand::thread_rng());
+        for server in glueless {
+            let server = NameServer {
+                name: server.name.clone(),
+                addresses: self.lookup_addresses(&server.name, depth + 1),
+            };
+            match self.ask(&[server], query) {
+                Ok(res) => return Ok(res),
+                Err(other) => unanswered = unanswered.or(other),
+            }
+        }
+        Err(unanswered)
+    }
+
+    /// Resolves the A records of `name`, falling back to AAAA if there are
+    /// none
+    fn lookup_addresses(&self, name: &str, depth: usize) -> Vec<IpAddr> {
+        for qtype in &[ResourceType::A, ResourceType::AAAA] {
+            let mut query = DnsQuery::new();
+            query.name = name.to_owned();
+            query.qtype = qtype.clone();
+            let mut req = DnsPacket::new();
+            req.header.questions_count = 1;
+            req.queries = vec![query];
+            if let Some(res) = self.iterate(&req, depth) {
+                let addresses = addresses(&res.answers, name);
+                if !addresses.is_empty() {
+                    return addresses;
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    /// Asks the servers, fastest first, until one of them gives a usable
+    /// answer, going round all their addresses with a longer timeout each
+    /// time until we run out of attempts or time
+    fn ask(&self, servers: &[NameServer], query: &DnsPacket) -> Result<DnsPacket, Unanswered> {
+        let addresses = interleave(self.by_rtt(servers));
+        let mut timeout = self.limits.timeout;
+        let mut unanswered = Unanswered::Silent;
+        for _ in 0..self.limits.attempts {
+            match self.race(&addresses, query, timeout) {
+                Ok(res) => return Ok(res),
+                Err(other) => unanswered = unanswered.or(other),
+            }
+            if Instant::now() >= self.limits.deadline {
+                break;
+            }
+            timeout *= 2;
+        }
+        Err(unanswered)
+    }
+
+    /// Asks `addresses` one after the other, moving on to the next as soon as
+    /// the last one failed or hasn't answered within `STAGGER`. The first
+    /// usable answer wins and the queries still out are cancelled
+    fn race(
+        &self,
+        addresses: &[IpAddr],
+        query: &DnsPacket,
+        timeout: Duration,
+    ) -> Result<DnsPacket, Unanswered> {
+        let cancel = Cancel::new();
+        let (answers, answered) = mpsc::channel();
+        let mut unanswered = Unanswered::Silent;
+        let res = thread::scope(|s| {
+            let mut next = addresses.iter();
+            let mut out = 0;
+            let res = loop {
+                let remaining = self
+                    .limits
+                    .deadline
+                    .saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    break None;
+                }
+                let wait = match next.next() {
+                    Some(address) => {
+                        let (answers, cancel) = (answers.clone(), &cancel);
+                        let query = query.clone();
+                        let timeout = timeout.min(remaining);
+                        s.spawn(move || {
+                            let res = (self.exchange)(&address.to_string(), query, timeout, cancel);
+                            // whoever lost is no longer listened to
+                            let _ = answers.send(res);
+                        });
+                        out += 1;
+                        STAGGER
+                    }
+                    None if out == 0 => break None,
+                    None => remaining,
+                };
+                match answered.recv_timeout(wait.min(remaining)) {
+                    Ok(res) => {
+                        out -= 1;
+                        match res.header.response_code {
+                            ResponseCode::NoError | ResponseCode::NameError => break Some(res),
+                            // our own failures come without the question
+                            _ if !res.queries.is_empty() => unanswered = Unanswered::Rejected,
+                            _ => (),
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break None,
+                }
+            };
+            cancel.cancel();
+            res
+        });
+        res.ok_or(unanswered)
+    }
+
+    /// The addresses of `servers` in the order we ask them in. Those we know
+    /// nothing about are in random order, IPv4 first since plenty of hosts
+    /// have no route for IPv6
+    fn by_rtt(&self, servers: &[NameServer]) -> Vec<IpAddr> {
+        let mut rng = rand::thread_rng();
+        let mut servers = servers.iter().collect::<Vec<&NameServer>>();
+        servers.shuffle(&mut rng);
+        let mut addresses = servers
+            .iter()
+            .flat_map(|server| {
+                let mut addresses = server.addresses.clone();
+                addresses.sort_by_key(|address| address.is_ipv6());
+                addresses
+            })
+            .map(|address| (address, self.infra.rtt(SocketAddr::new(address, 53))))
+            .collect::<Vec<(IpAddr, Duration)>>();
+        addresses.sort_by_key(|(_, rtt)| *rtt);
+        if let Some((_, fastest)) = addresses.first().cloned() {
+            let others = (1..addresses.len())
+                .filter(|i| {
+                    let rtt = addresses[*i].1;
+                    rtt > fastest && rtt <= fastest + RTT_BAND
+                })
+                .collect::<Vec<usize>>();
+            if !others.is_empty() && rng.gen_ratio(1, EXPLORE_ODDS) {
+                let other = addresses.remove(*others.choose(&mut rng).unwrap());
+                addresses.insert(0, other);
+            }
+        }
+        addresses.into_iter().map(|(address, _)| address).collect()
+    }
+}
+
+/// `addresses` with IPv4 and IPv6 taking turns, starting with the family of
+/// the first and otherwise keeping their order
+fn interleave(addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+    let first_is_ipv6 = match addresses.first() {
+        Some(address) => address.is_ipv6(),
+        None => return addresses,
+    };
+    let (mut first, mut second): (Vec<IpAddr>, Vec<IpAddr>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_is_ipv6);
+    let mut res = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    while let Some(address) = first.pop() {
+        res.push(address);
+        res.extend(second.pop());
+    }
+    res.extend(second.into_iter().rev());
+    res
+}
+
+/// The name made of the first `revealed` labels below `zone` on the way to
+/// `qname`, `None` once that would be all of `qname`
+fn minimised_name(qname: &str, zone: &str, revealed: usize) -> Option<String> {
+    let labels = qname.split('.').collect::<Vec<&str>>();
+    let zone_labels = if zone.is_empty() {
+        0
+    } else {
+        zone.split('.').count()
+    };
+    if labels.len() <= zone_labels + revealed {
+        return None;
+    }
+    Some(labels[labels.len() - zone_labels - revealed..].join("."))
+}
+
+/// `query` asking for the A records of `name` instead, which looks like any
+/// other query to the server (RFC 9156 section 2.1)
+fn minimised(query: &DnsPacket, name: &str) -> DnsPacket {
+    let mut query = query.clone();
+    query.queries.truncate(1);
+    query.header.questions_count = 1;
+    query.queries[0].name = name.to_owned();
+    query.queries[0].qtype = ResourceType::A;
+    query
+}
+
+/// If `res` delegates `qname` to a zone below `zone`, returns that zone along
+/// with its nameservers and whatever glue we were given for them
+fn referral(res: &DnsPacket, qname: &str, zone: &str) -> Option<(String, Vec<NameServer>)> {
+    // anything that isn't closer to `qname` than where we are would send us
+    // round in circles
+    let child = res
+        .authority
+        .iter()
+        .filter(|record| record.qtype == ResourceType::NS)
+        .map(|record| normalize(&record.name))
+        .find(|owner| owner != zone && in_zone(qname, owner) && in_zone(owner, zone))?;
+    let servers = res
+        .authority
+        .iter()
+        .filter(|record| record.qtype == ResourceType::NS && normalize(&record.name) == child)
+        .filter_map(|record| deserialize_domain_from_bytes(&[], &record.rdata).ok())
+        .map(|(name, _)| {
+            let name = normalize(&name);
+            let addresses = addresses(&res.additional, &name);
+            NameServer { name, addresses }
+        })
+        .collect::<Vec<NameServer>>();
+    if servers.is_empty() {
+        return None;
+    }
+    Some((child, servers))
+}
+
+/// The addresses among `records` that belong to `name`
+fn addresses(records: &[DnsAnswer], name: &str) -> Vec<IpAddr> {
+    records
+        .iter()
+        .filter(|record| normalize(&record.name) == name)
+        .filter_map(|record| match (&record.qtype, record.rdata.len()) {
+            (ResourceType::A, 4) => Some(IpAddr::V4(Ipv4Addr::new(
+                record.rdata[0],
+                record.rdata[1],
+                record.rdata[2],
+                record.rdata[3],
+            ))),
+            (ResourceType::AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&record.rdata);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether `name` is `zone` or one of its subdomains
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// Turns the response of the last server we asked into our response to `req`
+fn finish(req: &DnsPacket, mut res: DnsPacket) -> DnsPacket {
+    res.header.tx_id = req.header.tx_id;
+    res.header.is_response = true;
+    res.header.recursion_desired = req.header.recursion_desired;
+    res.header.recursion_available = true;
+    // we're passing on someone else's data
+    res.header.authoritative = false;
+    res.queries = req.queries.clone();
+    res.header.questions_count = res.queries.len() as u16;
+    res.header.answers_count = res.answers.len() as u16;
+    res.header.authority_count = res.authority.len() as u16;
+    res.header.additional_count = res.additional.len() as u16;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::serialize_domain_to_bytes;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    fn record(name: &str, qtype: ResourceType, rdata: Vec<u8>) -> DnsAnswer {
+        let mut record = DnsAnswer::new();
+        record.name = name.to_owned();
+        record.qtype = qtype;
+        record.ttl = 3600;
+        record.data_length = rdata.len() as u16;
+        record.rdata = rdata;
+        record
+    }
+
+    fn ns(zone: &str, host: &str) -> DnsAnswer {
+        record(zone, ResourceType::NS, serialize_domain_to_bytes(host))
+    }
+
+    fn a(name: &str, address: [u8; 4]) -> DnsAnswer {
+        record(name, ResourceType::A, address.to_vec())
+    }
+
+    fn request(name: &str) -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = ResourceType::A;
+        let mut req = DnsPacket::new();
+        req.header.tx_id = 0xbeef;
+        req.header.recursion_desired = true;
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    fn resolve<Q>(
+        roots: &[NameServer],
+        req: &DnsPacket,
+        limits: Limits,
+        exchange: Q,
+    ) -> Result<DnsPacket, ResolveError>
+    where
+        Q: Fn(&str, DnsPacket, Duration, &Cancel) -> DnsPacket + Sync,
+    {
+        Iterative::new(roots.to_vec(), exchange).resolve(req, limits)
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(1),
+            attempts: 3,
+            deadline: Instant::now() + Duration::from_secs(10),
+        }
+    }
+
+    fn root() -> Vec<NameServer> {
+        vec![NameServer {
+            name: "a.root-servers.net".to_owned(),
+            addresses: vec!["10.0.0.1".parse().unwrap()],
+        }]
+    }
+
+    /// A tiny internet: the root delegates com, com delegates foo.com
+    fn network(host: &str, req: DnsPacket) -> DnsPacket {
+        assert!(!req.header.recursion_desired);
+        let qname = req.queries[0].name.clone();
+        let mut res = req.clone();
+        res.header.is_response = true;
+        res.header.tx_id = 0;
+        match host {
+            "10.0.0.1" => {
+                res.authority = vec![ns("com", "a.gtld-servers.net")];
+                res.additional = vec![
+                    // glue for some other nameserver must not be used
+                    a("b.gtld-servers.net", [10, 0, 0, 99]),
+                    a("a.gtld-servers.net", [10, 0, 0, 2]),
+                ];
+            }
+            "10.0.0.2" => {
+                res.authority = vec![ns("foo.com", "ns1.foo.com"), ns("foo.com", "ns2.foo.com")];
+                res.additional = vec![
+                    a("ns1.foo.com", [10, 0, 0, 3]),
+                    a("ns2.foo.com", [10, 0, 0, 3]),
+                ];
+            }
+            "10.0.0.3" => {
+                res.header.authoritative = true;
+                match qname.as_str() {
+                    "www.foo.com" => res.answers = vec![a("www.foo.com", [1, 2, 3, 4])],
+                    "foo.com" => (),
+                    _ => res.header.response_code = ResponseCode::NameError,
+                }
+            }
+            _ => panic!("Asked {} which nobody told us about", host),
+        }
+        res
+    }
+
+    #[test]
+    fn test_follows_referrals_to_the_answer() {
+        let asked = Mutex::new(Vec::new());
+        let res = resolve(
+            &root(),
+            &request("www.foo.com"),
+            limits(),
+            |host, req, _, _| {
+                asked.lock().unwrap().push(host.to_owned());
+                network(host, req)
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"],
+            asked.into_inner().unwrap()
+        );
+        assert_eq!(vec![a("www.foo.com", [1, 2, 3, 4])], res.answers);
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert_eq!(0xbeef, res.header.tx_id);
+        assert!(res.header.recursion_available);
+        assert!(res.header.recursion_desired);
+        assert!(!res.header.authoritative);
+        assert_eq!(1, res.header.answers_count);
+        assert_eq!(request("www.foo.com").queries, res.queries);
+    }
+
+    #[test]
+    fn test_stops_at_nxdomain_and_nodata() {
+        let res = resolve(
+            &root(),
+            &request("nope.foo.com"),
+            limits(),
+            |host, req, _, _| network(host, req),
+        )
+        .unwrap();
+        assert_eq!(ResponseCode::NameError, res.header.response_code);
+
+        let res = resolve(&root(), &request("foo.com"), limits(), |host, req, _, _| {
+            network(host, req)
+        })
+        .unwrap();
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert!(res.answers.is_empty());
+    }
+
+    #[test]
+    fn test_picks_among_servers_and_skips_broken_ones() {
+        let roots = vec![
+            NameServer {
+                name: "a.root-servers.net".to_owned(),
+                addresses: vec!["10.0.0.1".parse().unwrap()],
+            },
+            NameServer {
+                name: "b.root-servers.net".to_owned(),
+                addresses: vec!["2001:db8::1".parse().unwrap(), "10.0.0.66".parse().unwrap()],
+            },
+        ];
+        let mut first_asked = HashSet::new();
+        for _ in 0..32 {
+            let asked = Mutex::new(Vec::new());
+            let res = resolve(
+                &roots,
+                &request("www.foo.com"),
+                limits(),
+                |host, req, _, _| {
+                    asked.lock().unwrap().push(host.to_owned());
+                    match host {
+                        "10.0.0.66" | "2001:db8::1" => {
+                            DnsPacket::new_error(ResponseCode::ServerError)
+                        }
+                        _ => network(host, req),
+                    }
+                },
+            )
+            .unwrap();
+            assert_eq!(1, res.answers.len());
+            first_asked.insert(asked.into_inner().unwrap().remove(0));
+        }
+        // both roots get a turn and their IPv4 addresses are tried first
+        let expected = ["10.0.0.1", "10.0.0.66"]
+            .iter()
+            .map(|host| host.to_string())
+            .collect::<HashSet<String>>();
+        assert_eq!(expected, first_asked);
+    }
+
+    /// Who gets asked first out of `roots` in `runs` resolutions
+    fn first_asked(roots: &[NameServer], infra: &Arc<InfraCache>, runs: usize) -> Vec<String> {
+        (0..runs)
+            .map(|_| {
+                let asked = Mutex::new(Vec::new());
+                let resolver = Iterative::new(roots.to_vec(), |host: &str, req, _, _| {
+                    asked.lock().unwrap().push(host.to_owned());
+                    network("10.0.0.1", req)
+                })
+                .with_infra(infra.clone());
+                resolver.resolve(&request("www.foo.com"), limits()).unwrap();
+                asked.into_inner().unwrap().remove(0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fastest_servers_are_asked_first() {
+        let roots = ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+            .iter()
+            .map(|address| NameServer {
+                name: address.to_string(),
+                addresses: vec![address.parse().unwrap()],
+            })
+            .collect::<Vec<NameServer>>();
+        let infra = Arc::new(InfraCache::new());
+        let rtt = |address: &str, ms| {
+            infra.answered(
+                SocketAddr::new(address.parse().unwrap(), 53),
+                Duration::from_millis(ms),
+            )
+        };
+        rtt("10.0.0.1", 100);
+        rtt("10.0.0.2", 20);
+        infra.timed_out("10.0.0.3:53".parse().unwrap(), Duration::from_secs(1));
+        let asked = first_asked(&roots, &infra, 200);
+        let count = |address: &str| asked.iter().filter(|asked| *asked == address).count();
+        // the slower one gets the odd turn, the one that timed out is held back
+        assert!(count("10.0.0.2") > 150);
+        assert!(count("10.0.0.1") > 0);
+        assert_eq!(0, count("10.0.0.3"));
+
+        // until it turns out to be the fastest
+        rtt("10.0.0.1", 900);
+        rtt("10.0.0.2", 900);
+        for _ in 0..10 {
+            rtt("10.0.0.3", 5);
+        }
+        let asked = first_asked(&roots, &infra, 40);
+        assert!(asked.iter().filter(|asked| *asked == "10.0.0.3").count() > 20);
+    }
+
+    #[test]
+    fn test_next_server_is_asked_before_a_slow_one_times_out() {
+        let roots = vec![NameServer {
+            name: "a.root-servers.net".to_owned(),
+            addresses: vec!["10.0.0.9".parse().unwrap(), "10.0.0.1".parse().unwrap()],
+        }];
+        // the dead one looks fastest so it always goes first
+        let infra = Arc::new(InfraCache::new());
+        infra.answered("10.0.0.9:53".parse().unwrap(), Duration::from_millis(10));
+        infra.answered("10.0.0.1:53".parse().unwrap(), Duration::from_millis(500));
+        let limits = Limits {
+            timeout: Duration::from_secs(5),
+            ..limits()
+        };
+        let cancelled = Mutex::new(false);
+        let start = Instant::now();
+        let resolver = Iterative::new(roots, |host: &str, req, timeout, cancel: &Cancel| {
+            if host != "10.0.0.9" {
+                return network(host, req);
+            }
+            while !cancel.is_cancelled() && start.elapsed() < timeout {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            *cancelled.lock().unwrap() = cancel.is_cancelled();
+            DnsPacket::new_error(ResponseCode::ServerError)
+        })
+        .with_infra(infra);
+        let res = resolver.resolve(&request("www.foo.com"), limits).unwrap();
+        assert_eq!(vec![a("www.foo.com", [1, 2, 3, 4])], res.answers);
+        assert!(start.elapsed() >= STAGGER);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(cancelled.into_inner().unwrap());
+    }
+
+    #[test]
+    fn test_interleave_address_families() {
+        let addresses = |addresses: &[&str]| {
+            addresses
+                .iter()
+                .map(|address| address.parse().unwrap())
+                .collect::<Vec<IpAddr>>()
+        };
+        assert_eq!(
+            addresses(&["10.0.0.1", "::1", "10.0.0.2", "::2", "10.0.0.3"]),
+            interleave(addresses(&[
+                "10.0.0.1", "10.0.0.2", "::1", "10.0.0.3", "::2"
+            ]))
+        );
+        assert_eq!(
+            addresses(&["::1", "10.0.0.1", "::2", "::3"]),
+            interleave(addresses(&["::1", "::2", "::3", "10.0.0.1"]))
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_referrals_must_get_closer() {
+        let asked = Mutex::new(0);
+        let res = resolve(
+            &root(),
+            &request("www.foo.com"),
+            limits(),
+            |host, req, _, _| {
+                *asked.lock().unwrap() += 1;
+                let mut res = network(host, req);
+                if host == "10.0.0.2" {
+                    // points back up at the root
+                    res.authority = vec![ns("", "a.root-servers.net")];
+                    res.additional = vec![a("a.root-servers.net", [10, 0, 0, 1])];
+                }
+                res
+            },
+        )
+        .unwrap();
+        assert_eq!(2, asked.into_inner().unwrap());
+        assert!(res.answers.is_empty());
+    }
+
+    #[test]
+    fn test_gives_up_without_usable_servers() {
+        let res = resolve(&root(), &request("www.foo.com"), limits(), |_, _, _, _| {
+            DnsPacket::new_error(ResponseCode::Refused)
+        });
+        assert_eq!(
+            Err(ResolveError::Failed(
+                "No nameserver answered for www.foo.com".to_owned()
+            )),
+            res
+        );
+    }
+
+    /// `network` where everything below foo.com is an empty non-terminal,
+    /// except the AAAA records of the `full` name and whatever `broken` says
+    fn deep_network(host: &str, req: DnsPacket, full: &str, broken: &str) -> DnsPacket {
+        let query = req.queries[0].clone();
+        let mut res = network(host, req);
+        if host == "10.0.0.3" && query.name != "www.foo.com" {
+            res.header.response_code = ResponseCode::NoError;
+            if query.name == broken {
+                res.header.response_code = ResponseCode::NameError;
+            }
+            if query.name == full && query.qtype == ResourceType::AAAA {
+                res.answers = vec![record(full, ResourceType::AAAA, vec![1; 16])];
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_minimised_queries_reveal_a_label_at_a_time() {
+        let asked = Mutex::new(Vec::new());
+        let resolver = Iterative::new(root(), |host: &str, req: DnsPacket, _, _| {
+            let query = &req.queries[0];
+            asked
+                .lock()
+                .unwrap()
+                .push((host.to_owned(), query.name.clone(), query.qtype.clone()));
+            // a.b.foo.com is there but this server thinks it isn't
+            deep_network(host, req, "www.a.b.foo.com", "a.b.foo.com")
+        })
+        .with_qname_minimisation(true);
+        let mut req = request("www.a.b.foo.com");
+        req.queries[0].qtype = ResourceType::AAAA;
+        let res = resolver.resolve(&req, limits()).unwrap();
+        assert_eq!(
+            vec![record("www.a.b.foo.com", ResourceType::AAAA, vec![1; 16])],
+            res.answers
+        );
+        assert_eq!(req.queries, res.queries);
+        let expected = vec![
+            ("10.0.0.1", "com", ResourceType::A),
+            ("10.0.0.2", "foo.com", ResourceType::A),
+            ("10.0.0.3", "b.foo.com", ResourceType::A),
+            ("10.0.0.3", "a.b.foo.com", ResourceType::A),
+            ("10.0.0.3", "www.a.b.foo.com", ResourceType::AAAA),
+        ]
+        .into_iter()
+        .map(|(host, name, qtype)| (host.to_owned(), name.to_owned(), qtype))
+        .collect::<Vec<(String, String, ResourceType)>>();
+        assert_eq!(expected, asked.into_inner().unwrap());
+    }
+
+    #[test]
+    fn test_minimised_queries_fall_back_only_when_rejected() {
+        let full = "www.a.b.foo.com";
+        for (rcode, falls_back) in &[
+            (ResponseCode::Refused, true),
+            (ResponseCode::NotImplemented, true),
+            // nobody answers, asking for the full name won't change that
+            (ResponseCode::ServerError, false),
+        ] {
+            let asked = Mutex::new(Vec::new());
+            let resolver = Iterative::new(root(), |host: &str, req: DnsPacket, _, _| {
+                let name = req.queries[0].name.clone();
+                asked.lock().unwrap().push(name.clone());
+                if host == "10.0.0.3" && name != full {
+                    return match rcode {
+                        ResponseCode::ServerError => DnsPacket::new_error(rcode.clone()),
+                        _ => {
+                            let mut res = req.clone();
+                            res.header.is_response = true;
+                            res.header.response_code = rcode.clone();
+                            res
+                        }
+                    };
+                }
+                deep_network(host, req, full, "")
+            })
+            .with_qname_minimisation(true);
+            let mut req = request(full);
+            req.queries[0].qtype = ResourceType::AAAA;
+            let res = resolver.resolve(&req, limits());
+            let asked = asked.into_inner().unwrap();
+            assert_eq!(*falls_back, res.is_ok(), "{:?}", rcode);
+            assert_eq!(*falls_back, asked.contains(&full.to_owned()), "{:?}", rcode);
+        }
+    }
+
+    #[test]
+    fn test_long_names_are_only_minimised_so_far() {
+        let name = "a.b.c.d.e.f.g.h.i.j.k.l.foo.com";
+        let asked = Mutex::new(Vec::new());
+        let resolver = Iterative::new(root(), |host: &str, req: DnsPacket, _, _| {
+            asked.lock().unwrap().push(req.queries[0].name.clone());
+            deep_network(host, req, name, "")
+        })
+        .with_qname_minimisation(true);
+        let mut req = request(name);
+        req.queries[0].qtype = ResourceType::AAAA;
+        let res = resolver.resolve(&req, limits()).unwrap();
+        assert_eq!(1, res.answers.len());
+        let asked = asked.into_inner().unwrap();
+        assert_eq!(MAX_MINIMISED_QUERIES + 1, asked.len());
+        assert_eq!("e.f.g.h.i.j.k.l.foo.com", asked[MAX_MINIMISED_QUERIES - 1]);
+        assert_eq!(name, asked[MAX_MINIMISED_QUERIES]);
+    }
+
+    /// foo.com is served by ns.bar.net and nobody hands out glue for it, the
+    /// aN.net zones are each served from the next one down, forever
+    fn glueless_network(host: &str, req: DnsPacket) -> DnsPacket {
+        let qname = req.queries[0].name.clone();
+        let mut res = req.clone();
+        res.header.is_response = true;
+        match host {
+            "10.0.0.1" => {
+                let tld = qname.rsplit('.').next().unwrap().to_owned();
+                res.authority = vec![ns(&tld, "a.gtld-servers.net")];
+                res.additional = vec![a("a.gtld-servers.net", [10, 0, 0, 2])];
+            }
+            "10.0.0.2" => {
+                let labels = qname.split('.').collect::<Vec<&str>>();
+                let zone = labels[labels.len() - 2..].join(".");
+                match zone.as_str() {
+                    "foo.com" => res.authority = vec![ns("foo.com", "ns.bar.net")],
+                    "bar.net" => {
+                        res.authority = vec![ns("bar.net", "ns1.bar.net")];
+                        res.additional = vec![a("ns1.bar.net", [10, 0, 0, 4])];
+                    }
+                    "baz.com" => res.authority = vec![ns("baz.com", "ns.baz.com")],
+                    _ => {
+                        let next = zone[1..zone.len() - 4].parse::<usize>().unwrap() + 1;
+                        res.authority = vec![ns(&zone, &format!("ns.a{}.net", next))];
+                    }
+                }
+            }
+            "10.0.0.4" => {
+                res.header.authoritative = true;
+                match (qname.as_str(), &req.queries[0].qtype) {
+                    ("ns.bar.net", ResourceType::A) => {
+                        res.answers = vec![a("ns.bar.net", [10, 0, 0, 3])]
+                    }
+                    ("ns.bar.net", _) => (),
+                    _ => res.header.response_code = ResponseCode::NameError,
+                }
+            }
+            _ => return network(host, req),
+        }
+        res
+    }
+
+    #[test]
+    fn test_resolves_nameservers_without_glue() {
+        let asked = Mutex::new(Vec::new());
+        let res = resolve(
+            &root(),
+            &request("www.foo.com"),
+            limits(),
+            |host, req, _, _| {
+                asked
+                    .lock()
+                    .unwrap()
+                    .push((host.to_owned(), req.queries[0].name.clone()));
+                glueless_network(host, req)
+            },
+        )
+        .unwrap();
+        assert_eq!(vec![a("www.foo.com", [1, 2, 3, 4])], res.answers);
+        let expected = vec![
+            ("10.0.0.1", "www.foo.com"),
+            ("10.0.0.2", "www.foo.com"),
+            ("10.0.0.1", "ns.bar.net"),
+            ("10.0.0.2", "ns.bar.net"),
+            ("10.0.0.4", "ns.bar.net"),
+            ("10.0.0.3", "www.foo.com"),
+        ]
+        .into_iter()
+        .map(|(host, name)| (host.to_owned(), name.to_owned()))
+        .collect::<Vec<(String, String)>>();
+        assert_eq!(expected, asked.into_inner().unwrap());
+    }
+
+    #[test]
+    fn test_nameservers_without_glue_inside_their_zone_are_skipped() {
+        let asked = Mutex::new(0);
+        let res = resolve(
+            &root(),
+            &request("www.baz.com"),
+            limits(),
+            |host, req, _, _| {
+                *asked.lock().unwrap() += 1;
+                glueless_network(host, req)
+            },
+        );
+        assert_eq!(2, asked.into_inner().unwrap());
+        res.unwrap_err();
+    }
+
+    #[test]
+    fn test_glueless_lookups_are_depth_limited() {
+        let asked = Mutex::new(0);
+        let res = resolve(
+            &root(),
+            &request("www.a1.net"),
+            limits(),
+            |host, req, _, _| {
+                *asked.lock().unwrap() += 1;
+                glueless_network(host, req)
+            },
+        );
+        res.unwrap_err();
+        // the chain never ends, it's the depth limit that stops us
+        assert!(asked.into_inner().unwrap() <= 2 * (1 + 2 + 4 + 8 + 16));
+    }
+
+    #[test]
+    fn test_retries_with_backoff_across_addresses() {
+        let roots = vec![NameServer {
+            name: "a.root-servers.net".to_owned(),
+            addresses: vec!["10.0.0.9".parse().unwrap(), "10.0.0.1".parse().unwrap()],
+        }];
+        let asked = Mutex::new(Vec::new());
+        let res = resolve(
+            &roots,
+            &request("www.foo.com"),
+            limits(),
+            |host, req, timeout, _| {
+                asked
+                    .lock()
+                    .unwrap()
+                    .push((host.to_owned(), timeout.as_secs()));
+                let tries = asked
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(asked, _)| asked == host)
+                    .count();
+                match host {
+                    // 10.0.0.9 is down and 10.0.0.1 only answers the second time
+                    "10.0.0.9" => DnsPacket::new_error(ResponseCode::ServerError),
+                    "10.0.0.1" if tries == 1 => DnsPacket::new_error(ResponseCode::ServerError),
+                    _ => network(host, req),
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(1, res.answers.len());
+        let expected = vec![
+            ("10.0.0.9", 1),
+            ("10.0.0.1", 1),
+            ("10.0.0.9", 2),
+            ("10.0.0.1", 2),
+            ("10.0.0.2", 1),
+            ("10.0.0.3", 1),
+        ]
+        .into_iter()
+        .map(|(host, timeout)| (host.to_owned(), timeout))
+        .collect::<Vec<(String, u64)>>();
+        assert_eq!(expected, asked.into_inner().unwrap());
+    }
+
+    #[test]
+    fn test_times_out_after_the_deadline() {
+        let limits = Limits {
+            timeout: Duration::from_millis(20),
+            attempts: 1000,
+            deadline: Instant::now() + Duration::from_millis(100),
+        };
+        let start = Instant::now();
+        let resolver = Iterative::new(root(), |_, _, timeout, _| {
+            assert!(limits.deadline >= Instant::now() + timeout - Duration::from_millis(5));
+            // nobody answers
+            std::thread::sleep(timeout);
+            DnsPacket::new_error(ResponseCode::ServerError)
+        });
+        let res = resolver.resolve(&request("www.foo.com"), limits);
+        assert_eq!(Err(ResolveError::Timeout), res);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
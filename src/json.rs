@@ -0,0 +1,268 @@
+//! A JSON flavoured resolve endpoint for scripts that would rather not speak
+//! wire format, e.g. `GET /resolve?name=foo.customtld&type=AAAA`. The
+//! response follows the schema popularised by Google and Cloudflare
+use std::convert::TryInto;
+
+use serde_json::{json, Value};
+
+use crate::answer::DnsAnswer;
+use crate::client::DnsClient;
+use crate::doh;
+use crate::header::ResourceType;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::packet::DnsPacket;
+use crate::query::DnsQuery;
+use crate::resolvers::Resolver;
+
+pub const DNS_JSON: &str = "application/dns-json";
+
+pub fn handle<R>(client: &DnsClient<R>, req: &HttpRequest) -> HttpResponse
+where
+    R: Resolver,
+{
+    if req.method != "GET" {
+        let mut res = HttpResponse::new(405);
+        res.set_header("Allow", "GET");
+        return res;
+    }
+    let query = match query_from_params(req) {
+        Ok(query) => query,
+        Err(e) => return error(&e),
+    };
+    let mut packet = DnsPacket::new();
+    packet.header.recursion_desired = true;
+    packet.header.questions_count = 1;
+    packet.queries = vec![query];
+    let packet = doh::answer(client, packet);
+    let mut res = HttpResponse::with_body(200, DNS_JSON, to_json(&packet).to_string().into_bytes());
+    res.set_header(
+        "Cache-Control",
+        &format!("max-age={}", doh::max_age(&packet)),
+    );
+    res
+}
+
+fn query_from_params(req: &HttpRequest) -> Result<DnsQuery, String> {
+    let name = match req.params.get("name") {
+        Some(name) if !name.is_empty() => name.trim_end_matches('.'),
+        _ => return Err("Missing the 'name' parameter".to_owned()),
+    };
+    // the type can either be given by its mnemonic or its number
+    let qtype = req.params.get("type").map(|t| t.as_str()).unwrap_or("A");
+    let qtype: Result<ResourceType, _> = match qtype.parse::<u16>() {
+        Ok(n) => n.try_into(),
+        Err(_) => qtype.to_ascii_uppercase().as_str().try_into(),
+    };
+    let mut query = DnsQuery::new();
+    query.name = name.to_owned();
+    query.qtype = match qtype {
+        Ok(ResourceType::Unused) | Err(_) => return Err("Unsupported record type".to_owned()),
+        Ok(qtype) => qtype,
+    };
+    Ok(query)
+}
+
+fn error(message: &str) -> HttpResponse {
+    HttpResponse::with_body(
+        400,
+        DNS_JSON,
+        json!({ "error": message }).to_string().into_bytes(),
+    )
+}
+
+pub fn to_json(packet: &DnsPacket) -> Value {
+    let mut res = json!({
+        "Status": packet.header.response_code.as_u8(),
+        "TC": packet.header.truncated,
+        "RD": packet.header.recursion_desired,
+        "RA": packet.header.recursion_available,
+        "AD": false,
+        "CD": false,
+        "Question": packet.queries.iter().map(|query| json!({
+            "name": fully_qualified(&query.name),
+            "type": query.qtype.as_u16(),
+        })).collect::<Vec<Value>>(),
+    });
+    // like the public apis, empty sections are left out rather than sent as []
+    for (key, records) in &[
+        ("Answer", &packet.answers),
+        ("Authority", &packet.authority),
+        ("Additional", &packet.additional),
+    ] {
+        if !records.is_empty() {
+            res[key] = records.iter().map(record_to_json).collect();
+        }
+    }
+    res
+}
+
+fn record_to_json(record: &DnsAnswer) -> Value {
+    json!({
+        "name": fully_qualified(&record.name),
+        "type": record.qtype.as_u16(),
+        "TTL": record.ttl,
+        "data": record.data_to_string(),
+    })
+}
+
+fn fully_qualified(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_owned()
+    } else {
+        name.to_owned() + "."
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::authorities;
+    use crate::cache::Cache;
+    use crate::header::ResponseCode;
+    use crate::resolvers::MockResolver;
+    use crate::serialization::serialize_domain_to_bytes;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    /// foo.com is an alias for bar.com
+    fn resolver() -> MockResolver {
+        let mut cname = DnsAnswer::new();
+        cname.name = "foo.com".to_owned();
+        cname.qtype = ResourceType::CName;
+        cname.ttl = 300;
+        cname.rdata = serialize_domain_to_bytes("bar.com");
+        cname.data_length = cname.rdata.len() as u16;
+        let mut answer = DnsAnswer::new();
+        answer.name = "bar.com".to_owned();
+        answer.qtype = ResourceType::A;
+        answer.ttl = 60;
+        answer.data_length = 4;
+        answer.rdata = vec![0xde, 0xca, 0xfb, 0xad];
+        MockResolver::new().with_records(vec![cname, answer])
+    }
+
+    fn get(params: &[(&str, &str)]) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.method = "GET".to_owned();
+        req.path = "/resolve".to_owned();
+        for (key, value) in params {
+            req.params.insert(key.to_string(), value.to_string());
+        }
+        req
+    }
+
+    fn body(res: &HttpResponse) -> Value {
+        serde_json::from_slice(&res.body).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_upstream() {
+        let cache = Cache::new(1);
+        let client = DnsClient::new(resolver(), &cache, HashMap::new());
+        let res = handle(&client, &get(&[("name", "foo.com."), ("type", "a")]));
+        assert_eq!(200, res.status);
+        assert_eq!(
+            json!({
+                "Status": 0,
+                "TC": false,
+                "RD": true,
+                "RA": true,
+                "AD": false,
+                "CD": false,
+                "Question": [{ "name": "foo.com.", "type": 1 }],
+                "Answer": [
+                    { "name": "foo.com.", "type": 5, "TTL": 300, "data": "bar.com." },
+                    { "name": "bar.com.", "type": 1, "TTL": 60, "data": "222.202.251.173" },
+                ],
+            }),
+            body(&res)
+        );
+        assert!(res
+            .headers
+            .contains(&("Cache-Control".to_owned(), "max-age=60".to_owned())));
+
+        // numeric types work just as well
+        let res = handle(&client, &get(&[("name", "foo.com"), ("type", "1")]));
+        assert_eq!(
+            json!([{ "name": "foo.com.", "type": 1 }]),
+            body(&res)["Question"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_authority() {
+        let temp_authorities_dir = TempDir::new("authorities").unwrap();
+        let zones = vec![temp_authorities_dir.path().to_str().unwrap().to_owned()];
+        let mut authority_file =
+            File::create(temp_authorities_dir.path().join("authority1.yml")).unwrap();
+        let input = b"
+ttl: 60
+origin: customtld
+records:
+  - type: SOA
+    class: IN
+    ttl: 60
+    name: soa
+    data:
+      domain: foo
+      fqdn: soa.customtld
+      email: foo.customtld
+      serial: 42
+      refresh: 43
+      retry: 44
+      expire: 45
+      minimum: 46
+  - type: AAAA
+    class: IN
+    ttl: 10
+    name: foo
+    data: ::1
+";
+        authority_file.write_all(input).unwrap();
+        let cache = Cache::new(1);
+        let client = DnsClient::new(resolver(), &cache, HashMap::new())
+            .with_authorities(authorities(&zones).unwrap());
+        let res = handle(
+            &client,
+            &get(&[("name", "foo.customtld"), ("type", "AAAA")]),
+        );
+        assert_eq!(
+            json!([{ "name": "foo.customtld.", "type": 28, "TTL": 10, "data": "::1" }]),
+            body(&res)["Answer"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_blocked() {
+        let cache = Cache::new(1);
+        let mut blocklist = HashMap::new();
+        blocklist.insert("foo.com".to_owned(), true);
+        let client = DnsClient::new(resolver(), &cache, blocklist);
+        let res = handle(&client, &get(&[("name", "bar.foo.com")]));
+        assert_eq!(json!(ResponseCode::Refused.as_u8()), body(&res)["Status"]);
+        assert_eq!(Value::Null, body(&res)["Answer"]);
+    }
+
+    #[test]
+    fn test_resolve_bad_requests() {
+        let cache = Cache::new(1);
+        let client = DnsClient::new(resolver(), &cache, HashMap::new());
+        let res = handle(&client, &get(&[]));
+        assert_eq!(400, res.status);
+        assert_eq!(
+            json!({ "error": "Missing the 'name' parameter" }),
+            body(&res)
+        );
+        let res = handle(&client, &get(&[("name", "foo.com"), ("type", "BOGUS")]));
+        assert_eq!(400, res.status);
+        let res = handle(&client, &get(&[("name", "foo.com"), ("type", "9999")]));
+        assert_eq!(400, res.status);
+
+        let mut post = get(&[("name", "foo.com")]);
+        post.method = "POST".to_owned();
+        assert_eq!(405, handle(&client, &post).status);
+    }
+}
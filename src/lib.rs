@@ -0,0 +1,8 @@
+//! The packet code the server is built on, along with a stub resolver for
+//! looking names up with it
+pub mod answer;
+pub mod header;
+pub mod packet;
+pub mod query;
+pub mod serialization;
+pub mod stub;
@@ -1,57 +1,288 @@
+use dns::{answer, header, packet, query, serialization};
 use serialization::{FromBytes, ToBytes};
-use std::net::UdpSocket;
-use ttl_cache::TtlCache;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-mod answer;
 mod authority;
 mod blocklist;
 mod cache;
 mod client;
-mod header;
-mod packet;
-mod query;
+mod config;
+mod dns64;
+mod doh;
+mod doq;
+mod forwarding;
+mod hints;
+mod hosts;
+mod http;
+mod inflight;
+mod infra;
+mod iterative;
+mod json;
 mod record;
 mod resolvers;
-mod serialization;
+mod signals;
+mod tls;
+mod upstream;
+
+/// The most we send back over UDP (RFC 1035), we don't read the larger sizes
+/// clients may offer with EDNS
+const MAX_UDP_SIZE: usize = 512;
+
+/// How many UDP queries we answer at the same time, one slow resolution
+/// doesn't hold up the others and identical ones get coalesced
+const UDP_WORKERS: usize = 16;
+
+/// How long a TCP connection may sit without a query before we close it
+const TCP_IDLE: Duration = Duration::from_secs(10);
 
 fn main() {
-    // calling this when the server is starting so that you know if the user
+    let config = config::Config::load(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        process::exit(1);
+    });
+    run(&config);
+}
+
+fn run(config: &config::Config) {
+    // loading these when the server is starting so that you know if the user
     // entered any invalid yaml configuration files, therefore it will fail early
     // before serving any requests
-    authority::authorities();
-
-    let sock = UdpSocket::bind("0.0.0.0:5554").expect("Could not create server");
-    let mut cache = TtlCache::<query::DnsQuery, answer::DnsAnswer>::new(1024);
-    let client = client::DnsClient::new(
-        &resolvers::default_resolver,
-        &mut cache,
-        blocklist::load_blocklist(),
-    );
-    loop {
+    let authorities = authority::authorities(&config.zones).unwrap_or_else(|e| panic!("{}", e));
+    let blocklist =
+        blocklist::load_blocklist(&config.blocklists).unwrap_or_else(|e| panic!("{}", e));
+    let hosts = hosts::load_hosts(&config.hosts).unwrap_or_else(|e| panic!("{}", e));
+    let roots = hints::root_hints(config.recursion.root_hints.as_deref())
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    let sock = UdpSocket::bind(&config.listeners.udp).expect("Could not create server");
+    // for the answers too large for UDP, on the same address and port
+    let tcp = TcpListener::bind(sock.local_addr().expect("Could not create server"))
+        .expect("Could not create TCP server");
+    let cache = cache::Cache::new(config.cache.size)
+        .with_ttl_limits(config.cache.min_ttl, config.cache.max_ttl);
+    // what we learn about other nameservers while sending them queries
+    let infra = Arc::new(infra::InfraCache::new());
+    let sender = infra.clone();
+    let send = move |host: &str, req, options| resolvers::udp_exchange(host, req, options, &sender);
+    // fails on a CA file for an upstream that can't be used
+    let log = |line: &str| println!("{}", line);
+    let resolver =
+        resolvers::from_config(config, roots, infra, send, log).unwrap_or_else(|e| panic!("{}", e));
+    let client = client::DnsClient::new(resolver, &cache, blocklist)
+        .with_authorities(authorities)
+        .with_hosts(hosts)
+        .configure(config);
+    // the encrypted transports are only served when we've been given a certificate,
+    // they all share it but each one negotiates its own application protocol
+    let tls_config = |alpn: &[u8]| {
+        let files = config.tls.as_ref().unwrap();
+        tls::server_config(&files.cert, &files.key, &[alpn]).unwrap_or_else(|e| panic!("{}", e))
+    };
+    let signals = signals::register();
+    let shutdown = AtomicBool::new(false);
+    let (client, shutdown) = (&client, &shutdown);
+    // every listener stops taking new queries once `shutdown` is set, the scope
+    // then waits for the ones still being answered
+    thread::scope(|s| {
+        s.spawn(move || signals::handle(signals, client, config, shutdown));
+        if !config.hosts.is_empty() {
+            s.spawn(move || hosts::watch(client, config, hosts::HOSTS_POLL, shutdown));
+        }
+        if config.tls.is_some() {
+            if let Some(addr) = &config.listeners.doh {
+                let tls = tls_config(b"http/1.1");
+                s.spawn(move || doh::serve(client, addr, tls, shutdown));
+            }
+            if let Some(addr) = &config.listeners.doq {
+                let tls = tls_config(doq::ALPN);
+                s.spawn(move || doq::serve(client, addr, tls, shutdown));
+            }
+        }
+        s.spawn(move || serve_tcp(&tcp, client, shutdown));
+        serve_udp(&sock, client, shutdown);
+    });
+}
+
+fn serve_udp<R>(sock: &UdpSocket, client: &client::DnsClient<R>, shutdown: &AtomicBool)
+where
+    R: resolvers::Resolver + Sync,
+{
+    sock.set_read_timeout(Some(signals::SHUTDOWN_POLL))
+        .expect("Could not set the read timeout");
+    // every worker takes the next query that comes in
+    thread::scope(|s| {
+        for _ in 0..UDP_WORKERS {
+            s.spawn(|| udp_worker(sock, client, shutdown));
+        }
+    });
+}
+
+fn udp_worker<R>(sock: &UdpSocket, client: &client::DnsClient<R>, shutdown: &AtomicBool)
+where
+    R: resolvers::Resolver,
+{
+    while !shutdown.load(Ordering::SeqCst) {
         let mut buf = [0; 1024];
-        let (nread, src) = sock.recv_from(&mut buf).unwrap();
-        match packet::DnsPacket::from_bytes(&mut buf[..nread]) {
-            Ok((packet, _)) => {
-                match client.results(packet) {
-                    Ok(packet) => {
-                        sock.send_to(&packet.to_bytes(), &src).unwrap();
+        let (nread, src) = match sock.recv_from(&mut buf) {
+            Ok(received) => received,
+            // timing out is how we get to check `shutdown`, and signals
+            // interrupt whatever we were waiting on
+            Err(ref e)
+                if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut
+                    || e.kind() == ErrorKind::Interrupted =>
+            {
+                continue
+            }
+            Err(e) => panic!("{}", e),
+        };
+        // a query that panics only costs its own answer, not the worker
+        let answered = panic::catch_unwind(AssertUnwindSafe(|| {
+            answer_udp(sock, client, &mut buf[..nread], src)
+        }));
+        if answered.is_err() {
+            eprintln!("Failed answering a query from {}", src);
+        }
+    }
+}
+
+fn answer_udp<R>(sock: &UdpSocket, client: &client::DnsClient<R>, buf: &mut [u8], src: SocketAddr)
+where
+    R: resolvers::Resolver,
+{
+    match packet::DnsPacket::from_bytes(buf) {
+        Ok((packet, _)) => {
+            match client.results(packet) {
+                Ok(packet) => {
+                    if let Err(e) = sock.send_to(&udp_response(&packet), src) {
+                        eprintln!("Could not answer {}: {}", src, e);
                     }
-                    Err(()) => (), // simply don't return any packets as the domain hit the blocklist
-                };
+                }
+                Err(()) => (), // simply don't return any packets as the domain hit the blocklist
+            };
+        }
+        Err(_) => {
+            let mut packet = packet::DnsPacket::new();
+            packet.header.response_code = header::ResponseCode::FormatError;
+            if let Err(e) = sock.send_to(&packet.to_bytes(), src) {
+                eprintln!("Could not answer {}: {}", src, e);
+            }
+        }
+    };
+}
+
+/// `res` the way it goes back over UDP. When it doesn't fit only the question
+/// goes back, with TC set so that the client asks again over TCP
+fn udp_response(res: &packet::DnsPacket) -> Vec<u8> {
+    let bytes = res.to_bytes();
+    if bytes.len() <= MAX_UDP_SIZE {
+        return bytes;
+    }
+    let mut truncated = packet::DnsPacket::new_response();
+    truncated.header = res.header.clone();
+    truncated.header.truncated = true;
+    truncated.header.answers_count = 0;
+    truncated.header.authority_count = 0;
+    truncated.header.additional_count = 0;
+    truncated.queries = res.queries.clone();
+    truncated.to_bytes()
+}
+
+fn serve_tcp<R>(listener: &TcpListener, client: &client::DnsClient<R>, shutdown: &AtomicBool)
+where
+    R: resolvers::Resolver + Sync,
+{
+    thread::scope(|s| {
+        s.spawn(|| doh::wake_on_shutdown(listener, shutdown));
+        for stream in listener.incoming() {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            s.spawn(move || {
+                // a client going away half way through a query is not our problem
+                let _ = handle_tcp(client, stream, shutdown);
+            });
+        }
+    });
+}
+
+/// Answers the queries that come in on `stream`, each with a two byte length
+/// in front (RFC 7766), until the client goes away or goes quiet
+fn handle_tcp<R>(
+    client: &client::DnsClient<R>,
+    mut stream: TcpStream,
+    shutdown: &AtomicBool,
+) -> io::Result<()>
+where
+    R: resolvers::Resolver,
+{
+    loop {
+        // waiting a little at a time, to notice when we're shutting down
+        stream.set_read_timeout(Some(signals::SHUTDOWN_POLL))?;
+        let idle = Instant::now();
+        loop {
+            if shutdown.load(Ordering::SeqCst) || idle.elapsed() >= TCP_IDLE {
+                return Ok(());
             }
+            match stream.peek(&mut [0]) {
+                Ok(0) => return Ok(()),
+                Ok(_) => break,
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock
+                        || e.kind() == ErrorKind::TimedOut
+                        || e.kind() == ErrorKind::Interrupted =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        stream.set_read_timeout(Some(TCP_IDLE))?;
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        let mut message = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut message)?;
+        let res = match packet::DnsPacket::from_bytes(&message) {
+            Ok((req, _)) => match client.results(req) {
+                Ok(res) => res,
+                // the blocklist says not to answer, so the client won't get anything
+                Err(()) => return Ok(()),
+            },
             Err(_) => {
-                let mut packet = packet::DnsPacket::new();
-                packet.header.response_code = header::ResponseCode::FormatError;
-                sock.send_to(&packet.to_bytes(), &src).unwrap();
+                let mut res = packet::DnsPacket::new();
+                res.header.response_code = header::ResponseCode::FormatError;
+                res
             }
         };
+        let mut bytes = res.to_bytes();
+        // the length in front can't say any more than that
+        if bytes.len() > u16::MAX as usize {
+            let mut failed = packet::DnsPacket::new_error(header::ResponseCode::ServerError);
+            failed.header.tx_id = res.header.tx_id;
+            bytes = failed.to_bytes();
+        }
+        let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+        framed.extend(&bytes);
+        stream.write_all(&framed)?;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
+    use std::collections::HashMap;
     use std::fs::File;
     use std::io::Write;
     use tempdir::TempDir;
@@ -61,7 +292,6 @@ mod tests {
     fn test_invalid_yaml_fails_early() {
         let temp_authorities_dir = TempDir::new("authorities").unwrap();
         let authority_file_path = temp_authorities_dir.path().join("authority1.yml");
-        env::set_var("AUTHORITY_DIR", temp_authorities_dir.path());
         let mut authority_file = File::create(authority_file_path).unwrap();
         let input = b"
 origin: foo.com
@@ -81,6 +311,255 @@ records:
       minimum: 46
 ";
         authority_file.write_all(input).unwrap();
-        main();
+        let mut config = config::Config::new();
+        config.zones = vec![temp_authorities_dir.path().to_str().unwrap().to_owned()];
+        run(&config);
+    }
+
+    #[test]
+    fn test_udp_stops_on_shutdown() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = sock.local_addr().unwrap();
+        let cache = cache::Cache::new(1);
+        let client = client::DnsClient::new(resolvers::StubResolver, &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| serve_udp(&sock, &client, &shutdown));
+            let mut query = query::DnsQuery::new();
+            query.name = "foo.com".to_owned();
+            let mut req = packet::DnsPacket::new();
+            req.header.questions_count = 1;
+            req.queries = vec![query];
+            let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client_sock.send_to(&req.to_bytes(), server_addr).unwrap();
+            let mut buf = [0; 512];
+            let nread = client_sock.recv(&mut buf).unwrap();
+            // leaving the scope joins the server, so this only passes if it stops
+            shutdown.store(true, Ordering::SeqCst);
+            let (res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            assert!(res.header.is_response);
+            assert_eq!(req.queries, res.queries);
+        });
+    }
+
+    /// Panics on `panic.com`, like a resolver with a bug in it
+    struct Panics(resolvers::MockResolver);
+
+    impl resolvers::Resolver for Panics {
+        fn resolve(
+            &self,
+            req: &packet::DnsPacket,
+            limits: resolvers::Limits,
+        ) -> Result<packet::DnsPacket, resolvers::ResolveError> {
+            assert_ne!(req.queries[0].name, "panic.com");
+            self.0.resolve(req, limits)
+        }
+    }
+
+    #[test]
+    fn test_udp_workers_survive_bad_queries() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = sock.local_addr().unwrap();
+        let cache = cache::Cache::new(0);
+        let resolver = Panics(resolvers::MockResolver::new());
+        let client = client::DnsClient::new(&resolver, &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| serve_udp(&sock, &client, &shutdown));
+            let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client_sock
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut buf = [0; 512];
+            // no question at all
+            let mut req = packet::DnsPacket::new();
+            req.header.tx_id = 1;
+            client_sock.send_to(&req.to_bytes(), server_addr).unwrap();
+            let nread = client_sock.recv(&mut buf).unwrap();
+            let (res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            assert_eq!(res.header.tx_id, 1);
+            assert_eq!(res.header.response_code, header::ResponseCode::FormatError);
+            // enough panics to take every worker down if they weren't caught
+            let mut query = query::DnsQuery::new();
+            query.name = "panic.com".to_owned();
+            req.header.questions_count = 1;
+            req.queries = vec![query];
+            for _ in 0..UDP_WORKERS {
+                client_sock.send_to(&req.to_bytes(), server_addr).unwrap();
+            }
+            req.header.tx_id = 2;
+            req.queries[0].name = "foo.com".to_owned();
+            client_sock.send_to(&req.to_bytes(), server_addr).unwrap();
+            let nread = client_sock.recv(&mut buf).unwrap();
+            shutdown.store(true, Ordering::SeqCst);
+            let (res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            assert_eq!(res.header.tx_id, 2);
+            assert_eq!(req.queries, res.queries);
+        });
+    }
+
+    /// Takes its time to answer, like a resolver that has to go and ask
+    struct Slow(resolvers::MockResolver);
+
+    impl resolvers::Resolver for Slow {
+        fn resolve(
+            &self,
+            req: &packet::DnsPacket,
+            limits: resolvers::Limits,
+        ) -> Result<packet::DnsPacket, resolvers::ResolveError> {
+            thread::sleep(Duration::from_millis(300));
+            self.0.resolve(req, limits)
+        }
+    }
+
+    #[test]
+    fn test_concurrent_udp_queries_are_coalesced() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = sock.local_addr().unwrap();
+        let mut record = answer::DnsAnswer::new();
+        record.name = "foo.com".to_owned();
+        record.qtype = header::ResourceType::A;
+        record.ttl = 60;
+        record.data_length = 4;
+        record.rdata = vec![10, 0, 0, 1];
+        let slow = Slow(resolvers::MockResolver::new().with_records(vec![record]));
+        // nothing is cached, so one after the other they'd each be resolved
+        let cache = cache::Cache::new(0);
+        let client = client::DnsClient::new(inflight::Coalesce::new(&slow), &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        let start = Instant::now();
+        thread::scope(|s| {
+            s.spawn(|| serve_udp(&sock, &client, &shutdown));
+            let asking = (0..8u16)
+                .map(|tx_id| {
+                    s.spawn(move || {
+                        let mut query = query::DnsQuery::new();
+                        query.name = "foo.com".to_owned();
+                        query.qtype = header::ResourceType::A;
+                        let mut req = packet::DnsPacket::new();
+                        req.header.tx_id = tx_id;
+                        req.header.questions_count = 1;
+                        req.queries = vec![query];
+                        let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+                        client_sock.send_to(&req.to_bytes(), server_addr).unwrap();
+                        let mut buf = [0; 512];
+                        let nread = client_sock.recv(&mut buf).unwrap();
+                        let (res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+                        (tx_id, res)
+                    })
+                })
+                .collect::<Vec<_>>();
+            for asked in asking {
+                let (tx_id, res) = asked.join().unwrap();
+                assert_eq!(tx_id, res.header.tx_id);
+                assert_eq!(1, res.answers.len());
+            }
+            shutdown.store(true, Ordering::SeqCst);
+        });
+        // answered together rather than one after the other, with one resolution
+        assert!(start.elapsed() < Duration::from_millis(8 * 300));
+        assert_eq!(vec!["foo.com"], slow.0.asked());
+    }
+
+    #[test]
+    fn test_answers_too_large_for_udp_come_over_tcp() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = sock.local_addr().unwrap();
+        let tcp = TcpListener::bind(server_addr).unwrap();
+        let records = (0..40)
+            .map(|i| {
+                let mut record = answer::DnsAnswer::new();
+                record.name = "foo.com".to_owned();
+                record.qtype = header::ResourceType::A;
+                record.ttl = 60;
+                record.data_length = 4;
+                record.rdata = vec![10, 0, 0, i];
+                record
+            })
+            .collect();
+        let resolver = resolvers::MockResolver::new().with_records(records);
+        let cache = cache::Cache::new(1);
+        let client = client::DnsClient::new(resolver, &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| serve_udp(&sock, &client, &shutdown));
+            s.spawn(|| serve_tcp(&tcp, &client, &shutdown));
+            let mut query = query::DnsQuery::new();
+            query.name = "foo.com".to_owned();
+            query.qtype = header::ResourceType::A;
+            let mut req = packet::DnsPacket::new();
+            req.header.tx_id = 0xbeef;
+            req.header.questions_count = 1;
+            req.queries = vec![query];
+            let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client_sock.send_to(&req.to_bytes(), server_addr).unwrap();
+            let mut buf = [0; 1024];
+            let nread = client_sock.recv(&mut buf).unwrap();
+            assert!(nread <= MAX_UDP_SIZE);
+            let (res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            assert!(res.header.truncated);
+            assert_eq!(0xbeef, res.header.tx_id);
+            assert_eq!(req.queries, res.queries);
+            assert!(res.answers.is_empty());
+
+            let mut stream = TcpStream::connect(server_addr).unwrap();
+            let bytes = req.to_bytes();
+            stream
+                .write_all(&(bytes.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&bytes).unwrap();
+            let mut length = [0; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut bytes = vec![0; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut bytes).unwrap();
+            shutdown.store(true, Ordering::SeqCst);
+            let (res, _) = packet::DnsPacket::from_bytes(&bytes).unwrap();
+            assert!(bytes.len() > MAX_UDP_SIZE);
+            assert!(!res.header.truncated);
+            assert_eq!(40, res.answers.len());
+        });
+    }
+
+    /// Sends `message` length-framed and reads the framed answer
+    fn tcp_exchange(stream: &mut TcpStream, message: &[u8]) -> packet::DnsPacket {
+        stream
+            .write_all(&(message.len() as u16).to_be_bytes())
+            .unwrap();
+        stream.write_all(message).unwrap();
+        let mut length = [0; 2];
+        stream.read_exact(&mut length).unwrap();
+        let mut bytes = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut bytes).unwrap();
+        packet::DnsPacket::from_bytes(&bytes).unwrap().0
+    }
+
+    #[test]
+    fn test_tcp_answers_formerr_for_a_truncated_question() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = tcp.local_addr().unwrap();
+        let cache = cache::Cache::new(0);
+        let resolver = resolvers::MockResolver::new();
+        let client = client::DnsClient::new(resolver, &cache, HashMap::new());
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| serve_tcp(&tcp, &client, &shutdown));
+            let mut req = packet::DnsPacket::new();
+            req.header.tx_id = 5;
+            req.header.questions_count = 1;
+            let mut malformed = req.to_bytes();
+            malformed.extend(&[3, b'f', b'o']);
+            let mut stream = TcpStream::connect(server_addr).unwrap();
+            let res = tcp_exchange(&mut stream, &malformed);
+            assert_eq!(5, res.header.tx_id);
+            assert_eq!(header::ResponseCode::FormatError, res.header.response_code);
+            // the same connection still gets its answers
+            let mut query = query::DnsQuery::new();
+            query.name = "foo.com".to_owned();
+            req.queries = vec![query];
+            let res = tcp_exchange(&mut stream, &req.to_bytes());
+            shutdown.store(true, Ordering::SeqCst);
+            assert_eq!(5, res.header.tx_id);
+            assert_eq!(req.queries, res.queries);
+        });
     }
 }
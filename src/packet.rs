@@ -40,10 +40,33 @@ impl DnsPacket {
     }
 }
 
+impl Default for DnsPacket {
+    fn default() -> Self {
+        DnsPacket::new()
+    }
+}
+
+/// Whether `res` is a response to the question `req` asked, the names may
+/// come back in a different case
+pub fn is_answer_to(req: &DnsPacket, res: &DnsPacket) -> bool {
+    res.header.is_response
+        && res.header.tx_id == req.header.tx_id
+        && res.queries.len() == req.queries.len()
+        && res.queries.iter().zip(&req.queries).all(|(theirs, ours)| {
+            theirs.name.eq_ignore_ascii_case(&ours.name)
+                && theirs.qtype == ours.qtype
+                && theirs.class == ours.class
+        })
+}
+
 impl FromBytes for DnsPacket {
     fn from_bytes(mut bytes: &[u8]) -> Result<(Self, usize), Self> {
         let original_bytes = bytes.clone();
-        let (header, mut total_num_read) = DnsHeader::from_bytes(&bytes[..12]).unwrap();
+        // anything shorter can't even hold a header
+        let (header, mut total_num_read) = match DnsHeader::from_bytes(bytes) {
+            Ok(header) => header,
+            Err(_) => return Err(DnsPacket::new_error(ResponseCode::FormatError)),
+        };
         // TODO check if the header says this is a request or response
         // If from response, then why are we even calling this function?
         let mut queries = Vec::with_capacity(header.questions_count as usize);
@@ -245,6 +268,44 @@ mod tests {
         assert_eq!(packet.to_bytes().to_vec(), bytes);
     }
 
+    #[test]
+    fn test_packet_too_short_for_a_header() {
+        assert_eq!(
+            ResponseCode::FormatError,
+            DnsPacket::from_bytes(&[]).unwrap_err().header.response_code
+        );
+        DnsPacket::from_bytes(&[0xbe, 0xef, 0x01, 0x00, 0x00, 0x01]).unwrap_err();
+    }
+
+    /// A query for one question followed by `question`
+    fn with_question(question: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![
+            0xbe, 0xef, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        bytes.extend(question);
+        bytes
+    }
+
+    #[test]
+    fn test_question_with_a_truncated_label() {
+        let (packet, _) = DnsPacket::from_bytes(&with_question(&[0x03, 0x66, 0x6f])).unwrap();
+        assert!(packet.queries.is_empty());
+    }
+
+    #[test]
+    fn test_question_without_type_and_class() {
+        let bytes = with_question(&[0x03, 0x66, 0x6f, 0x6f, 0x00]);
+        let (packet, _) = DnsPacket::from_bytes(&bytes).unwrap();
+        assert!(packet.queries.is_empty());
+    }
+
+    #[test]
+    fn test_question_pointing_past_the_end() {
+        let bytes = with_question(&[0xc0, 0xff, 0x00, 0x01, 0x00, 0x01]);
+        let (packet, _) = DnsPacket::from_bytes(&bytes).unwrap();
+        assert!(packet.queries.is_empty());
+    }
+
     #[test]
     fn test_packet_from_bytes_with_one_answer() {
         let bytes = [
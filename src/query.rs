@@ -35,11 +35,16 @@ impl DnsQuery {
             Ok(tup) => tup,
             Err(_) => return Err(DnsQuery::new()),
         };
-        let qtype = match NetworkEndian::read_u16(&bytes[curr_byte..curr_byte + 2]).try_into() {
+        // the type and class
+        let fields = match bytes.get(curr_byte..curr_byte + 4) {
+            Some(fields) => fields,
+            None => return Err(DnsQuery::new()),
+        };
+        let qtype = match NetworkEndian::read_u16(&fields[..2]).try_into() {
             Ok(code) => code,
             Err(code) => return Err(DnsQuery::new()),
         };
-        let class = NetworkEndian::read_u16(&bytes[curr_byte + 2..curr_byte + 4]);
+        let class = NetworkEndian::read_u16(&fields[2..]);
         // resize the slice so the caller of this function can continue
         // and not have to do any arithmetic or handle a tuple return type
         // or extra pointer variable
@@ -49,6 +54,12 @@ impl DnsQuery {
     }
 }
 
+impl Default for DnsQuery {
+    fn default() -> Self {
+        DnsQuery::new()
+    }
+}
+
 impl ToBytes for DnsQuery {
     fn to_bytes(&self) -> Vec<u8> {
         let mut res: Vec<u8> = Vec::new();
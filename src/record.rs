@@ -6,7 +6,7 @@ use yaml_rust::Yaml;
 use crate::header::ResourceType;
 use crate::serialization::{serialize_domain_to_bytes, ToBytes};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum RecordInformation {
     A(String),
     AAAA(String),
@@ -17,22 +17,23 @@ pub enum RecordInformation {
 }
 
 impl RecordInformation {
-    pub fn new_from_type_and_yaml(rec_type: ResourceType, yaml: &Yaml) -> Self {
-        match rec_type {
-            ResourceType::A => RecordInformation::A(extract_string(yaml, "").unwrap()),
-            ResourceType::AAAA => RecordInformation::AAAA(extract_string(yaml, "").unwrap()),
-            ResourceType::CName => RecordInformation::CName(extract_string(yaml, "").unwrap()),
+    pub fn new_from_type_and_yaml(rec_type: ResourceType, yaml: &Yaml) -> Result<Self, String> {
+        let data = || extract_string(yaml, "").map_err(|_| "The record data must be a string");
+        Ok(match rec_type {
+            ResourceType::A => RecordInformation::A(data()?),
+            ResourceType::AAAA => RecordInformation::AAAA(data()?),
+            ResourceType::CName => RecordInformation::CName(data()?),
             ResourceType::StartOfAuthority => {
-                RecordInformation::Soa(SoaInformation::from_yaml(&yaml))
+                RecordInformation::Soa(SoaInformation::from_yaml(&yaml)?)
             }
-            ResourceType::MX => RecordInformation::MX(MXInformation::from_yaml(&yaml)),
-            ResourceType::NS => RecordInformation::NS(extract_string(yaml, "").unwrap()),
-            _ => panic!("Unsupported resource type in record"),
-        }
+            ResourceType::MX => RecordInformation::MX(MXInformation::from_yaml(&yaml)?),
+            ResourceType::NS => RecordInformation::NS(data()?),
+            _ => return Err("Unsupported resource type in record".to_owned()),
+        })
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Record {
     pub name: String,
     pub ttl: u32,
@@ -55,23 +56,22 @@ impl Record {
         }
     }
 
-    pub fn from_yaml(yaml: &Yaml) -> Self {
-        let rec_type: ResourceType = extract_string(yaml, "type")
-            .unwrap()
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let rec_type: ResourceType = required_string(yaml, "type")?
             .as_str()
             .try_into()
-            .expect("Unsupported resource type");
-        Record {
-            name: extract_string(yaml, "name").unwrap(),
-            ttl: extract_integer(yaml, "ttl").unwrap() as u32,
+            .map_err(|_| "Unsupported resource type".to_owned())?;
+        Ok(Record {
+            name: required_string(yaml, "name")?,
+            ttl: required_integer(yaml, "ttl")? as u32,
             rec_type: rec_type.clone(),
-            rec_class: extract_string(yaml, "class").unwrap(),
-            data: RecordInformation::new_from_type_and_yaml(rec_type, &yaml["data"]),
-        }
+            rec_class: required_string(yaml, "class")?,
+            data: RecordInformation::new_from_type_and_yaml(rec_type, &yaml["data"])?,
+        })
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct SoaInformation {
     domain_name: String,
     fqdn: String,
@@ -97,17 +97,17 @@ impl SoaInformation {
         }
     }
 
-    pub fn from_yaml(yaml: &Yaml) -> Self {
-        SoaInformation {
-            domain_name: extract_string(yaml, "domain").unwrap(),
-            fqdn: extract_string(yaml, "fqdn").unwrap(),
-            email: extract_string(yaml, "email").unwrap(),
-            serial: extract_integer(yaml, "serial").unwrap() as usize,
-            refresh: extract_integer(yaml, "refresh").unwrap() as usize,
-            retry: extract_integer(yaml, "retry").unwrap() as usize,
-            expire: extract_integer(yaml, "expire").unwrap() as usize,
-            minimum: extract_integer(yaml, "minimum").unwrap() as usize,
-        }
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        Ok(SoaInformation {
+            domain_name: required_string(yaml, "domain")?,
+            fqdn: required_string(yaml, "fqdn")?,
+            email: required_string(yaml, "email")?,
+            serial: required_integer(yaml, "serial")? as usize,
+            refresh: required_integer(yaml, "refresh")? as usize,
+            retry: required_integer(yaml, "retry")? as usize,
+            expire: required_integer(yaml, "expire")? as usize,
+            minimum: required_integer(yaml, "minimum")? as usize,
+        })
     }
 }
 
@@ -131,7 +131,7 @@ impl ToBytes for SoaInformation {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct MXInformation {
     pub preference: u16,
     pub exchange: String,
@@ -145,11 +145,11 @@ impl MXInformation {
         }
     }
 
-    pub fn from_yaml(yaml: &Yaml) -> Self {
-        MXInformation {
-            preference: extract_integer(yaml, "preference").unwrap() as u16,
-            exchange: extract_string(yaml, "exchange").unwrap(),
-        }
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        Ok(MXInformation {
+            preference: required_integer(yaml, "preference")? as u16,
+            exchange: required_string(yaml, "exchange")?,
+        })
     }
 }
 
@@ -177,6 +177,14 @@ pub fn extract_string(yaml: &Yaml, key: &str) -> Result<String, ()> {
     }
 }
 
+fn required_integer(yaml: &Yaml, key: &str) -> Result<i64, String> {
+    extract_integer(yaml, key).map_err(|_| format!("'{}' must be an integer", key))
+}
+
+fn required_string(yaml: &Yaml, key: &str) -> Result<String, String> {
+    extract_string(yaml, key).map_err(|_| format!("'{}' must be a string", key))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -193,7 +201,7 @@ mod tests {
             data: 127.0.0.1
         ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        let actual_record = Record::from_yaml(&yaml[0]);
+        let actual_record = Record::from_yaml(&yaml[0]).unwrap();
         let mut expected_record = Record::new();
         expected_record.name = "localhost".to_owned();
         expected_record.ttl = 60;
@@ -216,7 +224,7 @@ mod tests {
             minimum: 46
         ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        let actual_authority_info = SoaInformation::from_yaml(&yaml[0]);
+        let actual_authority_info = SoaInformation::from_yaml(&yaml[0]).unwrap();
         let mut expected_authority_info = SoaInformation::new();
         expected_authority_info.domain_name = "foo".to_owned();
         expected_authority_info.fqdn = "soa.foo.com.".to_owned();
@@ -242,7 +250,7 @@ mod tests {
             minimum: 46
         ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        let actual_authority_info = SoaInformation::from_yaml(&yaml[0]);
+        let actual_authority_info = SoaInformation::from_yaml(&yaml[0]).unwrap();
         let mut expected_bytes = serialize_domain_to_bytes("soa.foo.com");
         expected_bytes.extend(serialize_domain_to_bytes("mail.foo.com"));
         expected_bytes.write_u32::<NetworkEndian>(42).unwrap();
@@ -261,7 +269,7 @@ mod tests {
             exchange: mail.foo.com
         ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        let actual_mx_info = MXInformation::from_yaml(&yaml[0]);
+        let actual_mx_info = MXInformation::from_yaml(&yaml[0]).unwrap();
         let mut expected_mx_info = MXInformation::new(42, "mail.foo.com".to_owned());
         assert_eq!(expected_mx_info, actual_mx_info);
     }
@@ -273,7 +281,7 @@ mod tests {
             exchange: mail.foo.com
         ";
         let yaml = YamlLoader::load_from_str(input).unwrap();
-        let actual_mx_info = MXInformation::from_yaml(&yaml[0]);
+        let actual_mx_info = MXInformation::from_yaml(&yaml[0]).unwrap();
         let mut expected_bytes = Vec::new();
         expected_bytes.write_u16::<NetworkEndian>(42).unwrap();
         expected_bytes.extend(serialize_domain_to_bytes("mail.foo.com"));
@@ -1,49 +1,1106 @@
+//! Resolvers answer the queries we aren't the authority for. Iterating from
+//! the roots and forwarding to upstreams both implement `Resolver`, and so
+//! does a `Chain` of resolvers that are asked one after the other
+#[cfg(test)]
 use crate::answer;
-use crate::header::{DnsHeader, ResourceType};
-use crate::packet;
+use crate::config::Config;
+use crate::dns64::Dns64;
+use crate::forwarding::{Forwarder, Rules};
+#[cfg(test)]
+use crate::header::ResourceType;
+use crate::header::ResponseCode;
+#[cfg(test)]
+use crate::hints::normalize;
+use crate::hints::NameServer;
+use crate::inflight::Coalesce;
+use crate::infra::InfraCache;
+use crate::iterative::Iterative;
+use crate::packet::{self, is_answer_to};
 use crate::serialization::{FromBytes, ToBytes};
-use std::net::{UdpSocket, Ipv4Addr};
-
-pub fn stub_resolver(_host: &str, req: packet::DnsPacket) -> packet::DnsPacket {
-    let mut res = packet::DnsPacket::new();
-    res.header = req.header;
-    res.header.is_response = true;
-    res.header.answers_count = 1;
-    res.header.additional_count = 0;
-    res.header.authority_count = 0;
-    let mut answer = answer::DnsAnswer::new();
-    answer.rdata = vec![0xde, 0xca, 0xfb, 0xad];
-    answer.name = req.queries[0].name.clone();
-    answer.class = 1;
-    answer.data_length = 4;
-    answer.qtype = req.queries[0].qtype.clone();
-    res.answers = vec![answer];
-    res.queries = req.queries;
+use byteorder::{ByteOrder, NetworkEndian};
+use rand::Rng;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a query waiting for its answer checks whether it's been
+/// cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(20);
+
+/// How long and how often we try before giving up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// How long the first attempt at a nameserver gets, every further round
+    /// through a zone's nameservers gets twice as long as the one before
+    pub timeout: Duration,
+    /// How many rounds through a zone's nameservers we make
+    pub attempts: u32,
+    /// When the client stops waiting for us, past it we answer SERVFAIL
+    pub deadline: Instant,
+}
+
+/// Why a query couldn't be resolved
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+    /// We ran out of time before anyone answered
+    Timeout,
+    /// There's nobody we're allowed to ask
+    Refused,
+    /// Everyone we asked failed us
+    Failed(String),
+}
+
+impl ResolveError {
+    /// What the client gets told
+    pub fn response_code(&self) -> ResponseCode {
+        match self {
+            ResolveError::Refused => ResponseCode::Refused,
+            _ => ResponseCode::ServerError,
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::Timeout => write!(f, "Timed out"),
+            ResolveError::Refused => write!(f, "Nobody to ask"),
+            ResolveError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+pub trait Resolver {
+    /// Answers `req`, which has a single question, within `limits`
+    fn resolve(
+        &self,
+        req: &packet::DnsPacket,
+        limits: Limits,
+    ) -> Result<packet::DnsPacket, ResolveError>;
+}
+
+impl<R: Resolver + ?Sized> Resolver for Box<R> {
+    fn resolve(
+        &self,
+        req: &packet::DnsPacket,
+        limits: Limits,
+    ) -> Result<packet::DnsPacket, ResolveError> {
+        (**self).resolve(req, limits)
+    }
+}
+
+impl<R: Resolver + ?Sized> Resolver for &R {
+    fn resolve(
+        &self,
+        req: &packet::DnsPacket,
+        limits: Limits,
+    ) -> Result<packet::DnsPacket, ResolveError> {
+        (**self).resolve(req, limits)
+    }
+}
+
+/// Asks its resolvers in order until one of them answers, with the error of
+/// the last one otherwise. An empty chain refuses everything
+pub struct Chain<'a> {
+    resolvers: Vec<Box<dyn Resolver + Send + Sync + 'a>>,
+}
+
+impl<'a> Chain<'a> {
+    pub fn new() -> Self {
+        Chain {
+            resolvers: Vec::new(),
+        }
+    }
+
+    /// Asks `resolver` when everything before it failed
+    pub fn then<R: Resolver + Send + Sync + 'a>(mut self, resolver: R) -> Self {
+        self.resolvers.push(Box::new(resolver));
+        self
+    }
+}
+
+impl<'a> Resolver for Chain<'a> {
+    fn resolve(
+        &self,
+        req: &packet::DnsPacket,
+        limits: Limits,
+    ) -> Result<packet::DnsPacket, ResolveError> {
+        let mut error = ResolveError::Refused;
+        for resolver in &self.resolvers {
+            match resolver.resolve(req, limits) {
+                Ok(res) => return Ok(res),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+}
+
+/// Builds the resolver `config` asks for: the forwarding rules first, then
+/// the upstreams and the roots, with DNS64 on top if it's enabled and
+/// identical queries coalesced. `send` sends a query to a single server and
+/// keeps `infra` up to date, `log` is told where the rules send queries
+pub fn from_config<'a, S, L>(
+    config: &Config,
+    roots: Vec<NameServer>,
+    infra: Arc<InfraCache>,
+    send: S,
+    log: L,
+) -> Result<Box<dyn Resolver + Send + Sync + 'a>, String>
+where
+    S: Fn(&str, packet::DnsPacket, QueryOptions) -> packet::DnsPacket + Clone + Send + Sync + 'a,
+    L: Fn(&str) + Send + Sync + 'static,
+{
+    let source_port = config.recursion.source_port;
+    let racing = move |host: &str, req, timeout, cancel: &Cancel| {
+        let options = QueryOptions {
+            source_port,
+            timeout,
+            cancel: cancel.clone(),
+        };
+        send(host, req, options)
+    };
+    // upstreams are asked one at a time, nobody cancels those
+    let exchange = {
+        let racing = racing.clone();
+        move |host: &str, req, timeout| racing(host, req, timeout, &Cancel::new())
+    };
+    let forwarding = &config.forwarding;
+    let mut chain = Chain::new();
+    if !forwarding.upstreams.is_empty() {
+        let forwarder =
+            Forwarder::new(&forwarding.upstreams, forwarding.strategy, exchange.clone())?;
+        chain = chain.then(forwarder);
+    }
+    // without upstreams there's nothing to fall back from
+    if (forwarding.upstreams.is_empty() || forwarding.fallback) && !roots.is_empty() {
+        let iterative = Iterative::new(roots, racing)
+            .with_qname_minimisation(config.recursion.qname_minimisation)
+            .with_infra(infra);
+        chain = chain.then(iterative);
+    }
+    let rules = forwarding
+        .rules
+        .iter()
+        .map(|rule| {
+            let forwarder = Forwarder::new(&rule.upstreams, forwarding.strategy, exchange.clone())?;
+            Ok((rule.suffix.clone(), forwarder))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut rules = Rules::new(rules, chain);
+    if forwarding.log_rules {
+        rules = rules.with_log(log);
+    }
+    let mut resolver: Box<dyn Resolver + Send + Sync + 'a> = Box::new(rules);
+    if config.dns64.enabled {
+        let dns64 = &config.dns64;
+        resolver = Box::new(Dns64::new(resolver, dns64.prefix, dns64.exclude.clone()));
+    }
+    Ok(Box::new(Coalesce::new(resolver)))
+}
+
+/// Answers every query with the same made up address, for when what comes
+/// back doesn't matter
+#[cfg(test)]
+pub struct StubResolver;
+
+#[cfg(test)]
+impl Resolver for StubResolver {
+    fn resolve(
+        &self,
+        req: &packet::DnsPacket,
+        _limits: Limits,
+    ) -> Result<packet::DnsPacket, ResolveError> {
+        let query = req
+            .queries
+            .first()
+            .ok_or_else(|| ResolveError::Failed("No question".to_owned()))?;
+        let mut res = packet::DnsPacket::new();
+        res.header = req.header.clone();
+        res.header.is_response = true;
+        res.header.answers_count = 1;
+        res.header.additional_count = 0;
+        res.header.authority_count = 0;
+        let mut answer = answer::DnsAnswer::new();
+        answer.rdata = vec![0xde, 0xca, 0xfb, 0xad];
+        answer.name = query.name.clone();
+        answer.class = 1;
+        answer.data_length = 4;
+        answer.qtype = query.qtype.clone();
+        res.answers = vec![answer];
+        res.queries = req.queries.clone();
+        Ok(res)
+    }
+}
+
+/// Answers from the records it was given like a recursive resolver would,
+/// names it has no records for don't exist. It remembers what it was asked
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockResolver {
+    records: Vec<answer::DnsAnswer>,
+    failures: Vec<(String, ResolveError)>,
+    asked: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl MockResolver {
+    pub fn new() -> Self {
+        MockResolver::default()
+    }
+
+    pub fn with_records(mut self, records: Vec<answer::DnsAnswer>) -> Self {
+        self.records.extend(records);
+        self
+    }
+
+    /// Queries for `name` fail with `error`
+    pub fn failing(mut self, name: &str, error: ResolveError) -> Self {
+        self.failures.push((normalize(name), error));
+        self
+    }
+
+    /// The names asked about so far
+    pub fn asked(&self) -> Vec<String> {
+        self.asked.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Resolver for MockResolver {
+    fn resolve(
+        &self,
+        req: &packet::DnsPacket,
+        _limits: Limits,
+    ) -> Result<packet::DnsPacket, ResolveError> {
+        let query = &req.queries[0];
+        let name = normalize(&query.name);
+        self.asked.lock().unwrap().push(query.name.clone());
+        if let Some((_, error)) = self.failures.iter().find(|(failing, _)| *failing == name) {
+            return Err(error.clone());
+        }
+        let owned = self
+            .records
+            .iter()
+            .filter(|record| normalize(&record.name) == name)
+            .collect::<Vec<&answer::DnsAnswer>>();
+        let mut res = req.clone();
+        res.header.is_response = true;
+        res.header.recursion_available = true;
+        if owned.is_empty() {
+            res.header.response_code = ResponseCode::NameError;
+        }
+        // the records asked for, or else the CNAME for the name
+        res.answers = owned
+            .iter()
+            .filter(|record| record.qtype == query.qtype)
+            .map(|record| (*record).clone())
+            .collect();
+        if res.answers.is_empty() {
+            res.answers = owned
+                .iter()
+                .filter(|record| record.qtype == ResourceType::CName)
+                .map(|record| (*record).clone())
+                .collect();
+        }
+        res.header.answers_count = res.answers.len() as u16;
+        Ok(res)
+    }
+}
+
+/// How a single query to another nameserver is sent
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    /// 0 picks a random port for every query
+    pub source_port: u16,
+    /// How long we wait for the answer
+    pub timeout: Duration,
+    /// Stops us waiting for it when someone else answered first
+    pub cancel: Cancel,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            source_port: 0,
+            timeout: Duration::from_secs(1),
+            cancel: Cancel::new(),
+        }
+    }
+}
+
+/// Tells queries that are still out that we don't need their answers
+/// anymore, clones share the same flag
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Cancel::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Sends `req` to the nameserver at `host`, an address with an optional port,
+/// over UDP and returns its response, asking again over TCP when it didn't
+/// fit. Anything going wrong on the way, including it not answering in time,
+/// is reported back as a server failure
+pub fn udp_exchange(
+    host: &str,
+    req: packet::DnsPacket,
+    options: QueryOptions,
+    infra: &InfraCache,
+) -> packet::DnsPacket {
+    // nameservers are on port 53, forwarders may well be somewhere else
+    let server = host
+        .parse::<SocketAddr>()
+        .or_else(|_| host.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)));
+    match server {
+        Ok(server) => exchange(server, req, options, infra),
+        Err(_) => packet::DnsPacket::new_error(ResponseCode::ServerError),
+    }
+}
+
+fn exchange(
+    server: SocketAddr,
+    req: packet::DnsPacket,
+    options: QueryOptions,
+    infra: &InfraCache,
+) -> packet::DnsPacket {
+    let start = Instant::now();
+    let original = req.clone();
+    // on top of that, mixing up the case of the names we ask for gives them a
+    // bit more to guess per letter (the 0x20 bit)
+    let randomise = infra.preserves_case(server);
+    let mut req = req;
+    if randomise {
+        for query in &mut req.queries {
+            query.name = randomise_case(&query.name);
+        }
+    }
+    let mut res = send(server, &req, &options, randomise, infra);
+    // our own failures come without the question
+    if res.queries.is_empty() && start.elapsed() >= options.timeout {
+        infra.timed_out(server, options.timeout);
+    }
+    if randomise && !res.queries.is_empty() && !same_case(&req, &res) {
+        // nothing came back with the names as we sent them. Ask again the
+        // plain way, if that gets an answer the server just doesn't keep the
+        // case and gets asked plainly from now on
+        let timeout = options.timeout.saturating_sub(start.elapsed());
+        let res = send(
+            server,
+            &original,
+            &QueryOptions { timeout, ..options },
+            false,
+            infra,
+        );
+        if !res.queries.is_empty() {
+            infra.ignores_case(server);
+        }
+        return res;
+    }
+    restore_case(&mut res, &req, &original);
     res
 }
 
-pub fn default_resolver(host: &str, req: packet::DnsPacket, listen_port: u16) -> packet::DnsPacket {
-    let socket = UdpSocket::bind(("0.0.0.0", listen_port))
-        .expect("Could not initialize listening port, is the port already taken?");
-    socket.send_to(&req.to_bytes(), (host, 53)).unwrap();
-    let mut res = [0; 1024];
-    socket.recv_from(&mut res).unwrap();
-    let res = match packet::DnsPacket::from_bytes(&mut res) {
-        // TODO PLEASE don't assume the server returns a correct response!
-        Ok((packet, _)) => packet,
-        Err(packet) => packet,
+/// Whether the questions in `res` have the names exactly as `req` asked them
+fn same_case(req: &packet::DnsPacket, res: &packet::DnsPacket) -> bool {
+    res.queries
+        .iter()
+        .zip(&req.queries)
+        .all(|(theirs, ours)| theirs.name == ours.name)
+}
+
+/// `name` with each letter in upper or lower case at random
+fn randomise_case(name: &str) -> String {
+    let mut rng = rand::thread_rng();
+    name.chars()
+        .map(|c| {
+            if rng.gen() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+/// Puts the names in `res` that we randomised the case of in `req` back the
+/// way they were in `original`
+fn restore_case(
+    res: &mut packet::DnsPacket,
+    req: &packet::DnsPacket,
+    original: &packet::DnsPacket,
+) {
+    for (sent, asked) in req.queries.iter().zip(&original.queries) {
+        for query in &mut res.queries {
+            if query.name.eq_ignore_ascii_case(&sent.name) {
+                query.name = asked.name.clone();
+            }
+        }
+        let records = res
+            .answers
+            .iter_mut()
+            .chain(res.authority.iter_mut())
+            .chain(res.additional.iter_mut());
+        for record in records {
+            if record.name.eq_ignore_ascii_case(&sent.name) {
+                record.name = asked.name.clone();
+            }
+        }
+    }
+}
+
+/// Sends `req` and waits for its answer, the response has the same id as
+/// `req` had. With `exact_case` answers with the names in another case are
+/// held back for half the timeout, in case the real one is still coming. How
+/// long the answer we went with took goes into `infra`
+fn send(
+    server: SocketAddr,
+    req: &packet::DnsPacket,
+    options: &QueryOptions,
+    exact_case: bool,
+    infra: &InfraCache,
+) -> packet::DnsPacket {
+    let mut req = req.clone();
+    let socket = match bind(server, options.source_port) {
+        Some(socket) => socket,
+        None => return packet::DnsPacket::new_error(ResponseCode::ServerError),
     };
-    for ans in &res.authority {
-        if ans.qtype == ResourceType::NS {
-            for add in &res.additional {
-                if add.qtype == ResourceType::A {
-                    let ip = Ipv4Addr::new(add.rdata[0], add.rdata[1], add.rdata[2], add.rdata[3]);
-                    println!("worked {:?}", ip);
-                    return default_resolver(&ip.to_string(), req, listen_port+1);
+    // a random id and port leave someone spoofing responses a lot of
+    // combinations to guess
+    let tx_id = req.header.tx_id;
+    req.header.tx_id = rand::random();
+    if socket.send_to(&req.to_bytes(), server).is_err() {
+        return packet::DnsPacket::new_error(ResponseCode::ServerError);
+    }
+    let start = Instant::now();
+    let deadline = start + options.timeout;
+    let mut other_case = None;
+    // whatever fits in a datagram
+    let mut buf = vec![0; 65535];
+    loop {
+        if Instant::now() >= start + options.timeout / 2 {
+            if let Some((res, rtt)) = other_case.take() {
+                infra.answered(server, rtt);
+                return res;
+            }
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0)
+            || options.cancel.is_cancelled()
+            || socket
+                .set_read_timeout(Some(remaining.min(CANCEL_POLL)))
+                .is_err()
+        {
+            return packet::DnsPacket::new_error(ResponseCode::ServerError);
+        }
+        let (nread, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // no answer yet, maybe we don't need one anymore
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(_) => return packet::DnsPacket::new_error(ResponseCode::ServerError),
+        };
+        // anything that isn't the answer to what we asked gets dropped
+        if from != server {
+            continue;
+        }
+        if let Ok((mut res, _)) = packet::DnsPacket::from_bytes(&buf[..nread]) {
+            if is_answer_to(&req, &res) {
+                res.header.tx_id = tx_id;
+                // someone who guessed the id and port still doesn't know the
+                // case we asked in, so this may not be from the server at all
+                if exact_case && !same_case(&req, &res) {
+                    other_case = Some((res, start.elapsed()));
+                    continue;
+                }
+                infra.answered(server, start.elapsed());
+                if res.header.truncated {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    let options = QueryOptions {
+                        timeout,
+                        ..options.clone()
+                    };
+                    res = tcp_exchange(server, &req, &options);
+                    res.header.tx_id = tx_id;
                 }
+                return res;
             }
         }
     }
-    //println!("{:?}", res);
-    res
+}
+
+/// Asks `server` again over TCP, where answers too large for UDP come in
+/// full. Messages are framed with a two byte length in front (RFC 7766)
+fn tcp_exchange(
+    server: SocketAddr,
+    req: &packet::DnsPacket,
+    options: &QueryOptions,
+) -> packet::DnsPacket {
+    match query_tcp(server, req, options) {
+        Ok(Some(res)) if is_answer_to(req, &res) => res,
+        _ => packet::DnsPacket::new_error(ResponseCode::ServerError),
+    }
+}
+
+fn query_tcp(
+    server: SocketAddr,
+    req: &packet::DnsPacket,
+    options: &QueryOptions,
+) -> io::Result<Option<packet::DnsPacket>> {
+    let deadline = Instant::now() + options.timeout;
+    // how long until we next look whether we've been cancelled
+    let wait = || {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(io::Error::from(ErrorKind::TimedOut));
+        }
+        if options.cancel.is_cancelled() {
+            return Err(io::Error::other("Cancelled"));
+        }
+        Ok(remaining.min(CANCEL_POLL))
+    };
+    // a connect can't be interrupted, so it gets a thread of its own that
+    // nobody waits for once we've been cancelled
+    let (connected, connection) = mpsc::channel();
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    thread::spawn(move || {
+        let _ = connected.send(TcpStream::connect_timeout(&server, timeout));
+    });
+    let mut stream = loop {
+        match connection.recv_timeout(wait()?) {
+            Ok(stream) => break stream?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Err(io::Error::other("Connect failed")),
+        }
+    };
+    stream.set_write_timeout(Some(deadline.saturating_duration_since(Instant::now())))?;
+    let bytes = req.to_bytes();
+    let mut message = (bytes.len() as u16).to_be_bytes().to_vec();
+    message.extend(&bytes);
+    stream.write_all(&message)?;
+    let mut length = [0; 2];
+    read_all(&mut stream, &mut length, wait)?;
+    let mut res = vec![0; NetworkEndian::read_u16(&length) as usize];
+    read_all(&mut stream, &mut res, wait)?;
+    Ok(packet::DnsPacket::from_bytes(&res).ok().map(|(res, _)| res))
+}
+
+/// Fills `buf` from `stream`, never blocking for longer than `wait` says
+fn read_all<W>(stream: &mut TcpStream, buf: &mut [u8], wait: W) -> io::Result<()>
+where
+    W: Fn() -> io::Result<Duration>,
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        stream.set_read_timeout(Some(wait()?))?;
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+            Ok(nread) => filled += nread,
+            Err(e)
+                if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut
+                    || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Binds the socket we query `server` from, a port that's taken just means
+/// rolling the dice again
+fn bind(server: SocketAddr, listen_port: u16) -> Option<UdpSocket> {
+    let ip: IpAddr = if server.is_ipv6() {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+    if listen_port != 0 {
+        return UdpSocket::bind((ip, listen_port)).ok();
+    }
+    (0..16)
+        .map(|_| rand::thread_rng().gen_range(1024..=u16::MAX))
+        .find_map(|port| UdpSocket::bind((ip, port)).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ForwardingRule;
+    use crate::infra::UNKNOWN_RTT;
+    use crate::query::DnsQuery;
+    use crate::upstream::Upstream;
+    use pretty_assertions::assert_eq;
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+
+    fn request(name: &str) -> packet::DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = ResourceType::A;
+        let mut req = packet::DnsPacket::new();
+        req.header.tx_id = 0xbeef;
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            timeout: Duration::from_millis(300),
+            attempts: 1,
+            deadline: Instant::now() + Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn test_chain_asks_until_someone_answers() {
+        let mut record = answer::DnsAnswer::new();
+        record.name = "foo.com".to_owned();
+        record.qtype = ResourceType::A;
+        let failing = MockResolver::new().failing("foo.com", ResolveError::Timeout);
+        let answering = MockResolver::new().with_records(vec![record.clone()]);
+        let chain = Chain::new().then(&failing).then(&answering);
+        let res = chain.resolve(&request("foo.com"), limits()).unwrap();
+        assert_eq!(vec![record], res.answers);
+        assert_eq!(vec!["foo.com"], failing.asked());
+        assert_eq!(vec!["foo.com"], answering.asked());
+
+        // an answer, even NXDOMAIN, ends the chain
+        let chain = Chain::new().then(&answering).then(&failing);
+        let res = chain.resolve(&request("bar.com"), limits()).unwrap();
+        assert_eq!(ResponseCode::NameError, res.header.response_code);
+        assert_eq!(vec!["foo.com"], failing.asked());
+
+        // when nobody answers it's the last one who gets to say why
+        let refusing = MockResolver::new().failing("foo.com", ResolveError::Refused);
+        let chain = Chain::new().then(&refusing).then(&failing);
+        assert_eq!(
+            Err(ResolveError::Timeout),
+            chain.resolve(&request("foo.com"), limits())
+        );
+        assert_eq!(
+            Err(ResolveError::Refused),
+            Chain::new().resolve(&request("foo.com"), limits())
+        );
+    }
+
+    #[test]
+    fn test_from_config_with_and_without_fallback() {
+        let mut config = Config::new();
+        config.recursion.source_port = 1234;
+        config.forwarding.upstreams = vec![Upstream::Udp("10.0.0.1:5353".parse().unwrap())];
+        let roots = vec![NameServer {
+            name: "a.root".to_owned(),
+            addresses: vec!["1.1.1.1".parse().unwrap()],
+        }];
+        let forwarder_up = Mutex::new(true);
+        let asked = Mutex::new(Vec::new());
+        let send = |host: &str, req: packet::DnsPacket, options: QueryOptions| {
+            assert_eq!(1234, options.source_port);
+            assert_eq!(Duration::from_millis(300), options.timeout);
+            asked.lock().unwrap().push(host.to_owned());
+            if host == "10.0.0.1:5353" && !*forwarder_up.lock().unwrap() {
+                return packet::DnsPacket::new_error(ResponseCode::ServerError);
+            }
+            let mut res = req.clone();
+            res.header.is_response = true;
+            res.header.authoritative = true;
+            res
+        };
+        let infra = Arc::new(InfraCache::new());
+        let resolver = from_config(&config, roots.clone(), infra, send, |_: &str| ()).unwrap();
+        resolver.resolve(&request("foo.com"), limits()).unwrap();
+        assert_eq!(vec!["10.0.0.1:5353"], asked.lock().unwrap().split_off(0));
+
+        *forwarder_up.lock().unwrap() = false;
+        resolver.resolve(&request("foo.com"), limits()).unwrap_err();
+        assert!(!asked
+            .lock()
+            .unwrap()
+            .split_off(0)
+            .contains(&"1.1.1.1".to_owned()));
+
+        config.forwarding.fallback = true;
+        let infra = Arc::new(InfraCache::new());
+        let resolver = from_config(&config, roots.clone(), infra, send, |_: &str| ()).unwrap();
+        resolver.resolve(&request("foo.com"), limits()).unwrap();
+        assert_eq!(Some(&"1.1.1.1".to_owned()), asked.lock().unwrap().last());
+
+        // no upstreams and no roots leaves nobody to ask
+        let resolver = from_config(
+            &Config::new(),
+            Vec::new(),
+            Arc::new(InfraCache::new()),
+            send,
+            |_: &str| (),
+        )
+        .unwrap();
+        assert_eq!(
+            Err(ResolveError::Refused),
+            resolver.resolve(&request("foo.com"), limits())
+        );
+    }
+
+    #[test]
+    fn test_from_config_logs_rule_matches() {
+        let mut config = Config::new();
+        config.forwarding.rules = vec![ForwardingRule {
+            suffix: "corp.internal".to_owned(),
+            upstreams: vec![Upstream::Udp("10.1.0.1:53".parse().unwrap())],
+        }];
+        let send = |_: &str, req: packet::DnsPacket, _| {
+            let mut res = req.clone();
+            res.header.is_response = true;
+            res
+        };
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        let log = move |line: &str| log.lock().unwrap().push(line.to_owned());
+        let infra = Arc::new(InfraCache::new());
+        let resolver = from_config(&config, Vec::new(), infra, send, log).unwrap();
+        resolver
+            .resolve(&request("www.corp.internal"), limits())
+            .unwrap();
+        assert_eq!(
+            vec!["www.corp.internal A goes to the upstreams for corp.internal"],
+            *logged.lock().unwrap()
+        );
+
+        config.forwarding.log_rules = false;
+        let log = |_: &str| panic!("Nothing should be logged");
+        let infra = Arc::new(InfraCache::new());
+        let resolver = from_config(&config, Vec::new(), infra, send, log).unwrap();
+        resolver
+            .resolve(&request("www.corp.internal"), limits())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_drops_responses_that_dont_match() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (nread, client) = server.recv_from(&mut buf).unwrap();
+            let (req, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            assert_ne!(0xbeef, req.header.tx_id);
+            let mut res = req.clone();
+            res.header.is_response = true;
+            res.header.authoritative = true;
+
+            // right everything, wrong sender
+            spoofer.send_to(&res.to_bytes(), client).unwrap();
+            let mut wrong_id = res.clone();
+            wrong_id.header.tx_id = req.header.tx_id.wrapping_add(1);
+            server.send_to(&wrong_id.to_bytes(), client).unwrap();
+            let mut wrong_question = res.clone();
+            wrong_question.queries = request("evil.com").queries;
+            server.send_to(&wrong_question.to_bytes(), client).unwrap();
+            server.send_to(&[0xde, 0xad], client).unwrap();
+            server.send_to(&res.to_bytes(), client).unwrap();
+        });
+        let res = exchange(
+            addr,
+            request("foo.com"),
+            QueryOptions::default(),
+            &InfraCache::new(),
+        );
+        handle.join().unwrap();
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert!(res.header.authoritative);
+        assert_eq!(0xbeef, res.header.tx_id);
+        assert_eq!(request("foo.com").queries, res.queries);
+    }
+
+    #[test]
+    fn test_truncated_responses_are_asked_again_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (nread, client) = udp.recv_from(&mut buf).unwrap();
+            let (req, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            let mut res = req.clone();
+            res.header.is_response = true;
+            res.header.truncated = true;
+            udp.send_to(&res.to_bytes(), client).unwrap();
+
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut length = [0; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut bytes = vec![0; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut bytes).unwrap();
+            let (tcp_req, _) = packet::DnsPacket::from_bytes(&bytes).unwrap();
+            assert_eq!(req, tcp_req);
+            // far more than fits in the datagram we'd have read it into
+            let mut res = req.clone();
+            res.header.is_response = true;
+            for i in 0..100 {
+                let mut record = answer::DnsAnswer::new();
+                record.name = "foo.com".to_owned();
+                record.qtype = ResourceType::A;
+                record.class = 1;
+                record.data_length = 4;
+                record.rdata = vec![10, 0, 0, i];
+                res.answers.push(record);
+            }
+            res.header.answers_count = res.answers.len() as u16;
+            let bytes = res.to_bytes();
+            assert!(bytes.len() > 1024);
+            stream
+                .write_all(&(bytes.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&bytes).unwrap();
+        });
+        let res = exchange(
+            addr,
+            request("foo.com"),
+            QueryOptions::default(),
+            &InfraCache::new(),
+        );
+        handle.join().unwrap();
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert!(!res.header.truncated);
+        assert_eq!(100, res.answers.len());
+        assert_eq!(0xbeef, res.header.tx_id);
+    }
+
+    #[test]
+    fn test_cancelled_tcp_queries_stop_waiting() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (nread, client) = udp.recv_from(&mut buf).unwrap();
+            let (mut res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            res.header.is_response = true;
+            res.header.truncated = true;
+            udp.send_to(&res.to_bytes(), client).unwrap();
+            // takes the query and never answers it
+            let (_stream, _) = tcp.accept().unwrap();
+            thread::sleep(Duration::from_secs(10));
+        });
+        let options = QueryOptions {
+            timeout: Duration::from_secs(10),
+            ..QueryOptions::default()
+        };
+        let cancel = options.cancel.clone();
+        let start = Instant::now();
+        let handle =
+            thread::spawn(move || exchange(addr, request("foo.com"), options, &InfraCache::new()));
+        thread::sleep(Duration::from_millis(200));
+        cancel.cancel();
+        let res = handle.join().unwrap();
+        assert_eq!(ResponseCode::ServerError, res.header.response_code);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_truncated_responses_without_tcp_are_server_failures() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (nread, client) = server.recv_from(&mut buf).unwrap();
+            let (mut res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            res.header.is_response = true;
+            res.header.truncated = true;
+            server.send_to(&res.to_bytes(), client).unwrap();
+        });
+        let res = exchange(
+            addr,
+            request("foo.com"),
+            QueryOptions::default(),
+            &InfraCache::new(),
+        );
+        handle.join().unwrap();
+        assert_eq!(ResponseCode::ServerError, res.header.response_code);
+    }
+
+    /// Answers the queries it gets with the question passed through `echo`,
+    /// the names it was asked for come out of the receiver
+    fn echo_server<F>(queries: usize, echo: F) -> (SocketAddr, mpsc::Receiver<String>)
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let (asked, names) = mpsc::channel();
+        thread::spawn(move || {
+            for _ in 0..queries {
+                let mut buf = [0; 1024];
+                let (nread, client) = server.recv_from(&mut buf).unwrap();
+                let (mut res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+                asked.send(res.queries[0].name.clone()).unwrap();
+                res.header.is_response = true;
+                res.queries[0].name = echo(&res.queries[0].name);
+                let mut record = answer::DnsAnswer::new();
+                record.name = res.queries[0].name.clone();
+                record.qtype = ResourceType::A;
+                record.class = 1;
+                record.data_length = 4;
+                record.rdata = vec![10, 0, 0, 1];
+                res.answers = vec![record];
+                res.header.answers_count = 1;
+                server.send_to(&res.to_bytes(), client).unwrap();
+            }
+        });
+        (addr, names)
+    }
+
+    // long enough that the random case coming out all lower case won't happen
+    const LONG_NAME: &str = "some.rather.long.name.under.example.com";
+
+    #[test]
+    fn test_query_names_get_random_case() {
+        let (addr, asked) = echo_server(2, |name| name.to_owned());
+        let infra = InfraCache::new();
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            let res = exchange(addr, request(LONG_NAME), QueryOptions::default(), &infra);
+            assert_eq!(request(LONG_NAME).queries, res.queries);
+            assert_eq!(LONG_NAME, res.answers[0].name);
+            sent.push(asked.recv().unwrap());
+        }
+        assert!(sent.iter().all(|name| name.eq_ignore_ascii_case(LONG_NAME)));
+        assert!(sent.iter().all(|name| name != LONG_NAME));
+        assert_ne!(sent[0], sent[1]);
+        assert!(infra.preserves_case(addr));
+    }
+
+    #[test]
+    fn test_servers_that_change_the_case_are_asked_plainly() {
+        let (addr, asked) = echo_server(3, |name| name.to_ascii_lowercase());
+        let infra = InfraCache::new();
+        let res = exchange(addr, request(LONG_NAME), QueryOptions::default(), &infra);
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert_eq!(request(LONG_NAME).queries, res.queries);
+        assert!(asked.recv().unwrap() != LONG_NAME);
+        assert_eq!(LONG_NAME, asked.recv().unwrap());
+        assert!(!infra.preserves_case(addr));
+
+        exchange(addr, request(LONG_NAME), QueryOptions::default(), &infra);
+        assert_eq!(LONG_NAME, asked.recv().unwrap());
+    }
+
+    #[test]
+    fn test_servers_that_change_the_case_answer_right_away() {
+        // the first query gets asked twice, randomised and plainly
+        let (addr, _asked) = echo_server(3, |name| name.to_ascii_lowercase());
+        let infra = InfraCache::new();
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            ..QueryOptions::default()
+        };
+        // the answer we went with came right away, not after holding it back
+        exchange(addr, request(LONG_NAME), options.clone(), &infra);
+        assert!(infra.rtt(addr) < Duration::from_millis(500));
+        let start = Instant::now();
+        let res = exchange(addr, request(LONG_NAME), options, &infra);
+        assert_eq!(request(LONG_NAME).queries, res.queries);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(infra.rtt(addr) < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_answers_in_another_case_dont_stop_the_random_case() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (nread, client) = server.recv_from(&mut buf).unwrap();
+            let (mut res, _) = packet::DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            res.header.is_response = true;
+            // what someone who guessed the id and port would send first
+            let mut spoofed = res.clone();
+            spoofed.queries[0].name = spoofed.queries[0].name.to_ascii_lowercase();
+            server.send_to(&spoofed.to_bytes(), client).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            server.send_to(&res.to_bytes(), client).unwrap();
+        });
+        let infra = InfraCache::new();
+        let res = exchange(addr, request(LONG_NAME), QueryOptions::default(), &infra);
+        handle.join().unwrap();
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert_eq!(request(LONG_NAME).queries, res.queries);
+        assert!(infra.preserves_case(addr));
+    }
+
+    #[test]
+    fn test_round_trip_times_are_recorded() {
+        let (addr, _asked) = echo_server(1, |name| name.to_owned());
+        let infra = InfraCache::new();
+        exchange(addr, request("foo.com"), QueryOptions::default(), &infra);
+        assert!(infra.rtt(addr) < UNKNOWN_RTT);
+
+        // bound but never answering
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap();
+        let options = QueryOptions {
+            timeout: Duration::from_millis(50),
+            ..QueryOptions::default()
+        };
+        let res = exchange(addr, request("foo.com"), options, &infra);
+        assert_eq!(ResponseCode::ServerError, res.header.response_code);
+        assert_eq!(2 * UNKNOWN_RTT, infra.rtt(addr));
+    }
+
+    #[test]
+    fn test_cancelled_queries_stop_waiting() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap();
+        let options = QueryOptions {
+            timeout: Duration::from_secs(5),
+            ..QueryOptions::default()
+        };
+        let cancel = options.cancel.clone();
+        let start = Instant::now();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let infra = InfraCache::new();
+        let res = exchange(addr, request("foo.com"), options, &infra);
+        handle.join().unwrap();
+        assert_eq!(ResponseCode::ServerError, res.header.response_code);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // giving up on it doesn't make it slow
+        assert_eq!(UNKNOWN_RTT, infra.rtt(addr));
+    }
+
+    #[test]
+    fn test_source_ports_are_random() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ports = (0..4)
+            .map(|_| {
+                bind(server.local_addr().unwrap(), 0)
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port()
+            })
+            .collect::<Vec<u16>>();
+        assert!(ports.iter().any(|port| *port != ports[0]));
+    }
 }
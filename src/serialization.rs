@@ -18,62 +18,66 @@ pub trait ToBytes {
 
 pub fn serialize_domain_to_bytes(domain: &str) -> Vec<u8> {
     let mut res = Vec::new();
-    let split: Vec<&str> = domain.split('.').collect();
-    if split.len() > 1 {
-        for word in split {
+    // the root is just the terminating zero octet, e.g. in referrals from the
+    // root servers
+    let domain = domain.trim_end_matches('.');
+    if !domain.is_empty() {
+        for word in domain.split('.') {
             res.push(word.len() as u8);
             res.append(&mut Vec::from(word.as_bytes().clone()));
         }
-        res.push(0);
     }
+    res.push(0);
     res
 }
 
+/// How many pointers we follow for one name, any more and they go round in
+/// circles
+const MAX_POINTERS: usize = 16;
+
 pub fn deserialize_domain_from_bytes(
     packet_bytes: &[u8],
     bytes: &[u8],
 ) -> Result<(String, usize), ()> {
-    let name_len = bytes[0];
+    domain_from_bytes(packet_bytes, bytes, 0)
+}
+
+fn domain_from_bytes(
+    packet_bytes: &[u8],
+    bytes: &[u8],
+    pointers: usize,
+) -> Result<(String, usize), ()> {
+    if pointers > MAX_POINTERS {
+        return Err(());
+    }
+    let name_len = *bytes.first().ok_or(())?;
+    if name_len == 0 {
+        return Ok((String::new(), 1));
+    }
     // the domain might just be the pointer
     if name_len & 0xc0 == 0xc0 {
-        return match expand_pointers(packet_bytes, bytes) {
-            Ok(name_bytes) => {
-                match deserialize_domain_from_bytes(packet_bytes, &name_bytes) {
-                    Ok((s, _)) => return Ok((s, 2)),
-                    Err(_) => return Err(()),
-                };
-            }
-            Err(_) => Err(()),
-        };
+        let name_bytes = expand_pointers(packet_bytes, bytes)?;
+        let (s, _) = domain_from_bytes(packet_bytes, &name_bytes, pointers + 1)?;
+        return Ok((s, 2));
     }
     // otherwise the domain starts with some characters
     let mut name = String::with_capacity(name_len as usize);
     let mut curr_byte = 0;
     loop {
-        let len = bytes[curr_byte];
+        let len = *bytes.get(curr_byte).ok_or(())?;
         if len & 0xc0 == 0xc0 {
-            match expand_pointers(&packet_bytes, &bytes) {
-                Ok(name_bytes) => {
-                    match deserialize_domain_from_bytes(packet_bytes, &name_bytes) {
-                        Ok((s, _)) => name.push_str(&s),
-                        Err(_) => return Err(()),
-                    };
-                }
-                Err(_) => return Err(()),
-            };
+            let name_bytes = expand_pointers(packet_bytes, &bytes[curr_byte..])?;
+            let (s, _) = domain_from_bytes(packet_bytes, &name_bytes, pointers + 1)?;
+            name.push_str(&s);
             curr_byte += 2;
-            // bounds check
-            // FIXME once we hit this point, we can assume we're done right?
-            // That is, once we reach a pointer, that pointer will go until the
-            // end?
+            // a pointer is always the end of a name
             break;
         }
         curr_byte += 1; // consume the size byte
-        for i in curr_byte..(curr_byte as u8 + len) as usize {
-            name.push(bytes[i] as char);
-        }
+        let label = bytes.get(curr_byte..curr_byte + len as usize).ok_or(())?;
+        name.extend(label.iter().map(|byte| *byte as char));
         curr_byte += len as usize;
-        if bytes[curr_byte] == 0 {
+        if *bytes.get(curr_byte).ok_or(())? == 0 {
             curr_byte += 1; // consume zero octet
             break;
         }
@@ -87,15 +91,13 @@ pub fn expand_pointers(packet_bytes: &[u8], name_bytes: &[u8]) -> Result<Vec<u8>
     let mut it = name_bytes.iter().enumerate();
     while let Some((idx, byte)) = it.next() {
         if byte & 0xc0 == 0xc0 {
-            let mut ptr = (byte & 0x3f) as u16;
-            ptr <<= 8;
-            if idx + 1 >= name_bytes.len() {
-                return Err(()); // compression flag was last byte in sequence
-            }
-            ptr += name_bytes[idx + 1] as u16;
+            // the compression flag may be the last byte in the sequence, and
+            // point past the end of the packet
+            let low = *name_bytes.get(idx + 1).ok_or(())?;
+            let mut ptr = ((*byte & 0x3f) as usize) << 8 | low as usize;
             it.next(); // skip the second byte in the compression flag
-            while packet_bytes[ptr as usize] != 0 {
-                res.push(packet_bytes[ptr as usize]);
+            while *packet_bytes.get(ptr).ok_or(())? != 0 {
+                res.push(packet_bytes[ptr]);
                 ptr += 1;
             }
             res.push(0);
@@ -152,6 +154,24 @@ mod tests {
         assert_eq!(expected_bytes.to_vec(), actual_bytes);
     }
 
+    #[test]
+    fn test_serialize_root_and_single_label_domains() {
+        assert_eq!(vec![0u8], serialize_domain_to_bytes(""));
+        assert_eq!(vec![0u8], serialize_domain_to_bytes("."));
+        assert_eq!(
+            vec![0x03u8, 0x63, 0x6f, 0x6d, 0x00],
+            serialize_domain_to_bytes("com")
+        );
+        assert_eq!(
+            serialize_domain_to_bytes("foo.com"),
+            serialize_domain_to_bytes("foo.com.")
+        );
+        assert_eq!(
+            (String::new(), 1),
+            deserialize_domain_from_bytes(&[], &[0x00, 0x00, 0x06]).unwrap()
+        );
+    }
+
     #[test]
     fn test_deserialize_domain_from_bytes() {
         let bytes = [
@@ -226,6 +246,23 @@ mod tests {
         assert_eq!(second_bytes[..9].to_vec(), res);
     }
 
+    #[test]
+    fn test_malformed_domains_are_errors() {
+        // a label cut short
+        let cut_short = [0x03, 0x66, 0x6f];
+        assert_eq!(Err(()), deserialize_domain_from_bytes(&[], &cut_short));
+        // no zero octet at the end
+        let unterminated = [0x03, 0x66, 0x6f, 0x6f];
+        assert_eq!(Err(()), deserialize_domain_from_bytes(&[], &unterminated));
+        // pointing past the end of the packet
+        let pointer = [0xc0, 0xff];
+        assert_eq!(Err(()), deserialize_domain_from_bytes(&[0x00], &pointer));
+        assert_eq!(Err(()), expand_pointers(&[0x00], &[0xc0]));
+        // pointing at itself
+        let bytes = [0x00u8, 0x00, 0xc0, 0x02];
+        assert_eq!(Err(()), deserialize_domain_from_bytes(&bytes, &bytes[2..]));
+    }
+
     #[test]
     fn test_deserialize_ipv4_from_str() {
         let mut actual = deserialize_ipv4_from_str("1.2.3.4");
@@ -0,0 +1,45 @@
+//! SIGTERM and SIGINT stop the server once the queries it is already answering
+//! are done, SIGHUP reloads the zones, blocklists and hosts files without a
+//! restart
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::client::DnsClient;
+use crate::config::Config;
+use crate::resolvers::Resolver;
+
+/// How often the listeners look up from waiting to check if they should stop
+pub const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+
+pub fn register() -> Signals {
+    Signals::new([SIGHUP, SIGINT, SIGTERM]).expect("Could not register signal handlers")
+}
+
+/// Handles signals until we're told to stop, at which point `shutdown` is set
+pub fn handle<R>(
+    mut signals: Signals,
+    client: &DnsClient<R>,
+    config: &Config,
+    shutdown: &AtomicBool,
+) where
+    R: Resolver,
+{
+    for signal in signals.forever() {
+        if signal == SIGHUP {
+            match client.reload(config) {
+                Ok(()) => println!("Reloaded the zones, blocklists and hosts files"),
+                Err(e) => eprintln!(
+                    "Could not reload, keeping the old zones, blocklists and hosts files: {}",
+                    e
+                ),
+            }
+        } else {
+            println!("Shutting down once the in-flight queries are answered");
+            shutdown.store(true, Ordering::SeqCst);
+            return;
+        }
+    }
+}
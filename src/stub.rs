@@ -0,0 +1,601 @@
+//! A stub resolver for programs that just want to look names up. It asks the
+//! nameservers from a resolv.conf file to do the resolving and hands back the
+//! records they answer with
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::answer::{character_strings, rdata_domain, DnsAnswer};
+use crate::header::{ResourceType, ResponseCode};
+use crate::packet::{is_answer_to, DnsPacket};
+use crate::query::DnsQuery;
+use crate::serialization::{FromBytes, ToBytes};
+
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// The same limits the C library puts on what it reads from resolv.conf,
+/// anything past them is ignored
+const MAX_NAMESERVERS: usize = 3;
+const MAX_SEARCH_DOMAINS: usize = 6;
+const MAX_NDOTS: usize = 15;
+const MAX_TIMEOUT: u64 = 30;
+const MAX_ATTEMPTS: usize = 5;
+
+/// What a resolv.conf file says about how to look names up
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvConf {
+    /// Asked in order until one of them answers
+    pub nameservers: Vec<SocketAddr>,
+    /// Domains tried after names that don't have `ndots` dots in them
+    pub search: Vec<String>,
+    pub ndots: usize,
+    /// How long a nameserver gets to answer
+    pub timeout: Duration,
+    /// How many rounds through the nameservers we go
+    pub attempts: usize,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        ResolvConf {
+            nameservers: vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53)],
+            search: vec![],
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+        }
+    }
+}
+
+impl ResolvConf {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Reads `nameserver`, `domain`, `search` and `options` lines. Like the C
+    /// library, whatever it doesn't understand is skipped
+    pub fn parse(contents: &str) -> Self {
+        let mut conf = ResolvConf::default();
+        let mut nameservers = Vec::new();
+        for line in contents.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    if let Some(Ok(ip)) = words.next().map(str::parse::<IpAddr>) {
+                        nameservers.push(SocketAddr::new(ip, 53));
+                    }
+                }
+                // whichever of domain and search comes last wins
+                Some("domain") => conf.search = words.take(1).map(domain).collect(),
+                Some("search") => {
+                    conf.search = words.take(MAX_SEARCH_DOMAINS).map(domain).collect()
+                }
+                Some("options") => {
+                    for option in words {
+                        let mut parts = option.splitn(2, ':');
+                        let (name, value) = (parts.next(), parts.next().map(str::parse::<u64>));
+                        match (name, value) {
+                            (Some("ndots"), Some(Ok(n))) => {
+                                conf.ndots = (n as usize).min(MAX_NDOTS)
+                            }
+                            (Some("timeout"), Some(Ok(n))) => {
+                                conf.timeout = Duration::from_secs(n.clamp(1, MAX_TIMEOUT))
+                            }
+                            (Some("attempts"), Some(Ok(n))) => {
+                                conf.attempts = (n as usize).clamp(1, MAX_ATTEMPTS)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // without any we ask a nameserver on this machine
+        if !nameservers.is_empty() {
+            nameservers.truncate(MAX_NAMESERVERS);
+            conf.nameservers = nameservers;
+        }
+        conf
+    }
+}
+
+fn domain(name: &str) -> String {
+    name.trim_end_matches('.').to_owned()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LookupError {
+    /// The name doesn't exist
+    NotFound,
+    /// The name exists but doesn't have records of the type we asked for
+    NoRecords,
+    /// None of the nameservers answered in time
+    Timeout,
+    Failed(String),
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "No such name"),
+            Self::NoRecords => write!(f, "No records of that type"),
+            Self::Timeout => write!(f, "No nameserver answered in time"),
+            Self::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mx {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+pub struct Resolver {
+    conf: ResolvConf,
+}
+
+impl Resolver {
+    pub fn new(conf: ResolvConf) -> Self {
+        Resolver { conf }
+    }
+
+    /// A resolver set up the same way as everything else on this machine
+    pub fn from_system() -> io::Result<Self> {
+        Ok(Resolver::new(ResolvConf::load(RESOLV_CONF)?))
+    }
+
+    /// The IPv4 and IPv6 addresses of `name`
+    pub fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, LookupError> {
+        let ips = self.search(name, |name| {
+            match (
+                self.query(name, ResourceType::A),
+                self.query(name, ResourceType::AAAA),
+            ) {
+                (Ok(mut v4), Ok(v6)) => {
+                    v4.extend(v6);
+                    Ok(v4)
+                }
+                (Ok(records), Err(_)) | (Err(_), Ok(records)) => Ok(records),
+                // a name that has neither still exists when one of them
+                // says so
+                (Err(LookupError::NotFound), Err(e)) | (Err(e), Err(_)) => Err(e),
+            }
+        })?;
+        Ok(ips
+            .iter()
+            .filter_map(|record| match record.rdata.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(&record.rdata[..]).ok()?)),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(&record.rdata[..]).ok()?)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// The mail exchangers for `name`, most preferred first
+    pub fn lookup_mx(&self, name: &str) -> Result<Vec<Mx>, LookupError> {
+        let mut mxs = self
+            .lookup(name, ResourceType::MX)?
+            .iter()
+            .filter_map(|record| {
+                let (exchange, _) = rdata_domain(&record.rdata, 2)?;
+                Some(Mx {
+                    preference: NetworkEndian::read_u16(&record.rdata),
+                    exchange: domain(&exchange),
+                })
+            })
+            .collect::<Vec<Mx>>();
+        mxs.sort_by_key(|mx| mx.preference);
+        Ok(mxs)
+    }
+
+    /// The text of each of `name`'s TXT records, the strings a record is made
+    /// of are put back together
+    pub fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        Ok(self
+            .lookup(name, ResourceType::TXT)?
+            .iter()
+            .filter_map(|record| {
+                let strings = character_strings(&record.rdata)?;
+                Some(String::from_utf8_lossy(&strings.concat()).into_owned())
+            })
+            .collect())
+    }
+
+    /// The servers for a service like `_sip._udp.foo.com`, by priority and
+    /// the heaviest first among those with the same one
+    pub fn lookup_srv(&self, name: &str) -> Result<Vec<Srv>, LookupError> {
+        let mut srvs = self
+            .lookup(name, ResourceType::SRV)?
+            .iter()
+            .filter_map(|record| {
+                let (target, _) = rdata_domain(&record.rdata, 6)?;
+                Some(Srv {
+                    priority: NetworkEndian::read_u16(&record.rdata),
+                    weight: NetworkEndian::read_u16(&record.rdata[2..]),
+                    port: NetworkEndian::read_u16(&record.rdata[4..]),
+                    target: domain(&target),
+                })
+            })
+            .collect::<Vec<Srv>>();
+        srvs.sort_by_key(|srv| (srv.priority, u16::MAX - srv.weight));
+        Ok(srvs)
+    }
+
+    /// The records of type `qtype` for `name`, for anything the other lookups
+    /// don't cover
+    pub fn lookup(&self, name: &str, qtype: ResourceType) -> Result<Vec<DnsAnswer>, LookupError> {
+        self.search(name, |name| self.query(name, qtype.clone()))
+    }
+
+    /// Looks up each of the names `name` could mean until one of them has
+    /// records
+    fn search<T, L>(&self, name: &str, lookup: L) -> Result<Vec<T>, LookupError>
+    where
+        L: Fn(&str) -> Result<Vec<T>, LookupError>,
+    {
+        let mut error = LookupError::NotFound;
+        for candidate in self.candidates(name) {
+            match lookup(&candidate) {
+                Err(LookupError::NotFound) => {}
+                Err(LookupError::NoRecords) => error = LookupError::NoRecords,
+                res => return res,
+            }
+        }
+        Err(error)
+    }
+
+    /// `name` with each of the search domains added to it, and on its own.
+    /// Names with enough dots in them are tried on their own first and names
+    /// ending in a dot only on their own
+    fn candidates(&self, name: &str) -> Vec<String> {
+        if name.ends_with('.') {
+            return vec![domain(name)];
+        }
+        let mut candidates = self
+            .conf
+            .search
+            .iter()
+            .map(|search| format!("{}.{}", name, search))
+            .collect::<Vec<String>>();
+        if name.matches('.').count() >= self.conf.ndots {
+            candidates.insert(0, name.to_owned());
+        } else {
+            candidates.push(name.to_owned());
+        }
+        candidates
+    }
+
+    /// Asks the nameservers for `name`, the records of type `qtype` in the
+    /// answer come back
+    fn query(&self, name: &str, qtype: ResourceType) -> Result<Vec<DnsAnswer>, LookupError> {
+        let mut query = DnsQuery::new();
+        query.name = name.to_owned();
+        query.qtype = qtype.clone();
+        let mut req = DnsPacket::new();
+        req.header.tx_id = rand::random();
+        req.header.recursion_desired = true;
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        let mut error = LookupError::Timeout;
+        for _ in 0..self.conf.attempts {
+            for &server in &self.conf.nameservers {
+                let res = match exchange(server, &req, self.conf.timeout) {
+                    Ok(res) => res,
+                    Err(e)
+                        if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                    {
+                        continue
+                    }
+                    Err(e) => {
+                        error = LookupError::Failed(format!("{}: {}", server, e));
+                        continue;
+                    }
+                };
+                match res.header.response_code {
+                    ResponseCode::NoError => {
+                        let records = res
+                            .answers
+                            .into_iter()
+                            .filter(|record| record.qtype == qtype)
+                            .collect::<Vec<DnsAnswer>>();
+                        if records.is_empty() {
+                            return Err(LookupError::NoRecords);
+                        }
+                        return Ok(records);
+                    }
+                    ResponseCode::NameError => return Err(LookupError::NotFound),
+                    // maybe the next one does better
+                    code => error = LookupError::Failed(format!("{} answered {:?}", server, code)),
+                }
+            }
+        }
+        Err(error)
+    }
+}
+
+/// Sends `req` to `server` over UDP and waits for the answer, asking again
+/// over TCP when it didn't fit
+fn exchange(server: SocketAddr, req: &DnsPacket, timeout: Duration) -> io::Result<DnsPacket> {
+    let ip: IpAddr = if server.is_ipv6() {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind((ip, 0))?;
+    // only what `server` sends gets through
+    socket.connect(server)?;
+    socket.send(&req.to_bytes())?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; 65535];
+    loop {
+        socket.set_read_timeout(Some(remaining(deadline)?))?;
+        let nread = match socket.recv(&mut buf) {
+            Ok(nread) => nread,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Ok((res, _)) = DnsPacket::from_bytes(&buf[..nread]) {
+            if is_answer_to(req, &res) {
+                if res.header.truncated {
+                    return tcp_exchange(server, req, deadline);
+                }
+                return Ok(res);
+            }
+        }
+    }
+}
+
+/// Messages over TCP have a two byte length in front (RFC 7766)
+fn tcp_exchange(server: SocketAddr, req: &DnsPacket, deadline: Instant) -> io::Result<DnsPacket> {
+    let mut stream = TcpStream::connect_timeout(&server, remaining(deadline)?)?;
+    stream.set_write_timeout(Some(remaining(deadline)?))?;
+    let bytes = req.to_bytes();
+    let mut message = (bytes.len() as u16).to_be_bytes().to_vec();
+    message.extend(&bytes);
+    stream.write_all(&message)?;
+    stream.set_read_timeout(Some(remaining(deadline)?))?;
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+    let mut res = vec![0; NetworkEndian::read_u16(&length) as usize];
+    stream.set_read_timeout(Some(remaining(deadline)?))?;
+    stream.read_exact(&mut res)?;
+    let not_an_answer = || io::Error::new(ErrorKind::InvalidData, "Not an answer to our query");
+    match DnsPacket::from_bytes(&res) {
+        Ok((res, _)) if is_answer_to(req, &res) => Ok(res),
+        _ => Err(not_an_answer()),
+    }
+}
+
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining == Duration::from_secs(0) {
+        return Err(io::Error::from(ErrorKind::TimedOut));
+    }
+    Ok(remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::serialize_domain_to_bytes;
+    use pretty_assertions::assert_eq;
+    use std::thread;
+
+    fn record(name: &str, qtype: ResourceType, rdata: Vec<u8>) -> DnsAnswer {
+        let mut record = DnsAnswer::new();
+        record.name = name.to_owned();
+        record.qtype = qtype;
+        record.ttl = 60;
+        record.data_length = rdata.len() as u16;
+        record.rdata = rdata;
+        record
+    }
+
+    /// Answers from `records` like a recursive resolver would, unless it's
+    /// told to answer everything with `code`
+    fn nameserver(records: Vec<DnsAnswer>, code: ResponseCode) -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || loop {
+            let mut buf = [0; 1024];
+            let (nread, client) = server.recv_from(&mut buf).unwrap();
+            let (mut res, _) = DnsPacket::from_bytes(&buf[..nread]).unwrap();
+            let query = res.queries[0].clone();
+            res.header.is_response = true;
+            res.answers = records
+                .iter()
+                .filter(|record| record.name == query.name && record.qtype == query.qtype)
+                .cloned()
+                .collect();
+            res.header.answers_count = res.answers.len() as u16;
+            res.header.response_code = code.clone();
+            if code == ResponseCode::NoError
+                && !records.iter().any(|record| record.name == query.name)
+            {
+                res.header.response_code = ResponseCode::NameError;
+            }
+            server.send_to(&res.to_bytes(), client).unwrap();
+        });
+        addr
+    }
+
+    fn resolver(nameservers: Vec<SocketAddr>) -> Resolver {
+        Resolver::new(ResolvConf {
+            nameservers,
+            search: vec!["foo.com".to_owned()],
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            ..ResolvConf::default()
+        })
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let conf = ResolvConf::parse(
+            "# written by hand\n\
+             nameserver 10.0.0.1\n\
+             nameserver fd00::1 ; the IPv6 one\n\
+             nameserver not-an-address\n\
+             nameserver 10.0.0.2\n\
+             nameserver 10.0.0.3\n\
+             domain foo.com\n\
+             search bar.com. baz.com\n\
+             options rotate ndots:2 timeout:3 attempts:9\n\
+             sortlist 130.155.160.0/255.255.240.0\n",
+        );
+        assert_eq!(
+            ResolvConf {
+                nameservers: vec![
+                    "10.0.0.1:53".parse().unwrap(),
+                    "[fd00::1]:53".parse().unwrap(),
+                    "10.0.0.2:53".parse().unwrap(),
+                ],
+                search: vec!["bar.com".to_owned(), "baz.com".to_owned()],
+                ndots: 2,
+                timeout: Duration::from_secs(3),
+                attempts: MAX_ATTEMPTS,
+            },
+            conf
+        );
+        assert_eq!(ResolvConf::default(), ResolvConf::parse(""));
+        assert_eq!(
+            vec!["foo.com".to_owned()],
+            ResolvConf::parse("search bar.com\ndomain foo.com.").search
+        );
+    }
+
+    #[test]
+    fn test_search_candidates() {
+        let mut resolver = resolver(vec![]);
+        resolver.conf.search.push("bar.com".to_owned());
+        assert_eq!(
+            vec!["www.foo.com", "www.bar.com", "www"],
+            resolver.candidates("www")
+        );
+        assert_eq!(
+            vec!["www.baz", "www.baz.foo.com", "www.baz.bar.com"],
+            resolver.candidates("www.baz")
+        );
+        assert_eq!(vec!["www"], resolver.candidates("www."));
+        resolver.conf.ndots = 2;
+        assert_eq!(
+            vec!["www.baz.foo.com", "www.baz.bar.com", "www.baz"],
+            resolver.candidates("www.baz")
+        );
+    }
+
+    #[test]
+    fn test_lookups() {
+        let mut mx = vec![0x00, 0x14];
+        mx.extend(serialize_domain_to_bytes("mx2.foo.com"));
+        let mut preferred_mx = vec![0x00, 0x0a];
+        preferred_mx.extend(serialize_domain_to_bytes("mx1.foo.com"));
+        let mut srv = vec![0x00, 0x0a, 0x00, 0x05, 0x13, 0xc4];
+        srv.extend(serialize_domain_to_bytes("sip1.foo.com"));
+        let mut heavier_srv = vec![0x00, 0x0a, 0x00, 0x3c, 0x13, 0xc4];
+        heavier_srv.extend(serialize_domain_to_bytes("sip2.foo.com"));
+        let server = nameserver(
+            vec![
+                record("www.foo.com", ResourceType::A, vec![10, 0, 0, 1]),
+                record(
+                    "www.foo.com",
+                    ResourceType::AAAA,
+                    vec![0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+                ),
+                record("v4.foo.com", ResourceType::A, vec![10, 0, 0, 2]),
+                record("foo.com", ResourceType::MX, mx),
+                record("foo.com", ResourceType::MX, preferred_mx),
+                record("foo.com", ResourceType::TXT, b"\x05v=spf\x041 -a".to_vec()),
+                record("_sip._udp.foo.com", ResourceType::SRV, srv),
+                record("_sip._udp.foo.com", ResourceType::SRV, heavier_srv),
+            ],
+            ResponseCode::NoError,
+        );
+        let resolver = resolver(vec![server]);
+
+        assert_eq!(
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "fd00::1".parse().unwrap()
+            ],
+            resolver.lookup_ip("www").unwrap()
+        );
+        assert_eq!(
+            vec!["10.0.0.2".parse::<IpAddr>().unwrap()],
+            resolver.lookup_ip("v4.foo.com").unwrap()
+        );
+        assert_eq!(
+            vec![
+                Mx {
+                    preference: 10,
+                    exchange: "mx1.foo.com".to_owned()
+                },
+                Mx {
+                    preference: 20,
+                    exchange: "mx2.foo.com".to_owned()
+                },
+            ],
+            resolver.lookup_mx("foo.com").unwrap()
+        );
+        assert_eq!(
+            vec!["v=spf1 -a".to_owned()],
+            resolver.lookup_txt("foo.com").unwrap()
+        );
+        let srvs = resolver.lookup_srv("_sip._udp").unwrap();
+        assert_eq!(
+            vec!["sip2.foo.com", "sip1.foo.com"],
+            srvs.iter().map(|srv| &srv.target).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            (10, 60, 5060),
+            (srvs[0].priority, srvs[0].weight, srvs[0].port)
+        );
+
+        assert_eq!(Err(LookupError::NotFound), resolver.lookup_ip("nope"));
+        assert_eq!(Err(LookupError::NoRecords), resolver.lookup_mx("www"));
+    }
+
+    #[test]
+    fn test_nameservers_that_fail_are_skipped() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let failing = nameserver(vec![], ResponseCode::ServerError);
+        let answering = nameserver(
+            vec![record("www.foo.com", ResourceType::A, vec![10, 0, 0, 1])],
+            ResponseCode::NoError,
+        );
+        let silent = silent.local_addr().unwrap();
+        assert_eq!(
+            vec!["10.0.0.1".parse::<IpAddr>().unwrap()],
+            resolver(vec![silent, failing, answering])
+                .lookup_ip("www.foo.com")
+                .unwrap()
+        );
+        assert_eq!(
+            Err(LookupError::Timeout),
+            resolver(vec![silent]).lookup_ip("www.foo.com")
+        );
+        match resolver(vec![failing]).lookup_ip("www.foo.com") {
+            Err(LookupError::Failed(e)) => assert!(e.contains("ServerError"), "{}", e),
+            res => panic!("{:?}", res),
+        }
+    }
+}
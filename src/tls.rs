@@ -0,0 +1,148 @@
+use std::convert::TryFrom;
+use std::iter::once;
+use std::sync::Arc;
+
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, Error, RootCertStore, ServerConfig, SignatureScheme,
+};
+
+/// Builds the TLS configuration shared by every encrypted listener. `alpn`
+/// is the list of application protocols the listener is willing to speak,
+/// e.g. `http/1.1` for DNS-over-HTTPS
+pub fn server_config(
+    cert_file: &str,
+    key_file: &str,
+    alpn: &[&[u8]],
+) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .map_err(|e| format!("Could not read certificate file {}: {}", cert_file, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate in {}: {}", cert_file, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_file));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| format!("Could not read private key file {}: {}", key_file, e))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate/key pair: {}", e))?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/// Builds the TLS configuration for talking to an upstream resolver. Its
+/// certificate has to check out against the usual root certificates, or those
+/// in `ca_file`, and when there are `pins` one of the certificates in its
+/// chain has to have a public key that is pinned
+pub fn client_config(
+    ca_file: Option<&str>,
+    pins: &[Vec<u8>],
+    alpn: &[u8],
+) -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(ca_file) = ca_file {
+        for cert in CertificateDer::pem_file_iter(ca_file)
+            .map_err(|e| format!("Could not read certificate file {}: {}", ca_file, e))?
+        {
+            let cert = cert.map_err(|e| format!("Invalid certificate in {}: {}", ca_file, e))?;
+            roots
+                .add(cert)
+                .map_err(|e| format!("Invalid certificate in {}: {}", ca_file, e))?;
+        }
+    }
+    let provider = Arc::new(default_provider());
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            verifier,
+            pins: pins.to_vec(),
+        }))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// The SHA-256 digest of the public key (SPKI) in `cert`, which is what a pin
+/// is
+pub fn spki_pin(cert: &CertificateDer) -> Option<Vec<u8>> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(
+        digest(&SHA256, cert.subject_public_key_info().as_ref())
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+/// Verifies certificates the usual way and then checks the pins on top
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let pinned = once(end_entity)
+            .chain(intermediates)
+            .filter_map(spki_pin)
+            .any(|pin| self.pins.contains(&pin));
+        if !self.pins.is_empty() && !pinned {
+            return Err(Error::General(
+                "None of the certificates has a pinned public key".to_owned(),
+            ));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,397 @@
+//! The resolvers a forwarder sends its queries to. Besides plain UDP they can
+//! be reached over DNS-over-TLS (RFC 7858) and DNS-over-HTTPS (RFC 8484), the
+//! encrypted connections are kept open and reused for the queries that follow
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+
+use crate::header::ResponseCode;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::packet::{is_answer_to, DnsPacket};
+use crate::serialization::{FromBytes, ToBytes};
+use crate::tls;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Upstream {
+    Udp(SocketAddr),
+    Tls(TlsUpstream),
+    /// DNS-over-HTTPS, queries are posted to the path
+    Https(TlsUpstream, String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TlsUpstream {
+    pub address: SocketAddr,
+    /// Sent as SNI, the certificate has to be valid for it
+    pub name: String,
+    /// SHA-256 digests of public keys, see `tls::client_config`
+    pub pins: Vec<Vec<u8>>,
+    /// A PEM file with certificate authorities to trust besides the usual ones
+    pub ca: Option<String>,
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Upstream::Udp(address) => write!(f, "{}", address),
+            Upstream::Tls(tls) => write!(f, "tls://{} ({})", tls.name, tls.address),
+            Upstream::Https(tls, path) => {
+                write!(f, "https://{}{} ({})", tls.name, path, tls.address)
+            }
+        }
+    }
+}
+
+/// How we talk to an upstream
+pub enum Transport {
+    Udp(SocketAddr),
+    Tls(Connections),
+    Https(Connections, String),
+}
+
+impl Transport {
+    pub fn new(upstream: &Upstream) -> Result<Self, String> {
+        Ok(match upstream {
+            Upstream::Udp(address) => Transport::Udp(*address),
+            Upstream::Tls(tls) => Transport::Tls(Connections::new(tls, b"dot")?),
+            Upstream::Https(tls, path) => {
+                Transport::Https(Connections::new(tls, b"http/1.1")?, path.clone())
+            }
+        })
+    }
+
+    /// Sends `req` and returns the response, failures come back as a server
+    /// failure. Plain UDP goes through `udp` like any other query we send
+    pub fn exchange<Q>(&self, req: &DnsPacket, timeout: Duration, udp: Q) -> DnsPacket
+    where
+        Q: Fn(&str, DnsPacket, Duration) -> DnsPacket,
+    {
+        let res = match self {
+            Transport::Udp(address) => return udp(&address.to_string(), req.clone(), timeout),
+            Transport::Tls(connections) => connections.query_tls(req, timeout),
+            Transport::Https(connections, path) => connections.query_https(path, req, timeout),
+        };
+        res.unwrap_or_else(|e| {
+            eprintln!("Could not forward a query: {}", e);
+            DnsPacket::new_error(ResponseCode::ServerError)
+        })
+    }
+}
+
+type Connection = BufReader<StreamOwned<ClientConnection, TcpStream>>;
+
+/// An encrypted upstream along with the connections to it that are idle
+pub struct Connections {
+    upstream: TlsUpstream,
+    config: Arc<ClientConfig>,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Connections {
+    fn new(upstream: &TlsUpstream, alpn: &[u8]) -> Result<Self, String> {
+        Ok(Connections {
+            upstream: upstream.clone(),
+            config: tls::client_config(upstream.ca.as_deref(), &upstream.pins, alpn)?,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// RFC 7858 frames messages the same way as DNS over TCP, with a two byte
+    /// length in front
+    fn query_tls(&self, req: &DnsPacket, timeout: Duration) -> Result<DnsPacket, String> {
+        let mut query = req.clone();
+        query.header.tx_id = rand::random();
+        let bytes = query.to_bytes();
+        let res = self.with_connection(timeout, |conn| {
+            let mut message = (bytes.len() as u16).to_be_bytes().to_vec();
+            message.extend(&bytes);
+            conn.get_mut().write_all(&message)?;
+            conn.get_mut().flush()?;
+            let mut length = [0; 2];
+            conn.read_exact(&mut length)?;
+            let mut res = vec![0; NetworkEndian::read_u16(&length) as usize];
+            conn.read_exact(&mut res)?;
+            Ok(res)
+        })?;
+        self.check(&query, req, &res)
+    }
+
+    fn query_https(
+        &self,
+        path: &str,
+        req: &DnsPacket,
+        timeout: Duration,
+    ) -> Result<DnsPacket, String> {
+        let mut query = req.clone();
+        // RFC 8484 asks for 0 so identical queries can be cached by HTTP caches
+        query.header.tx_id = 0;
+        let mut http = HttpRequest::new();
+        http.method = "POST".to_owned();
+        http.path = path.to_owned();
+        http.headers
+            .insert("Host".to_owned(), self.upstream.name.clone());
+        http.headers
+            .insert("Content-Type".to_owned(), DNS_MESSAGE.to_owned());
+        http.headers
+            .insert("Accept".to_owned(), DNS_MESSAGE.to_owned());
+        http.body = query.to_bytes();
+        let bytes = http.to_bytes();
+        let res = self.with_connection(timeout, |conn| {
+            conn.get_mut().write_all(&bytes)?;
+            conn.get_mut().flush()?;
+            HttpResponse::read_from(conn).map_err(std::io::Error::other)
+        })?;
+        if res.status != 200 || res.header("content-type") != Some(DNS_MESSAGE) {
+            return Err(format!(
+                "{} answered with HTTP {}",
+                self.upstream.name, res.status
+            ));
+        }
+        self.check(&query, req, &res.body)
+    }
+
+    /// Makes sure `res` is the answer to `query` and turns it into the answer
+    /// to `req`
+    fn check(&self, query: &DnsPacket, req: &DnsPacket, res: &[u8]) -> Result<DnsPacket, String> {
+        match DnsPacket::from_bytes(res) {
+            Ok((mut res, _)) if is_answer_to(query, &res) => {
+                res.header.tx_id = req.header.tx_id;
+                Ok(res)
+            }
+            _ => Err(format!("{} sent an invalid response", self.upstream.name)),
+        }
+    }
+
+    /// Runs `exchange` on an idle connection if there is one, or else on a
+    /// new one. The server may have closed an idle connection in the meantime,
+    /// so when that fails it's worth another go on a fresh one
+    fn with_connection<T, E>(&self, timeout: Duration, exchange: E) -> Result<T, String>
+    where
+        E: Fn(&mut Connection) -> std::io::Result<T>,
+    {
+        let idle = self.idle.lock().unwrap().pop();
+        if let Some(mut conn) = idle {
+            set_timeouts(conn.get_ref().get_ref(), timeout);
+            if let Ok(result) = exchange(&mut conn) {
+                self.idle.lock().unwrap().push(conn);
+                return Ok(result);
+            }
+        }
+        let mut conn = self.connect(timeout)?;
+        let result = exchange(&mut conn).map_err(|e| format!("{}: {}", self.upstream.name, e))?;
+        self.idle.lock().unwrap().push(conn);
+        Ok(result)
+    }
+
+    fn connect(&self, timeout: Duration) -> Result<Connection, String> {
+        let stream = TcpStream::connect_timeout(&self.upstream.address, timeout)
+            .map_err(|e| format!("Could not connect to {}: {}", self.upstream.address, e))?;
+        set_timeouts(&stream, timeout);
+        // queries are small and we wait for every one of them
+        let _ = stream.set_nodelay(true);
+        let name = ServerName::try_from(self.upstream.name.clone())
+            .map_err(|_| format!("Invalid server name {}", self.upstream.name))?;
+        let conn = ClientConnection::new(self.config.clone(), name).map_err(|e| e.to_string())?;
+        Ok(BufReader::new(StreamOwned::new(conn, stream)))
+    }
+}
+
+fn set_timeouts(stream: &TcpStream, timeout: Duration) {
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::DnsQuery;
+    use crate::tls::{server_config, spki_pin};
+    use pretty_assertions::assert_eq;
+    use ring::digest::{digest, SHA256};
+    use rustls::pki_types::CertificateDer;
+    use rustls::ServerConnection;
+    use std::fs::write;
+    use std::io::Error;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tempdir::TempDir;
+
+    type ServerStream = BufReader<StreamOwned<ServerConnection, TcpStream>>;
+
+    /// A TLS server on loopback with a self signed certificate for localhost
+    struct StandIn {
+        upstream: TlsUpstream,
+        cert: CertificateDer<'static>,
+        connections: Arc<AtomicUsize>,
+        _dir: TempDir,
+    }
+
+    /// Starts a stand-in that calls `serve` for every request on a
+    /// connection, the connection is closed once it fails
+    fn stand_in(alpn: &[u8], serve: fn(&mut ServerStream) -> std::io::Result<()>) -> StandIn {
+        let dir = TempDir::new("upstream").unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_file = dir.path().join("cert.pem");
+        let key_file = dir.path().join("key.pem");
+        write(&cert_file, cert.cert.pem()).unwrap();
+        write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+        let tls = server_config(
+            cert_file.to_str().unwrap(),
+            key_file.to_str().unwrap(),
+            &[alpn],
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let conn = ServerConnection::new(tls.clone()).unwrap();
+                let mut stream = BufReader::new(StreamOwned::new(conn, stream.unwrap()));
+                thread::spawn(move || while serve(&mut stream).is_ok() {});
+            }
+        });
+        StandIn {
+            upstream: TlsUpstream {
+                address,
+                name: "localhost".to_owned(),
+                pins: Vec::new(),
+                ca: Some(cert_file.to_str().unwrap().to_owned()),
+            },
+            cert: CertificateDer::from(cert.cert.der().to_vec()),
+            connections,
+            _dir: dir,
+        }
+    }
+
+    fn answer(req: &[u8]) -> Vec<u8> {
+        let (mut res, _) = DnsPacket::from_bytes(req).unwrap();
+        res.header.is_response = true;
+        res.header.recursion_available = true;
+        res.to_bytes()
+    }
+
+    fn serve_tls(stream: &mut ServerStream) -> std::io::Result<()> {
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        let mut req = vec![0; NetworkEndian::read_u16(&length) as usize];
+        stream.read_exact(&mut req)?;
+        let res = answer(&req);
+        let mut message = (res.len() as u16).to_be_bytes().to_vec();
+        message.extend(res);
+        stream.get_mut().write_all(&message)?;
+        stream.get_mut().flush()
+    }
+
+    /// Hangs up after every answer, like a server with a short idle timeout
+    fn serve_tls_once(stream: &mut ServerStream) -> std::io::Result<()> {
+        serve_tls(stream)?;
+        Err(Error::other("hanging up"))
+    }
+
+    fn serve_https(stream: &mut ServerStream) -> std::io::Result<()> {
+        let req = match HttpRequest::read_from(stream) {
+            Ok(Some(req)) => req,
+            _ => return Err(Error::other("no request")),
+        };
+        assert_eq!("POST", req.method);
+        assert_eq!("/resolve", req.path);
+        assert_eq!(Some("localhost"), req.header("host"));
+        assert_eq!(Some(DNS_MESSAGE), req.header("content-type"));
+        let res = HttpResponse::with_body(200, DNS_MESSAGE, answer(&req.body));
+        stream.get_mut().write_all(&res.to_bytes())?;
+        stream.get_mut().flush()
+    }
+
+    fn request() -> DnsPacket {
+        let mut query = DnsQuery::new();
+        query.name = "foo.com".to_owned();
+        let mut req = DnsPacket::new();
+        req.header.tx_id = 0xbeef;
+        req.header.questions_count = 1;
+        req.queries = vec![query];
+        req
+    }
+
+    fn ask(transport: &Transport) -> DnsPacket {
+        transport.exchange(&request(), Duration::from_secs(5), |_, _, _| {
+            panic!("Nothing should go over UDP")
+        })
+    }
+
+    fn assert_answered(res: &DnsPacket) {
+        assert_eq!(ResponseCode::NoError, res.header.response_code);
+        assert!(res.header.is_response);
+        assert_eq!(0xbeef, res.header.tx_id);
+        assert_eq!(request().queries, res.queries);
+    }
+
+    #[test]
+    fn test_dns_over_tls_reuses_connections() {
+        let stand_in = stand_in(b"dot", serve_tls);
+        let mut upstream = stand_in.upstream.clone();
+        upstream.pins = vec![spki_pin(&stand_in.cert).unwrap()];
+        let transport = Transport::new(&Upstream::Tls(upstream)).unwrap();
+        for _ in 0..3 {
+            assert_answered(&ask(&transport));
+        }
+        assert_eq!(1, stand_in.connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_reconnects_when_the_server_hung_up() {
+        let stand_in = stand_in(b"dot", serve_tls_once);
+        let transport = Transport::new(&Upstream::Tls(stand_in.upstream.clone())).unwrap();
+        for _ in 0..2 {
+            assert_answered(&ask(&transport));
+        }
+        assert_eq!(2, stand_in.connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_dns_over_https() {
+        let stand_in = stand_in(b"http/1.1", serve_https);
+        let upstream = Upstream::Https(stand_in.upstream.clone(), "/resolve".to_owned());
+        let transport = Transport::new(&upstream).unwrap();
+        for _ in 0..2 {
+            assert_answered(&ask(&transport));
+        }
+        assert_eq!(1, stand_in.connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_certificates_are_checked() {
+        let stand_in = stand_in(b"dot", serve_tls);
+        // the same pin as `openssl pkey -pubin -outform der | sha256sum` gives
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        assert_eq!(
+            digest(&SHA256, &cert.key_pair.public_key_der()).as_ref(),
+            &spki_pin(&CertificateDer::from(cert.cert.der().to_vec())).unwrap()[..]
+        );
+
+        let mut pinned = stand_in.upstream.clone();
+        pinned.pins = vec![vec![0; 32]];
+        let mut untrusted = stand_in.upstream.clone();
+        untrusted.ca = None;
+        let mut wrong_name = stand_in.upstream.clone();
+        wrong_name.name = "dns.example.com".to_owned();
+        for upstream in [pinned, untrusted, wrong_name] {
+            let res = ask(&Transport::new(&Upstream::Tls(upstream)).unwrap());
+            assert_eq!(ResponseCode::ServerError, res.header.response_code);
+        }
+        let upstream = Upstream::Tls(stand_in.upstream.clone());
+        assert_answered(&ask(&Transport::new(&upstream).unwrap()));
+    }
+}